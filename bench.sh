@@ -0,0 +1,72 @@
+#!/usr/bin/env bash
+# Compare the blocking diesel reads with the async-db feature under load.
+#
+# Needs wrk, curl, a migrated and seeded database and Redis, configured as for `cargo run`.
+# The server reads bench.toml on top of the defaults, the environment still wins.
+# Usage: ./bench.sh [connections] [duration]
+# Prints the wrk output of each run and a Markdown table of the results for the README.
+#
+# The API allows 200 requests per 30s for each user and client address (IP and port),
+# so every wrk connection waits 160ms between requests to stay under it. Both builds get
+# the same load, about 6 requests per second per connection; compare their latencies.
+set -euo pipefail
+
+CONNECTIONS=${1:-200}
+DURATION=${2:-30s}
+SECURE_PORT=${SECURE_PORT:-8443}
+BASE="https://127.0.0.1:${SECURE_PORT}"
+EMAIL=${BENCH_EMAIL:-satoshi@nakamotoinstitute.org}
+PASSWORD=${BENCH_PASSWORD:-123456}
+RESULTS=$(mktemp)
+PACING=$(mktemp)
+trap 'rm -f "$RESULTS" "$PACING"' EXIT
+echo 'function delay() return 160 end' >"$PACING"
+
+# Run wrk and add a table row: variant, endpoint, req/s, average and p99 latency
+measure() {
+    local name=$1 endpoint=$2 cookie=$3 url=$4
+    local output
+    echo "== ${name}: ${endpoint}"
+    output=$(wrk -t4 -c"${CONNECTIONS}" -d"${DURATION}" --latency -s "$PACING" -H "Cookie: ${cookie}" "$url")
+    echo "$output"
+    local rps avg p99 errors
+    rps=$(awk '/^Requests\/sec:/ { print $2 }' <<<"$output")
+    avg=$(awk '$1 == "Latency" { print $2; exit }' <<<"$output")
+    p99=$(awk '$1 == "99%" { print $2 }' <<<"$output")
+    # Rate limited (429) or failed responses, should stay 0
+    errors=$(awk '/^  Non-2xx or 3xx responses:/ { print $5 }' <<<"$output")
+    echo "| ${name} | ${endpoint} | ${rps} | ${avg} | ${p99} | ${errors:-0} |" >>"$RESULTS"
+}
+
+run() {
+    local name=$1
+    shift
+    cargo build --release "$@"
+    ./target/release/actix_simple_bp --config bench.toml &
+    local server=$!
+    trap "kill $server 2>/dev/null" RETURN
+    until curl -ks "${BASE}/health" >/dev/null; do sleep 1; done
+
+    local jar
+    jar=$(mktemp)
+    curl -ks -c "$jar" -H 'Content-Type: application/json' \
+        -d "{\"email\": \"${EMAIL}\", \"password\": \"${PASSWORD}\"}" "${BASE}/api/ext/v1/login" >/dev/null
+    local cookie
+    cookie=$(awk '!/^#/ && NF >= 7 { printf "%s=%s; ", $6, $7 }' "$jar")
+    local user_id
+    user_id=$(curl -ks -H "Cookie: ${cookie}" "${BASE}/api/v1/user?limit=1" | sed -E 's/.*"id":"([^"]+)".*/\1/')
+    rm -f "$jar"
+
+    measure "$name" list "$cookie" "${BASE}/api/v1/user?limit=25"
+    measure "$name" get "$cookie" "${BASE}/api/v1/user/${user_id}"
+}
+
+run blocking
+run async-db --features async-db
+
+echo
+echo "${CONNECTIONS} connections for ${DURATION} each:"
+echo
+echo "| Build | Endpoint | Requests/s | Average latency | p99 latency | Non-2xx |"
+echo "| ----- | -------- | ---------- | --------------- | ----------- | ------- |"
+cat "$RESULTS"
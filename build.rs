@@ -0,0 +1,38 @@
+//! Embeds every migration, up.sql and down.sql, so the binary can run, list and revert them on its own
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let directory = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("migrations");
+    println!("cargo:rerun-if-changed={}", directory.display());
+
+    let mut names = fs::read_dir(&directory)
+        .expect("Cannot read the migrations directory")
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().join("up.sql").is_file())
+        .map(|entry| entry.file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let migrations = names
+        .iter()
+        .map(|name| {
+            let path = directory.join(name);
+            // The version diesel records: the timestamp in front of the name without separators
+            let version = name.split('_').next().unwrap().replace('-', "");
+            println!("cargo:rerun-if-changed={}", path.display());
+            format!(
+                "    EmbeddedMigration {{ name: {:?}, version: {:?}, up: include_str!({:?}), down: include_str!({:?}) }},\n",
+                name,
+                version,
+                path.join("up.sql"),
+                path.join("down.sql"),
+            )
+        })
+        .collect::<String>();
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("migrations.rs");
+    fs::write(out, format!("&[\n{}]\n", migrations)).unwrap();
+}
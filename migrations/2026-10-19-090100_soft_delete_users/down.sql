@@ -0,0 +1,5 @@
+DROP INDEX users_deleted_at ON users;
+
+ALTER TABLE users
+  DROP COLUMN deleted_at,
+  DROP COLUMN deleted_by;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+  ADD COLUMN deleted_at TIMESTAMP NULL DEFAULT NULL,
+  ADD COLUMN deleted_by VARCHAR(36) NULL DEFAULT NULL;
+
+CREATE INDEX users_deleted_at ON users (deleted_at);
@@ -0,0 +1,14 @@
+CREATE TABLE audit_log (
+  id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  actor_id VARCHAR(36) NULL,
+  action VARCHAR(50) NOT NULL,
+  target_type VARCHAR(50) NOT NULL,
+  target_id VARCHAR(36) NULL,
+  changes TEXT NULL,
+  ip VARCHAR(45) NULL,
+  request_id VARCHAR(128) NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  INDEX audit_log_actor (actor_id, created_at),
+  INDEX audit_log_target (target_type, target_id, created_at),
+  INDEX audit_log_created_at (created_at)
+);
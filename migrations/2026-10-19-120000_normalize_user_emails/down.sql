@@ -0,0 +1,4 @@
+DROP INDEX users_email_normalized ON users;
+
+ALTER TABLE users
+  DROP COLUMN email_normalized;
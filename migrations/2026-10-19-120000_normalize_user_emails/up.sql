@@ -0,0 +1,10 @@
+ALTER TABLE users
+  ADD COLUMN email_normalized VARCHAR(100) NOT NULL DEFAULT '';
+
+UPDATE users SET email_normalized = LOWER(TRIM(email));
+
+ALTER TABLE users
+  ALTER COLUMN email_normalized DROP DEFAULT;
+
+-- Fails if existing users share an email, merge or rename them first
+CREATE UNIQUE INDEX users_email_normalized ON users (email_normalized);
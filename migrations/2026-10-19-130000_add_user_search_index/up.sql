@@ -0,0 +1 @@
+CREATE FULLTEXT INDEX users_search ON users (first_name, last_name, email);
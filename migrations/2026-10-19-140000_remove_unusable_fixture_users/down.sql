@@ -0,0 +1,3 @@
+INSERT IGNORE INTO users (id, first_name, last_name, email, email_normalized, password, salt1, salt2, created_by, updated_by) VALUES
+('00000000-0000-0000-0000-000000000000', 'admin', 'user', 'admin@admin.com', 'admin@admin.com', '123', '', '', '00000000-0000-0000-0000-000000000000', '00000000-0000-0000-0000-000000000000'),
+('1802d2f8-1a18-43c1-9c58-1c3f7100c842', 'test', 'user', 'test@admin.com', 'test@admin.com', '123', '', '', '00000000-0000-0000-0000-000000000000', '00000000-0000-0000-0000-000000000000');
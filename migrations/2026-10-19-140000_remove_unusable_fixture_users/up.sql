@@ -0,0 +1,4 @@
+-- The users inserted by create_users have a plain text password and no salt,
+-- nobody can log in with them. Use the `seed` and `create-admin` commands instead.
+DELETE FROM users
+WHERE email IN ('admin@admin.com', 'test@admin.com') AND password = '123' AND salt1 = '';
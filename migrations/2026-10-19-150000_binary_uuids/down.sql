@@ -0,0 +1,29 @@
+ALTER TABLE users
+  MODIFY id VARBINARY(36) NOT NULL,
+  MODIFY created_by VARBINARY(36) NOT NULL,
+  MODIFY updated_by VARBINARY(36) NOT NULL,
+  MODIFY deleted_by VARBINARY(36) NULL DEFAULT NULL;
+
+UPDATE users SET
+  id = LOWER(CONCAT(HEX(SUBSTR(id, 1, 4)), '-', HEX(SUBSTR(id, 5, 2)), '-', HEX(SUBSTR(id, 7, 2)), '-', HEX(SUBSTR(id, 9, 2)), '-', HEX(SUBSTR(id, 11, 6)))),
+  created_by = LOWER(CONCAT(HEX(SUBSTR(created_by, 1, 4)), '-', HEX(SUBSTR(created_by, 5, 2)), '-', HEX(SUBSTR(created_by, 7, 2)), '-', HEX(SUBSTR(created_by, 9, 2)), '-', HEX(SUBSTR(created_by, 11, 6)))),
+  updated_by = LOWER(CONCAT(HEX(SUBSTR(updated_by, 1, 4)), '-', HEX(SUBSTR(updated_by, 5, 2)), '-', HEX(SUBSTR(updated_by, 7, 2)), '-', HEX(SUBSTR(updated_by, 9, 2)), '-', HEX(SUBSTR(updated_by, 11, 6)))),
+  deleted_by = LOWER(CONCAT(HEX(SUBSTR(deleted_by, 1, 4)), '-', HEX(SUBSTR(deleted_by, 5, 2)), '-', HEX(SUBSTR(deleted_by, 7, 2)), '-', HEX(SUBSTR(deleted_by, 9, 2)), '-', HEX(SUBSTR(deleted_by, 11, 6))));
+
+ALTER TABLE users
+  MODIFY id VARCHAR(36) NOT NULL,
+  MODIFY created_by VARCHAR(36) NOT NULL,
+  MODIFY updated_by VARCHAR(36) NOT NULL,
+  MODIFY deleted_by VARCHAR(36) NULL DEFAULT NULL;
+
+ALTER TABLE audit_log
+  MODIFY actor_id VARBINARY(36) NULL,
+  MODIFY target_id VARBINARY(36) NULL;
+
+UPDATE audit_log SET
+  actor_id = LOWER(CONCAT(HEX(SUBSTR(actor_id, 1, 4)), '-', HEX(SUBSTR(actor_id, 5, 2)), '-', HEX(SUBSTR(actor_id, 7, 2)), '-', HEX(SUBSTR(actor_id, 9, 2)), '-', HEX(SUBSTR(actor_id, 11, 6)))),
+  target_id = LOWER(CONCAT(HEX(SUBSTR(target_id, 1, 4)), '-', HEX(SUBSTR(target_id, 5, 2)), '-', HEX(SUBSTR(target_id, 7, 2)), '-', HEX(SUBSTR(target_id, 9, 2)), '-', HEX(SUBSTR(target_id, 11, 6))));
+
+ALTER TABLE audit_log
+  MODIFY actor_id VARCHAR(36) NULL,
+  MODIFY target_id VARCHAR(36) NULL;
@@ -0,0 +1,33 @@
+-- Store UUIDs as their 16 bytes instead of 36 characters.
+-- Columns are converted in place so keys and indexes stay, going through VARBINARY
+-- to hold the text and the bytes. UNHEX rather than UUID_TO_BIN keeps MySQL 5.7 working,
+-- a malformed id turns into NULL and fails the NOT NULL columns instead of being stored.
+ALTER TABLE users
+  MODIFY id VARBINARY(36) NOT NULL,
+  MODIFY created_by VARBINARY(36) NOT NULL,
+  MODIFY updated_by VARBINARY(36) NOT NULL,
+  MODIFY deleted_by VARBINARY(36) NULL DEFAULT NULL;
+
+UPDATE users SET
+  id = UNHEX(REPLACE(id, '-', '')),
+  created_by = UNHEX(REPLACE(created_by, '-', '')),
+  updated_by = UNHEX(REPLACE(updated_by, '-', '')),
+  deleted_by = UNHEX(REPLACE(deleted_by, '-', ''));
+
+ALTER TABLE users
+  MODIFY id BINARY(16) NOT NULL,
+  MODIFY created_by BINARY(16) NOT NULL,
+  MODIFY updated_by BINARY(16) NOT NULL,
+  MODIFY deleted_by BINARY(16) NULL DEFAULT NULL;
+
+ALTER TABLE audit_log
+  MODIFY actor_id VARBINARY(36) NULL,
+  MODIFY target_id VARBINARY(36) NULL;
+
+UPDATE audit_log SET
+  actor_id = UNHEX(REPLACE(actor_id, '-', '')),
+  target_id = UNHEX(REPLACE(target_id, '-', ''));
+
+ALTER TABLE audit_log
+  MODIFY actor_id BINARY(16) NULL,
+  MODIFY target_id BINARY(16) NULL;
@@ -0,0 +1,12 @@
+-- Fails if users of different tenants share an email, merge or rename them first
+CREATE UNIQUE INDEX users_email_normalized ON users (email_normalized);
+
+ALTER TABLE users
+  DROP FOREIGN KEY users_tenant;
+
+DROP INDEX users_tenant_email_normalized ON users;
+
+ALTER TABLE users
+  DROP COLUMN tenant_id;
+
+DROP TABLE tenants;
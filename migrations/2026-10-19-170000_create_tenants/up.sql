@@ -0,0 +1,27 @@
+CREATE TABLE tenants (
+  id BINARY(16) NOT NULL PRIMARY KEY,
+  slug VARCHAR(63) NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  created_by BINARY(16) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by BINARY(16) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE INDEX tenants_slug (slug)
+);
+
+-- The built-in tenant with the nil id holds the existing users, its admins manage the tenants
+INSERT INTO tenants (id, slug, name, created_by, updated_by)
+  VALUES (UNHEX(REPEAT('0', 32)), 'default', 'Default', UNHEX(REPEAT('0', 32)), UNHEX(REPEAT('0', 32)));
+
+ALTER TABLE users
+  ADD COLUMN tenant_id BINARY(16) NULL;
+
+UPDATE users SET tenant_id = UNHEX(REPEAT('0', 32));
+
+ALTER TABLE users
+  MODIFY tenant_id BINARY(16) NOT NULL,
+  ADD CONSTRAINT users_tenant FOREIGN KEY (tenant_id) REFERENCES tenants (id);
+
+-- Emails are unique per tenant, the new index serves the tenant's queries as well
+CREATE UNIQUE INDEX users_tenant_email_normalized ON users (tenant_id, email_normalized);
+DROP INDEX users_email_normalized ON users;
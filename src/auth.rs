@@ -1,4 +1,6 @@
 use crate::config::CONFIG;
+use crate::models::tenant::TenantId;
+use crate::server_helpers::cache::{self, Cache};
 use crate::server_helpers::errors::ApiError;
 use actix_redis::RedisSession;
 use argon2rs::argon2i_simple;
@@ -7,19 +9,37 @@ use std::time::Duration as TimeDuration;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use uuid::Uuid;
 
+/// Session key holding the epoch the session was created in
+pub const SESSION_EPOCH_KEY: &str = "session_epoch";
+
+/// Session key holding the tenant of the user, the session identity is only their id
+pub const SESSION_TENANT_KEY: &str = "tenant_id";
+
+/// Upper bound for new passwords, hashing very long inputs is needlessly slow
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Rejected when found anywhere in a new password
+const COMMON_PASSWORDS: [&str; 8] = [
+    "password", "123456", "qwerty", "letmein", "iloveyou", "welcome", "admin", "abc123",
+];
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PrivateClaim {
     pub user_id: Uuid,
     pub email: String,
+    /// The tenant the user belongs to, requests with the token act in it
+    #[serde(default)]
+    pub tenant_id: TenantId,
     exp: i64,
 }
 
 impl PrivateClaim {
-    pub fn new(user_id: Uuid, email: String) -> Self {
+    pub fn new(user_id: Uuid, email: String, tenant_id: TenantId) -> Self {
         Self {
             user_id,
             email,
-            exp: (OffsetDateTime::now_utc() + Duration::seconds(CONFIG.jwt_expiration)).unix_timestamp(),
+            tenant_id,
+            exp: (OffsetDateTime::now_utc() + Duration::seconds(CONFIG.jwt_expiration.as_secs() as i64)).unix_timestamp(),
         }
     }
 }
@@ -55,6 +75,100 @@ pub fn hash(password: &str, salt: &String) -> String {
         .collect()
 }
 
+/// Get the user id from an identity, a plain id in session mode or a JWT otherwise
+pub fn identity_user_id(identity: &str) -> Option<Uuid> {
+    Uuid::parse_str(identity)
+        .ok()
+        .or_else(|| decode_jwt(identity).ok().map(|claim| claim.user_id))
+}
+
+/// Get the tenant of an identity, plain ids in session mode predate tenants and belong to the default one
+pub fn identity_tenant_id(identity: &str) -> Option<TenantId> {
+    match Uuid::parse_str(identity) {
+        Ok(_) => Some(TenantId::DEFAULT),
+        Err(_) => decode_jwt(identity).ok().map(|claim| claim.tenant_id),
+    }
+}
+
+/// Check a new password against the configured strength policy
+///
+/// `field` names the password in the messages, `personal` holds values
+/// the password must not contain, like the user's name.
+pub fn check_password_strength(field: &str, password: &str, personal: &[&str]) -> Result<(), ApiError> {
+    let problems = password_problems(
+        field,
+        password,
+        CONFIG.password_min_length,
+        CONFIG.password_min_classes,
+        personal,
+    );
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(problems))
+    }
+}
+
+fn password_problems(
+    field: &str,
+    password: &str,
+    min_length: usize,
+    min_classes: usize,
+    personal: &[&str],
+) -> Vec<String> {
+    let mut problems = vec![];
+    let length = password.chars().count();
+    if length < min_length {
+        problems.push(format!("{} must be at least {} characters", field, min_length));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        problems.push(format!("{} must be at most {} characters", field, MAX_PASSWORD_LENGTH));
+    }
+    // lowercase, uppercase, digits and symbols
+    let classes: [fn(char) -> bool; 4] = [
+        char::is_lowercase,
+        char::is_uppercase,
+        char::is_numeric,
+        |c| !c.is_alphanumeric(),
+    ];
+    let used = classes
+        .iter()
+        .filter(|class| password.chars().any(class))
+        .count();
+    if used < min_classes {
+        problems.push(format!(
+            "{} must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+            field, min_classes
+        ));
+    }
+    let lowered = password.to_lowercase();
+    if COMMON_PASSWORDS.iter().any(|common| lowered.contains(common)) {
+        problems.push(format!("{} is too common", field));
+    }
+    let is_personal = personal
+        .iter()
+        .any(|value| value.chars().count() >= 3 && lowered.contains(&value.to_lowercase()));
+    if is_personal {
+        problems.push(format!("{} must not contain your name or email", field));
+    }
+    problems
+}
+
+fn session_epoch_key(user_id: Uuid) -> String {
+    format!("session_epoch:{}", user_id)
+}
+
+/// Current session epoch of a user, sessions stamped with an older one are no longer valid
+pub async fn session_epoch(redis: Cache, user_id: Uuid) -> Result<i64, ApiError> {
+    let epoch = cache::get(redis, &session_epoch_key(user_id)).await?;
+    Ok(epoch.and_then(|epoch| epoch.parse().ok()).unwrap_or(0))
+}
+
+/// Move a user to a new session epoch, which invalidates all of their existing sessions
+pub async fn invalidate_sessions(redis: Cache, user_id: Uuid) -> Result<i64, ApiError> {
+    cache::incr(redis, &session_epoch_key(user_id)).await
+}
+
 // WIZ_OPT: IP rate limiter
 use actix_ratelimit::{RateLimiter, RedisStoreActor, RedisStore};
 use actix::Addr;
@@ -72,16 +186,13 @@ pub fn get_ip_rate_limiter(store: &Addr<RedisStore>) -> RateLimiter<RedisStoreAc
 }
 
 // WIZ_OPT: use Redis as identity( and session) storage
-use actix_web::cookie::SameSite;
 /// Gets the session service for injection into an Actix app
 pub fn get_session_service() -> RedisSession {
-    let time_out = CONFIG.session_timeout as u32;
-    let same_site = get_same_site();
     RedisSession::new(&CONFIG.redis_url, &CONFIG.session_key.as_ref())
         .cookie_name(&CONFIG.session_name)
-        .ttl(time_out*60)
+        .ttl(CONFIG.session_timeout.as_secs() as u32)
         .cookie_secure(CONFIG.session_secure)
-        .cookie_same_site(same_site)
+        .cookie_same_site(CONFIG.session_samesite.into())
 }
 
 use actix_identity::{CookieIdentityPolicy, IdentityService, IdentityPolicy, RequestIdentity};
@@ -92,23 +203,11 @@ pub fn get_identity_service<T: IdentityPolicy>(policy: T) -> IdentityService<imp
 
 // WIZ_OPT: use cookies as identity storage
 pub fn get_cookie_policy() -> CookieIdentityPolicy {
-    let same_site = get_same_site();
     CookieIdentityPolicy::new(&CONFIG.session_key.as_ref())
         .name("id-".to_string() + &CONFIG.session_name)
-        .max_age_time(Duration::minutes(CONFIG.session_timeout))
+        .max_age_time(Duration::seconds(CONFIG.session_timeout.as_secs() as i64))
         .secure(CONFIG.session_secure)
-        .same_site(same_site)
-}
-
-
-fn get_same_site() -> SameSite {
-    let same_site : SameSite;
-    match (&CONFIG.session_samesite.to_uppercase()).as_str() {
-        "STRICT" => same_site = SameSite::Strict,
-        "NONE" => same_site = SameSite::None,
-        _ => same_site = SameSite::Lax,
-    }
-    same_site
+        .same_site(CONFIG.session_samesite.into())
 }
 
 fn mask_str(str: &String, mask : &String) -> String{
@@ -155,20 +254,67 @@ pub mod tests {
 
     #[test]
     fn it_creates_a_jwt() {
-        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into(), TenantId::DEFAULT);
         let jwt = create_jwt(private_claim);
         assert!(jwt.is_ok());
     }
 
     #[test]
     fn it_decodes_a_jwt() {
-        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into(), TenantId(Uuid::new_v4()));
         let jwt = create_jwt(private_claim.clone()).unwrap();
         let decoded = decode_jwt(&jwt).unwrap();
         assert_eq!(private_claim, decoded);
     }
 
 
+    #[test]
+    fn it_accepts_a_strong_password() {
+        let problems = password_problems("new_password", "Correct-Horse-Battery", 10, 3, &["satoshi"]);
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn it_reports_weak_passwords() {
+        let problems = password_problems("new_password", "satoshi123456", 16, 3, &["satoshi"]);
+        assert_eq!(
+            problems,
+            vec![
+                "new_password must be at least 16 characters".to_string(),
+                "new_password must mix at least 3 of lowercase letters, uppercase letters, digits and symbols".to_string(),
+                "new_password is too common".to_string(),
+                "new_password must not contain your name or email".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_gets_the_user_id_of_an_identity() {
+        let user_id = Uuid::new_v4();
+        let jwt = create_jwt(PrivateClaim::new(user_id, EMAIL.into(), TenantId::DEFAULT)).unwrap();
+        assert_eq!(identity_user_id(&user_id.to_string()), Some(user_id));
+        assert_eq!(identity_user_id(&jwt), Some(user_id));
+        assert_eq!(identity_user_id("nobody"), None);
+    }
+
+    #[test]
+    fn it_gets_the_tenant_of_an_identity() {
+        let tenant = TenantId(Uuid::new_v4());
+        let jwt = create_jwt(PrivateClaim::new(Uuid::new_v4(), EMAIL.into(), tenant)).unwrap();
+        assert_eq!(identity_tenant_id(&jwt), Some(tenant));
+        assert_eq!(identity_tenant_id(&Uuid::new_v4().to_string()), Some(TenantId::DEFAULT));
+        assert_eq!(identity_tenant_id("nobody"), None);
+    }
+
+    #[test]
+    fn it_puts_tokens_without_a_tenant_in_the_default_one() {
+        let claim: PrivateClaim = serde_json::from_str(
+            r#"{"user_id": "a421a56e-8652-4da6-90ee-59dfebb9d1b4", "email": "satoshi@nakamotoinstitute.org", "exp": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(claim.tenant_id, TenantId::DEFAULT);
+    }
+
     #[test]
     fn it_masks_a_string() {
         let salt1 = "salt1salt1salt1".to_string();
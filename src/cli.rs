@@ -0,0 +1,92 @@
+//! Command line arguments
+//!
+//! Parsed once into the OPTS lazy_static. Tests don't get the real process
+//! arguments (those belong to the test harness), they use the defaults.
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Clone, Debug, Default, StructOpt)]
+#[structopt(name = "actix_simple_bp")]
+pub struct Opts {
+    /// TOML file with configuration values [default: ./config.toml if it exists]
+    #[structopt(short, long, parse(from_os_str), env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, print the result and exit
+    #[structopt(long)]
+    pub check_config: bool,
+
+    /// Permanently remove users deleted longer than USER_RETENTION ago and exit
+    #[structopt(long)]
+    pub purge_deleted_users: bool,
+
+    /// Address to bind to
+    #[structopt(long)]
+    pub server: Option<String>,
+
+    /// Plain HTTP port (redirects to HTTPS)
+    #[structopt(long)]
+    pub port: Option<String>,
+
+    /// HTTPS port
+    #[structopt(long)]
+    pub secure_port: Option<String>,
+
+    /// Override any configuration value, eg. --set session_timeout=30m
+    #[structopt(short, long = "set", number_of_values = 1)]
+    pub overrides: Vec<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum Command {
+    /// Manage the database migrations embedded in the binary
+    Migrate(MigrateCommand),
+    /// Create the fixture users from a YAML or JSON file, skipping emails already in use
+    Seed {
+        #[structopt(parse(from_os_str), default_value = "fixtures/users.yaml")]
+        file: PathBuf,
+        /// Slug of the tenant the users are created in
+        #[structopt(long, default_value = "default")]
+        tenant: String,
+    },
+    /// Create the first admin of a tenant, prompts for the password
+    CreateAdmin {
+        #[structopt(long)]
+        email: String,
+        #[structopt(long, default_value = "Admin")]
+        first_name: String,
+        #[structopt(long, default_value = "User")]
+        last_name: String,
+        /// Slug of the tenant, admins of the default tenant manage the tenants
+        #[structopt(long, default_value = "default")]
+        tenant: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub enum MigrateCommand {
+    /// Apply every pending migration
+    Run,
+    /// Revert the latest applied migration
+    Revert,
+    /// List the migrations and whether they have been applied
+    Status,
+}
+
+lazy_static! {
+    pub static ref OPTS: Opts = get_opts();
+}
+
+#[cfg(not(test))]
+fn get_opts() -> Opts {
+    Opts::from_args()
+}
+
+#[cfg(test)]
+fn get_opts() -> Opts {
+    Opts::default()
+}
@@ -0,0 +1,154 @@
+//! One-shot maintenance commands, run from the command line instead of the server
+//!
+//! Each command prints its result and exits the process.
+
+use crate::auth::check_password_strength;
+use crate::cli::MigrateCommand;
+use crate::config::CONFIG;
+use crate::database::connection::{init_pool, PoolType};
+use crate::database::transaction::unit_of_work;
+use crate::database::migrations::{connect, revert_latest, run_pending, status};
+use crate::database::seed::{fixture_user, load_fixtures, seed, Fixture};
+use crate::handlers::user::retention_cutoff;
+use crate::models::tenant::{all_ids, find_by_slug, TenantId};
+use crate::models::user::{admin_exists, create, emails_in_use, normalize_email, purge};
+use crate::server_helpers::errors::ApiError;
+use diesel::mysql::MysqlConnection;
+use std::path::Path;
+
+/// Run, revert or list the embedded migrations
+pub fn migrate(command: MigrateCommand) -> ! {
+    let result = connect(&CONFIG.database_url).and_then(|conn| match command {
+        MigrateCommand::Run => run_pending(&conn).map(|applied| {
+            for name in &applied {
+                println!("Applied {}", name);
+            }
+            println!("{} migration(s) applied", applied.len());
+        }),
+        MigrateCommand::Revert => revert_latest(&conn).map(|reverted| match reverted {
+            Some(name) => println!("Reverted {}", name),
+            None => println!("No migration to revert"),
+        }),
+        MigrateCommand::Status => status(&conn).map(|migrations| {
+            for migration in migrations {
+                let mark = if migration.applied { "[x]" } else { "[ ]" };
+                println!("{} {}", mark, migration.name);
+            }
+        }),
+    });
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(error) => {
+            eprintln!("Could not migrate: {:?}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Create the fixture users in a tenant from a file, skipping the ones whose email is in use there
+pub fn seed_users(file: &Path, tenant_slug: &str) -> ! {
+    let result = init_pool::<MysqlConnection>(CONFIG.clone())
+        .map(PoolType::from)
+        .map_err(ApiError::from)
+        .and_then(|pool| {
+            let tenant = tenant_by_slug(&pool, tenant_slug)?;
+            seed(&pool, tenant, &load_fixtures(file)?)
+        });
+    match result {
+        Ok(report) => {
+            for email in &report.created {
+                println!("Created {}", email);
+            }
+            for email in &report.skipped {
+                println!("Skipped {}, the email is in use", email);
+            }
+            println!("{} user(s) created, {} skipped", report.created.len(), report.skipped.len());
+            std::process::exit(0);
+        }
+        Err(error) => {
+            eprintln!("Could not seed users: {:?}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Create the first admin of a fresh install or a new tenant, the password is read from the terminal
+pub fn create_admin(email: &str, first_name: &str, last_name: &str, tenant_slug: &str) -> ! {
+    let result = init_pool::<MysqlConnection>(CONFIG.clone())
+        .map(PoolType::from)
+        .map_err(ApiError::from)
+        .and_then(|pool| {
+            let tenant = tenant_by_slug(&pool, tenant_slug)?;
+            let conn = pool.get()?;
+            if admin_exists(&conn, tenant)? {
+                return Err(ApiError::BadRequest("An admin already exists, manage users through the API".into()));
+            }
+            if !emails_in_use(&conn, tenant, &[normalize_email(email)])?.is_empty() {
+                return Err(ApiError::BadRequest(format!("{} is already in use", email)));
+            }
+            let password = prompt_password()?;
+            check_password_strength("password", &password, &[first_name, last_name, email])?;
+            let fixture = Fixture {
+                id: None,
+                first_name: first_name.into(),
+                last_name: last_name.into(),
+                email: email.into(),
+                password,
+                is_admin: true,
+            };
+            create(&conn, &fixture_user(&fixture, tenant))
+        });
+    match result {
+        Ok(admin) => {
+            println!("Created admin {} ({})", admin.email, admin.id);
+            std::process::exit(0);
+        }
+        Err(error) => {
+            eprintln!("Could not create the admin: {:?}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn tenant_by_slug(pool: &PoolType, slug: &str) -> Result<TenantId, ApiError> {
+    let conn = pool.get()?;
+    let tenant = find_by_slug(&conn, slug)?.ok_or_else(|| ApiError::NotFound(format!("Tenant {} not found", slug)))?;
+    Ok(TenantId(tenant.id))
+}
+
+fn prompt_password() -> Result<String, ApiError> {
+    let read = |prompt| {
+        rpassword::read_password_from_tty(Some(prompt))
+            .map_err(|error| ApiError::BadRequest(format!("Cannot read the password: {}", error)))
+    };
+    let password = read("Password: ")?;
+    if read("Repeat the password: ")? != password {
+        return Err(ApiError::BadRequest("The passwords do not match".into()));
+    }
+    Ok(password)
+}
+
+/// Permanently remove the users that were deleted longer than USER_RETENTION ago, in every tenant
+pub fn purge_deleted_users() -> ! {
+    let result = init_pool::<MysqlConnection>(CONFIG.clone())
+        .map(PoolType::from)
+        .map_err(ApiError::from)
+        .and_then(|pool| {
+            let deleted_before = retention_cutoff()?;
+            let conn = pool.get()?;
+            let tenants = all_ids(&conn)?;
+            tenants.into_iter().try_fold(0, |purged, tenant| {
+                Ok(purged + unit_of_work(&pool, |conn| purge(conn, tenant, deleted_before))?)
+            })
+        });
+    match result {
+        Ok(purged) => {
+            println!("Purged {} deleted user(s)", purged);
+            std::process::exit(0);
+        }
+        Err(error) => {
+            eprintln!("Could not purge deleted users: {:?}", error);
+            std::process::exit(1);
+        }
+    }
+}
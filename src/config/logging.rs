@@ -0,0 +1,39 @@
+//! Set up the global logger
+//!
+//! `LOG_FORMAT=text` (default) keeps the plain env_logger output.
+//! `LOG_FORMAT=json` writes every record as a single JSON object, access log
+//! entries are already JSON and are written as is.
+
+use super::CONFIG;
+use crate::middleware::access_log::ACCESS_LOG_TARGET;
+use chrono::Utc;
+use serde_json::json;
+use std::io::Write;
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Initialize env_logger using the configured format, levels come from RUST_LOG
+pub fn init_logger() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if CONFIG.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            if record.target() == ACCESS_LOG_TARGET {
+                return writeln!(buf, "{}", record.args());
+            }
+            let entry = json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", entry)
+        });
+    }
+    builder.init();
+}
@@ -1,39 +1,260 @@
-//! Inject dotenv and env variables into the Config struct
+//! Layered configuration, loaded into the Config struct
 //!
-//! The envy crate injects environment variables into a struct.
+//! Values come from (later layers win): built-in defaults, a TOML file
+//! (`--config`, `CONFIG_FILE` or ./config.toml), environment variables
+//! (augmented by a .env file through dotenv) and command line flags.
 //!
-//! dotenv allows environment variables to be augmented/overwriten by a
-//! .env file.
+//! Every field is parsed into its own type and all problems are collected
+//! into a single report, instead of failing on the first one.
+//! Secrets are checked as well, see the secrets module.
 //!
 //! This file throws the Config struct into a CONFIG lazy_static to avoid
 //! multiple processing.
 
+use crate::cli::{Opts, OPTS};
+use crate::config::layers::{
+    parse_bool, parse_duration, parse_enum, parse_ip_list, parse_list, parse_optional, parse_url_list, ConfigReport,
+    Layers,
+};
+use crate::config::logging::LogFormat;
+use crate::config::secrets::{audit_secrets, fatal_problems, AppEnv};
+use crate::config::telemetry::TraceExporter;
 use crate::database::connection::DatabaseConnection;
+use crate::models::tenant::is_valid_slug;
+use crate::outbox::sinks::SinkKind;
+use actix_web::cookie::SameSite;
 use dotenv::dotenv;
-use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::Duration;
 
+pub mod layers;
+pub mod logging;
+pub mod secrets;
+pub mod telemetry;
 pub mod tls;
 
+const SECOND: Duration = Duration::from_secs(1);
+const MINUTE: Duration = Duration::from_secs(60);
 
-#[derive(Clone, Deserialize, Debug)]
+/// Keys of all configuration values, environment variables are the uppercase versions
+const KEYS: [&str; 59] = [
+    "actix_ssl_cert_file",
+    "actix_ssl_key_file",
+    "app_env",
+    "auth_salt",
+    "auth_salt_file",
+    "auto_migrate",
+    "database",
+    "database_connect_backoff",
+    "database_connect_retries",
+    "database_pool_max_lifetime",
+    "database_pool_max_size",
+    "database_pool_min_idle",
+    "database_pool_test_on_checkout",
+    "database_pool_timeout",
+    "database_replica_retry",
+    "database_replica_timeout",
+    "database_replica_urls",
+    "database_replica_urls_file",
+    "database_url",
+    "database_url_file",
+    "jwt_expiration",
+    "jwt_key",
+    "jwt_key_file",
+    "log_format",
+    "otlp_endpoint",
+    "outbox_batch_size",
+    "outbox_log_file",
+    "outbox_poll_interval",
+    "outbox_redis_stream",
+    "outbox_retention",
+    "outbox_sinks",
+    "outbox_webhook_urls",
+    "outbox_webhook_urls_file",
+    "password_min_classes",
+    "password_min_length",
+    "port",
+    "read_your_writes_window",
+    "redis_url",
+    "registration_auto_login",
+    "registration_email_domains",
+    "registration_enabled",
+    "registration_invite_only",
+    "registration_invite_ttl",
+    "rust_log",
+    "secure_port",
+    "server",
+    "session_key",
+    "session_key_file",
+    "session_name",
+    "session_samesite",
+    "session_secure",
+    "session_timeout",
+    "tenant_default",
+    "tenant_domain",
+    "trace_exporter",
+    "trace_file",
+    "trusted_proxies",
+    "user_retention",
+    "user_search_fulltext",
+];
+
+/// Keys that can be read from a file named by `<KEY>_FILE`
+const FILE_KEYS: [&str; 6] = [
+    "auth_salt",
+    "database_replica_urls",
+    "database_url",
+    "jwt_key",
+    "outbox_webhook_urls",
+    "session_key",
+];
+
+const DEFAULTS: [(&str, &str); 49] = [
+    ("actix_ssl_cert_file", "./.certs/ssl_cert.pem"),
+    ("actix_ssl_key_file", "./.certs/ssl_key.pem"),
+    ("app_env", "development"),
+    ("auto_migrate", "false"),
+    ("database", "mysql"),
+    ("database_connect_backoff", "1s"),
+    ("database_connect_retries", "5"),
+    ("database_pool_max_lifetime", "30m"),
+    ("database_pool_max_size", "10"),
+    ("database_pool_min_idle", ""),
+    ("database_pool_test_on_checkout", "true"),
+    ("database_pool_timeout", "30s"),
+    ("database_replica_retry", "30s"),
+    ("database_replica_timeout", "1s"),
+    ("database_replica_urls", ""),
+    ("jwt_expiration", "24h"),
+    ("log_format", "text"),
+    ("otlp_endpoint", "http://localhost:4317"),
+    ("outbox_batch_size", "100"),
+    ("outbox_log_file", "./outbox.log"),
+    ("outbox_poll_interval", "1s"),
+    ("outbox_redis_stream", "user-events"),
+    ("outbox_retention", "7d"),
+    ("outbox_sinks", ""),
+    ("outbox_webhook_urls", ""),
+    ("password_min_classes", "3"),
+    ("password_min_length", "10"),
+    ("port", "8080"),
+    ("read_your_writes_window", "5s"),
+    ("redis_url", "127.0.0.1:6379"),
+    ("registration_auto_login", "false"),
+    ("registration_email_domains", ""),
+    ("registration_enabled", "false"),
+    ("registration_invite_only", "false"),
+    ("registration_invite_ttl", "7d"),
+    ("rust_log", "actix_web=info,actix_server=info"),
+    ("secure_port", "8443"),
+    ("server", "127.0.0.1"),
+    ("session_name", "auth"),
+    ("session_samesite", "lax"),
+    ("session_secure", "true"),
+    ("session_timeout", "20m"),
+    ("tenant_default", "default"),
+    ("tenant_domain", ""),
+    ("trace_exporter", "none"),
+    ("trace_file", "./traces.log"),
+    ("trusted_proxies", ""),
+    ("user_retention", "30d"),
+    ("user_search_fulltext", "true"),
+];
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl From<SameSitePolicy> for SameSite {
+    fn from(policy: SameSitePolicy) -> Self {
+        match policy {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Config {
+    pub app_env: AppEnv,
     pub auth_salt: String,
+    /// Apply pending migrations on startup instead of refusing to start
+    pub auto_migrate: bool,
     pub database: DatabaseConnection,
+    /// Wait before the first retry to connect at startup, doubled for every further retry
+    pub database_connect_backoff: Duration,
+    /// How often to retry connecting at startup before giving up
+    pub database_connect_retries: u32,
+    /// Close connections older than this, None keeps them open
+    pub database_pool_max_lifetime: Option<Duration>,
+    pub database_pool_max_size: u32,
+    /// Idle connections the pool keeps open, None keeps DATABASE_POOL_MAX_SIZE
+    pub database_pool_min_idle: Option<u32>,
+    /// Check a connection with a query before handing it out
+    pub database_pool_test_on_checkout: bool,
+    /// How long to wait for a free connection
+    pub database_pool_timeout: Duration,
+    /// Read-only replicas, empty reads from the primary
+    pub database_replica_urls: Vec<String>,
+    /// How long a failing replica is skipped before it is tried again
+    pub database_replica_retry: Duration,
+    /// How long to wait for a replica connection before falling back to the primary
+    pub database_replica_timeout: Duration,
     pub database_url: String,
-    pub jwt_expiration: i64,
+    pub jwt_expiration: Duration,
     pub jwt_key: String,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: String,
+    /// Most events the outbox publisher claims per poll
+    pub outbox_batch_size: i64,
+    pub outbox_log_file: String,
+    pub outbox_poll_interval: Duration,
+    pub outbox_redis_stream: String,
+    /// How long delivered outbox events are kept
+    pub outbox_retention: Duration,
+    /// Where outbox events are published, empty only marks them delivered
+    pub outbox_sinks: Vec<SinkKind>,
+    pub outbox_webhook_urls: Vec<String>,
+    pub password_min_classes: usize,
+    pub password_min_length: usize,
     pub redis_url: String,
+    pub registration_auto_login: bool,
+    /// Lowercase domains allowed to self-register, empty allows every domain
+    pub registration_email_domains: Vec<String>,
+    pub registration_enabled: bool,
+    pub registration_invite_only: bool,
+    pub registration_invite_ttl: Duration,
     pub rust_log: String,
     pub server: String,
-    pub port: String,
-    pub secure_port: String,
+    pub port: u16,
+    /// How long a user's reads go to the primary after their own write
+    pub read_your_writes_window: Duration,
+    pub secure_port: u16,
     pub session_key: String,
     pub session_name: String,
     pub session_secure: bool,
-    pub session_samesite: String,
-    pub session_timeout: i64,
+    pub session_samesite: SameSitePolicy,
+    pub session_timeout: Duration,
+    /// Slug of the tenant for requests that name none, empty makes naming one mandatory
+    pub tenant_default: String,
+    /// Domain whose subdomains are tenant slugs, eg. "example.com" for acme.example.com
+    pub tenant_domain: String,
+    pub trace_exporter: TraceExporter,
+    pub trace_file: String,
+    /// Proxies whose X-Forwarded-For header names the client, eg. a load balancer
+    pub trusted_proxies: Vec<IpAddr>,
+    /// How long soft deleted users are kept before they can be purged
+    pub user_retention: Duration,
+    /// Search users with the FULLTEXT index, a prefix LIKE is used otherwise
+    pub user_search_fulltext: bool,
     pub actix_ssl_cert_file: String,
-    pub actix_ssl_key_file: String
+    pub actix_ssl_key_file: String,
 }
 
 // Throw the Config struct into a CONFIG lazy_static to avoid multiple processing
@@ -41,22 +262,182 @@ lazy_static! {
     pub static ref CONFIG: Config = get_config();
 }
 
-/// Use envy to inject dotenv and env vars into the Config struct
+/// Load the config or print the report and exit
 fn get_config() -> Config {
-    dotenv().ok();
-    match envy::from_env::<Config>() {
+    match load_config(&OPTS) {
         Ok(config) => config,
-        Err(error) => panic!("Configuration Error: {:#?}", error),
+        Err(report) => {
+            eprintln!("{}", report);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Collect all layers and parse them into a Config
+pub fn load_config(opts: &Opts) -> Result<Config, ConfigReport> {
+    dotenv().ok();
+    parse_config(Layers::load(&KEYS, &DEFAULTS, &FILE_KEYS, opts))
+}
+
+fn parse_config(layers: Layers) -> Result<Config, ConfigReport> {
+    let mut fields = layers.reader();
+    let config = Config {
+        app_env: fields.parse("app_env", parse_enum),
+        auth_salt: fields.required("auth_salt"),
+        auto_migrate: fields.parse("auto_migrate", parse_bool),
+        database: fields.parse("database", parse_enum),
+        database_connect_backoff: fields.parse("database_connect_backoff", |value| parse_duration(value, SECOND)),
+        database_connect_retries: fields.value("database_connect_retries"),
+        database_pool_max_lifetime: fields.parse("database_pool_max_lifetime", |value| {
+            parse_duration(value, SECOND).map(|lifetime| Some(lifetime).filter(|lifetime| lifetime.as_secs() > 0))
+        }),
+        database_pool_max_size: fields.value("database_pool_max_size"),
+        database_pool_min_idle: fields.parse("database_pool_min_idle", parse_optional),
+        database_pool_test_on_checkout: fields.parse("database_pool_test_on_checkout", parse_bool),
+        database_pool_timeout: fields.parse("database_pool_timeout", |value| parse_duration(value, SECOND)),
+        database_replica_urls: fields.parse("database_replica_urls", parse_url_list),
+        database_replica_retry: fields.parse("database_replica_retry", |value| parse_duration(value, SECOND)),
+        database_replica_timeout: fields.parse("database_replica_timeout", |value| parse_duration(value, SECOND)),
+        database_url: fields.required("database_url"),
+        jwt_expiration: fields.parse("jwt_expiration", |value| parse_duration(value, SECOND)),
+        jwt_key: fields.required("jwt_key"),
+        log_format: fields.parse("log_format", parse_enum),
+        otlp_endpoint: fields.required("otlp_endpoint"),
+        outbox_batch_size: fields.value("outbox_batch_size"),
+        outbox_log_file: fields.required("outbox_log_file"),
+        outbox_poll_interval: fields.parse("outbox_poll_interval", |value| parse_duration(value, SECOND)),
+        outbox_redis_stream: fields.required("outbox_redis_stream"),
+        outbox_retention: fields.parse("outbox_retention", |value| parse_duration(value, SECOND)),
+        outbox_sinks: fields.parse("outbox_sinks", |value| {
+            parse_list(value)?.iter().map(|sink| parse_enum(sink)).collect()
+        }),
+        outbox_webhook_urls: fields.parse("outbox_webhook_urls", parse_url_list),
+        password_min_classes: fields.value("password_min_classes"),
+        password_min_length: fields.value("password_min_length"),
+        redis_url: fields.required("redis_url"),
+        registration_auto_login: fields.parse("registration_auto_login", parse_bool),
+        registration_email_domains: fields.parse("registration_email_domains", parse_list),
+        registration_enabled: fields.parse("registration_enabled", parse_bool),
+        registration_invite_only: fields.parse("registration_invite_only", parse_bool),
+        registration_invite_ttl: fields.parse("registration_invite_ttl", |value| parse_duration(value, SECOND)),
+        rust_log: fields.required("rust_log"),
+        server: fields.required("server"),
+        port: fields.value("port"),
+        read_your_writes_window: fields.parse("read_your_writes_window", |value| parse_duration(value, SECOND)),
+        secure_port: fields.value("secure_port"),
+        session_key: fields.required("session_key"),
+        session_name: fields.required("session_name"),
+        session_secure: fields.parse("session_secure", parse_bool),
+        session_samesite: fields.parse("session_samesite", parse_enum),
+        session_timeout: fields.parse("session_timeout", |value| parse_duration(value, MINUTE)),
+        tenant_default: fields.value("tenant_default"),
+        tenant_domain: fields.parse("tenant_domain", |value| Ok(value.trim_matches('.').to_lowercase())),
+        trace_exporter: fields.parse("trace_exporter", parse_enum),
+        trace_file: fields.required("trace_file"),
+        trusted_proxies: fields.parse("trusted_proxies", parse_ip_list),
+        user_retention: fields.parse("user_retention", |value| parse_duration(value, SECOND)),
+        user_search_fulltext: fields.parse("user_search_fulltext", parse_bool),
+        actix_ssl_cert_file: fields.required("actix_ssl_cert_file"),
+        actix_ssl_key_file: fields.required("actix_ssl_key_file"),
+    };
+
+    if config.port != 0 && config.port == config.secure_port {
+        fields.error("secure_port", "must differ from PORT".into());
+    }
+    if config.session_samesite == SameSitePolicy::None && !config.session_secure {
+        fields.error("session_samesite", "none requires SESSION_SECURE=true".into());
+    }
+    if config.password_min_classes < 1 || config.password_min_classes > 4 {
+        fields.error("password_min_classes", "must be between 1 and 4".into());
+    }
+    // Shorter passwords would not pass the login validation
+    if config.password_min_length < 6 {
+        fields.error("password_min_length", "must be at least 6".into());
+    }
+    if config.database_pool_max_size < 1 {
+        fields.error("database_pool_max_size", "must be at least 1".into());
+    }
+    if config.database_pool_min_idle.is_some_and(|min_idle| min_idle > config.database_pool_max_size) {
+        fields.error("database_pool_min_idle", "must not exceed DATABASE_POOL_MAX_SIZE".into());
+    }
+    // The async pools connect lazily, so a URL they cannot use would only show up with the first query
+    #[cfg(feature = "async-db")]
+    {
+        use sqlx::mysql::MySqlConnectOptions;
+        if config.database_url.parse::<MySqlConnectOptions>().is_err() {
+            fields.error("database_url", "is not a valid MySQL URL for the async pool".into());
+        }
+        if config.database_replica_urls.iter().any(|url| url.parse::<MySqlConnectOptions>().is_err()) {
+            fields.error("database_replica_urls", "has an invalid MySQL URL for the async pool".into());
+        }
+    }
+    if config.outbox_batch_size < 1 {
+        fields.error("outbox_batch_size", "must be at least 1".into());
+    }
+    if config.outbox_poll_interval.as_millis() == 0 {
+        fields.error("outbox_poll_interval", "must not be zero".into());
+    }
+    if config.outbox_sinks.contains(&SinkKind::Webhook) && config.outbox_webhook_urls.is_empty() {
+        fields.error("outbox_webhook_urls", "must not be empty with the webhook sink".into());
+    }
+    if config.outbox_sinks.contains(&SinkKind::Redis) && config.redis_url.is_empty() {
+        fields.error("redis_url", "must not be empty with the redis outbox sink".into());
+    }
+    if !config.tenant_default.is_empty() && !is_valid_slug(&config.tenant_default) {
+        fields.error("tenant_default", "must be a tenant slug".into());
+    }
+    if config.session_timeout.as_secs() < 60 {
+        fields.error("session_timeout", "must be at least one minute".into());
+    }
+    let mut problems = fatal_problems(&config);
+    if config.app_env == AppEnv::Production {
+        problems.extend(audit_secrets(&config));
+    }
+    for problem in problems {
+        fields.error(&problem.key, problem.message);
+    }
+    fields.finish(config)
+}
+
+/// Validate the configuration for --check-config
+///
+/// Prints either the report or a confirmation and exits the process.
+pub fn check_config() -> ! {
+    match load_config(&OPTS) {
+        Ok(config) => {
+            println!(
+                "Configuration OK: https://{}:{}, http://{}:{}, database {:?}",
+                config.server, config.secure_port, config.server, config.port, config.database
+            );
+            std::process::exit(0);
+        }
+        Err(report) => {
+            eprintln!("{}", report);
+            std::process::exit(1);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::layers::Source;
+
+    fn get_layers() -> Layers {
+        let mut layers = Layers::default();
+        for (key, value) in DEFAULTS.iter() {
+            layers.set(key, value.to_string(), Source::Default);
+        }
+        layers.set("database_url", "mysql://localhost/test".into(), Source::Environment);
+        for key in ["auth_salt", "jwt_key", "session_key"].iter() {
+            layers.set(key, "wpX3r8mZq1LkT9vB6nJ2hC5yD0fG7sA4".into(), Source::Environment);
+        }
+        layers
+    }
 
     #[test]
     fn it_gets_a_config() {
-        let config = get_config();
+        let config = load_config(&Opts::default()).unwrap();
         assert_ne!(config.server, "".to_string());
     }
 
@@ -65,4 +446,125 @@ mod tests {
         let config = &CONFIG;
         assert_ne!(config.server, "".to_string());
     }
+
+    #[test]
+    fn it_parses_typed_values() {
+        let mut layers = get_layers();
+        layers.set("session_samesite", "Strict".into(), Source::Environment);
+        layers.set("session_timeout", "30".into(), Source::Environment);
+        layers.set("jwt_expiration", "2h".into(), Source::CommandLine);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.session_samesite, SameSitePolicy::Strict);
+        assert_eq!(config.session_timeout, Duration::from_secs(30 * 60));
+        assert_eq!(config.jwt_expiration, Duration::from_secs(2 * 60 * 60));
+    }
+
+    #[test]
+    fn it_parses_the_registration_domains() {
+        let mut layers = get_layers();
+        layers.set("registration_email_domains", "Example.com, ,example.org".into(), Source::Environment);
+        let config = parse_config(layers).unwrap();
+        assert!(!config.registration_enabled);
+        assert_eq!(config.registration_email_domains, vec!["example.com", "example.org"]);
+    }
+
+    #[test]
+    fn it_parses_the_pool_settings() {
+        let mut layers = get_layers();
+        layers.set("database_pool_max_lifetime", "0".into(), Source::Environment);
+        layers.set("database_pool_min_idle", "2".into(), Source::Environment);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.database_pool_max_lifetime, None);
+        assert_eq!(config.database_pool_min_idle, Some(2));
+        assert_eq!(config.database_pool_timeout, Duration::from_secs(30));
+
+        let mut layers = get_layers();
+        layers.set("database_pool_min_idle", "11".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        assert_eq!(report.0[0].key, "database_pool_min_idle");
+    }
+
+    #[test]
+    fn it_reads_the_replica_urls_from_a_file() {
+        let path = std::env::temp_dir().join("actix_simple_bp_test_replica_urls");
+        std::fs::write(&path, "mysql://replica-1/test, mysql://replica-2/test\n").unwrap();
+        let mut layers = get_layers();
+        layers.set("database_replica_urls_file", path.display().to_string(), Source::Environment);
+        layers.resolve_files(&FILE_KEYS);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.database_replica_urls, vec!["mysql://replica-1/test", "mysql://replica-2/test"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_reads_the_webhook_urls_from_a_file() {
+        let path = std::env::temp_dir().join("actix_simple_bp_test_webhook_urls");
+        std::fs::write(&path, "https://hooks.example.com/users?token=secret\n").unwrap();
+        let mut layers = get_layers();
+        layers.set("outbox_sinks", "webhook".into(), Source::Environment);
+        layers.set("outbox_webhook_urls_file", path.display().to_string(), Source::Environment);
+        layers.resolve_files(&FILE_KEYS);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.outbox_webhook_urls, vec!["https://hooks.example.com/users?token=secret"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_parses_the_outbox_sinks() {
+        let mut layers = get_layers();
+        layers.set("outbox_sinks", "Log, redis".into(), Source::Environment);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.outbox_sinks, vec![SinkKind::Log, SinkKind::Redis]);
+
+        let mut layers = get_layers();
+        layers.set("outbox_sinks", "webhook".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        assert_eq!(report.0[0].key, "outbox_webhook_urls");
+    }
+
+    #[test]
+    fn it_checks_the_tenant_settings() {
+        let mut layers = get_layers();
+        layers.set("tenant_domain", ".Example.com".into(), Source::Environment);
+        layers.set("tenant_default", "".into(), Source::Environment);
+        let config = parse_config(layers).unwrap();
+        assert_eq!(config.tenant_domain, "example.com");
+        assert_eq!(config.tenant_default, "");
+
+        let mut layers = get_layers();
+        layers.set("tenant_default", "Acme Inc".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        assert_eq!(report.0[0].key, "tenant_default");
+    }
+
+    #[test]
+    fn it_reports_all_invalid_values() {
+        let mut layers = get_layers();
+        layers.set("port", "http".into(), Source::Environment);
+        layers.set("session_samesite", "loose".into(), Source::Environment);
+        layers.set("session_secure", "maybe".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        let keys: Vec<&str> = report.0.iter().map(|error| error.key.as_str()).collect();
+        assert_eq!(keys, vec!["port", "session_secure", "session_samesite"]);
+    }
+
+    #[test]
+    fn it_refuses_example_secrets_in_production() {
+        let mut layers = get_layers();
+        layers.set("app_env", "production".into(), Source::Environment);
+        layers.set("jwt_key", "4125442A472D4B614E645267556B58703273357638792F423F4528482B4D6251".into(), Source::Environment);
+        layers.set("session_secure", "false".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        let keys: Vec<&str> = report.0.iter().map(|error| error.key.as_str()).collect();
+        assert_eq!(keys, vec!["jwt_key", "session_secure"]);
+    }
+
+    #[test]
+    fn it_refuses_a_short_session_key() {
+        let mut layers = get_layers();
+        layers.set("session_key", "short".into(), Source::Environment);
+        let report = parse_config(layers).unwrap_err();
+        assert_eq!(report.0[0].key, "session_key");
+    }
 }
@@ -0,0 +1,139 @@
+//! Sanity checks for the configured secrets
+//!
+//! Keys shorter than 32 bytes are always rejected, `RedisSession` and
+//! `CookieIdentityPolicy` would panic on them later on.
+//! Known example values, short or low-entropy secrets and insecure session
+//! cookies are rejected in production and logged as warnings otherwise.
+
+use super::layers::ConfigError;
+use super::Config;
+use std::collections::HashMap;
+
+/// Minimum key length required by the cookie `Key` derivation
+const MIN_COOKIE_KEY_LENGTH: usize = 32;
+
+/// Minimum Shannon entropy of a secret, per character and in total
+const MIN_BITS_PER_CHAR: f64 = 3.0;
+const MIN_TOTAL_BITS: f64 = 80.0;
+
+/// Values shipped in .env.example or commonly used as placeholders
+const KNOWN_SECRETS: [&str; 7] = [
+    "URSCSDTKALAPOOLECOORTWSDAERT",
+    "4125442A472D4B614E645267556B58703273357638792F423F4528482B4D6251",
+    "89A6267556B5230227390442A47A810614EE786738792F4CC22528411B402B81",
+    "secret",
+    "changeme",
+    "password",
+    "123456",
+];
+
+/// (key, minimum length) of every secret
+const SECRETS: [(&str, usize); 3] = [("auth_salt", 24), ("jwt_key", 32), ("session_key", 32)];
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AppEnv {
+    #[default]
+    Development,
+    Production,
+}
+
+/// Problems that break the server regardless of the environment
+pub fn fatal_problems(config: &Config) -> Vec<ConfigError> {
+    let mut problems = vec![];
+    if config.session_key.len() < MIN_COOKIE_KEY_LENGTH {
+        problems.push(problem(
+            "session_key",
+            format!("must be at least {} bytes long", MIN_COOKIE_KEY_LENGTH),
+        ));
+    }
+    problems
+}
+
+/// Hardening problems, errors in production and warnings in development
+pub fn audit_secrets(config: &Config) -> Vec<ConfigError> {
+    let mut problems = vec![];
+    for (key, min_length) in SECRETS.iter() {
+        let value = secret_value(config, key);
+        if value.is_empty() {
+            continue;
+        }
+        if is_known_secret(value) {
+            problems.push(problem(key, "uses a publicly known example value".into()));
+        } else if value.len() < *min_length {
+            problems.push(problem(
+                key,
+                format!("is too short, use at least {} characters", min_length),
+            ));
+        } else if is_low_entropy(value) {
+            problems.push(problem(
+                key,
+                "has too little entropy, use a randomly generated value".into(),
+            ));
+        }
+    }
+    if !config.session_secure {
+        problems.push(problem(
+            "session_secure",
+            "must be true, the server only serves session cookies over TLS".into(),
+        ));
+    }
+    problems
+}
+
+fn secret_value<'a>(config: &'a Config, key: &str) -> &'a str {
+    match key {
+        "auth_salt" => &config.auth_salt,
+        "jwt_key" => &config.jwt_key,
+        "session_key" => &config.session_key,
+        _ => "",
+    }
+}
+
+fn problem(key: &str, message: String) -> ConfigError {
+    ConfigError {
+        key: key.into(),
+        message,
+    }
+}
+
+fn is_known_secret(value: &str) -> bool {
+    KNOWN_SECRETS
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(value))
+}
+
+/// Estimate the entropy of a value from its character distribution
+fn is_low_entropy(value: &str) -> bool {
+    let mut counts = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let length = value.chars().count() as f64;
+    let bits_per_char: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / length;
+            -p * p.log2()
+        })
+        .sum();
+    bits_per_char < MIN_BITS_PER_CHAR || bits_per_char * length < MIN_TOTAL_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_detects_known_secrets() {
+        assert!(is_known_secret("urscsdtkalapoolecoortwsdaert"));
+        assert!(!is_known_secret("wpX3r8mZq1LkT9vB6nJ2hC5yD0fG7sA4"));
+    }
+
+    #[test]
+    fn it_detects_low_entropy() {
+        assert!(is_low_entropy(&"a".repeat(64)));
+        assert!(is_low_entropy(&"ab".repeat(32)));
+        assert!(!is_low_entropy("wpX3r8mZq1LkT9vB6nJ2hC5yD0fG7sA4"));
+    }
+}
@@ -0,0 +1,93 @@
+//! Set up tracing spans and their OpenTelemetry export
+//!
+//! `TRACE_EXPORTER` selects where finished spans go:
+//! `none` (default), `otlp` (sent to `OTLP_ENDPOINT`), `stdout` or `file`
+//! (appended to `TRACE_FILE`). W3C `traceparent` headers are used for
+//! propagation in every mode, `none` included.
+
+use super::CONFIG;
+use opentelemetry::sdk::export::trace::stdout;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{self, Resource};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, KeyValue};
+use std::fs::OpenOptions;
+use std::io::Error;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    #[default]
+    None,
+    Otlp,
+    Stdout,
+    File,
+}
+
+/// Keeps the exporter alive, pending spans are flushed when it is dropped
+pub struct TelemetryGuard {
+    _runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+    }
+}
+
+/// Install the global tracing subscriber and span exporter
+pub fn init_telemetry() -> std::io::Result<TelemetryGuard> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let trace_config = sdk::trace::config().with_resource(Resource::new(vec![
+        KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ]));
+    let mut runtime = None;
+    let tracer = match CONFIG.trace_exporter {
+        TraceExporter::None => {
+            // Spans still get ids so that the trace context is propagated, nothing exports them
+            let provider = sdk::trace::TracerProvider::builder().with_config(trace_config).build();
+            let tracer = provider.get_tracer(env!("CARGO_PKG_NAME"), Some(env!("CARGO_PKG_VERSION")));
+            let _ = global::set_tracer_provider(provider);
+            tracer
+        }
+        TraceExporter::Otlp => {
+            // actix runs on tokio 0.2, the tonic based exporter needs a tokio 1 runtime of its own
+            let exporter_runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("otlp-exporter")
+                .enable_all()
+                .build()?;
+            let tracer = {
+                let _guard = exporter_runtime.enter();
+                opentelemetry_otlp::new_pipeline()
+                    .with_endpoint(&CONFIG.otlp_endpoint)
+                    .with_trace_config(trace_config)
+                    .with_tonic()
+                    .install_batch(opentelemetry::runtime::Tokio)
+            };
+            runtime = Some(exporter_runtime);
+            tracer.map_err(|error| Error::other(error.to_string()))?
+        }
+        TraceExporter::Stdout => stdout::new_pipeline()
+            .with_trace_config(trace_config)
+            .install_simple(),
+        TraceExporter::File => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&CONFIG.trace_file)?;
+            stdout::new_pipeline()
+                .with_writer(file)
+                .with_trace_config(trace_config)
+                .install_simple()
+        }
+    };
+
+    let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|error| Error::other(error.to_string()))?;
+    Ok(TelemetryGuard { _runtime: runtime })
+}
@@ -1,51 +1,105 @@
 //! Database-related functions
-use crate::config::{Config, CONFIG};
-use actix_web::web;
+use crate::config::Config;
+use crate::database::routing::RoutedPool;
+use actix_rt::time::delay_for;
+#[cfg(feature = "async-db")]
+use sqlx::mysql::MySqlPoolOptions;
 use diesel::{
     mysql::MysqlConnection,
     r2d2::{ConnectionManager, PoolError},
     Connection,
 };
+use std::time::Duration;
 
-#[serde(untagged)]
-#[derive(Clone, Deserialize, Debug, PartialEq)]
-#[serde(field_identifier, rename_all = "lowercase")]
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum DatabaseConnection {
+    #[default]
     Mysql,
 }
 
+/// The longest wait between two attempts to connect at startup
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
 pub type Pool<T> = r2d2::Pool<ConnectionManager<T>>;
 pub type MysqlPool = Pool<MysqlConnection>;
 
 #[cfg(feature = "mysql")]
-pub type PoolType = MysqlPool;
+pub type PoolType = RoutedPool;
+
+/// The connection model functions run on, see `transaction::unit_of_work`
+#[cfg(feature = "mysql")]
+pub type Conn = MysqlConnection;
 
 
 #[derive(Clone)]
 pub enum InferPool {
-    Mysql(MysqlPool),
+    Mysql(RoutedPool),
 }
 
 impl InferPool {
     pub fn init_pool(config: Config) -> Result<Self, r2d2::Error> {
         match config.database {
-            DatabaseConnection::Mysql => init_pool::<MysqlConnection>(config).map(InferPool::Mysql),
+            DatabaseConnection::Mysql => RoutedPool::new(config).map(InferPool::Mysql),
         }
         .map_err(Into::into)
     }
+
+    /// Build the pool, retrying with an exponential backoff while the database is not reachable yet
+    pub async fn init_pool_with_retry(config: Config) -> Result<Self, r2d2::Error> {
+        let mut backoff = config.database_connect_backoff;
+        let mut attempt = 1;
+        loop {
+            match InferPool::init_pool(config.clone()) {
+                Ok(pool) => return Ok(pool),
+                Err(error) if attempt <= config.database_connect_retries => {
+                    log::warn!(
+                        "Could not connect to the database (attempt {} of {}), retrying in {:?}: {}",
+                        attempt,
+                        config.database_connect_retries + 1,
+                        backoff,
+                        error
+                    );
+                    delay_for(backoff).await;
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
 }
 
-pub fn init_pool<T>(config: Config) -> Result<Pool<T>, PoolError>
+/// A pool builder with the DATABASE_POOL_* settings
+pub fn pool_builder<T>(config: &Config) -> r2d2::Builder<ConnectionManager<T>>
 where
     T: Connection + 'static,
 {
-    let manager = ConnectionManager::<T>::new(config.database_url);
-    Pool::builder().build(manager)
+    Pool::builder()
+        .max_size(config.database_pool_max_size)
+        .min_idle(config.database_pool_min_idle)
+        .connection_timeout(config.database_pool_timeout)
+        .max_lifetime(config.database_pool_max_lifetime)
+        .test_on_check_out(config.database_pool_test_on_checkout)
 }
 
-pub fn add_pool(cfg: &mut web::ServiceConfig) {
-    let pool = InferPool::init_pool(CONFIG.clone()).expect("Failed to create connection pool");
-    match pool {
-        InferPool::Mysql(mysql_pool) => cfg.data(mysql_pool),
-    };
+/// Options for the async pool with the DATABASE_POOL_* settings
+/// Unlike the r2d2 pool it opens connections on demand unless DATABASE_POOL_MIN_IDLE is set.
+#[cfg(feature = "async-db")]
+pub fn async_pool_options(config: &Config) -> MySqlPoolOptions {
+    MySqlPoolOptions::new()
+        .max_connections(config.database_pool_max_size)
+        .min_connections(config.database_pool_min_idle.unwrap_or(0))
+        .connect_timeout(config.database_pool_timeout)
+        .max_lifetime(config.database_pool_max_lifetime)
+        .test_before_acquire(config.database_pool_test_on_checkout)
+}
+
+/// Build a pool, failing when its idle connections cannot be opened within DATABASE_POOL_TIMEOUT
+pub fn init_pool<T>(config: Config) -> Result<Pool<T>, PoolError>
+where
+    T: Connection + 'static,
+{
+    let manager = ConnectionManager::<T>::new(config.database_url.as_str());
+    pool_builder(&config).build(manager)
 }
@@ -0,0 +1,152 @@
+//! Migrations embedded in the binary, run by diesel_migrations
+//!
+//! build.rs embeds the up.sql and down.sql of every migration in the `migrations` directory,
+//! so the binary alone can run, list and revert them.
+//! Diesel tracks the applied versions in the `__diesel_schema_migrations` table,
+//! so `diesel migration` keeps working alongside `migrate run/revert/status`.
+
+use crate::server_helpers::errors::ApiError;
+use diesel::connection::SimpleConnection;
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel_migrations::{run_migrations, setup_database, Migration, MigrationConnection, RunMigrationsError};
+use std::fmt::Display;
+use std::path::Path;
+
+pub struct EmbeddedMigration {
+    pub name: &'static str,
+    version: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+impl Migration for EmbeddedMigration {
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn run(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.up).map_err(Into::into)
+    }
+
+    fn revert(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.down).map_err(Into::into)
+    }
+
+    /// Diesel names migrations after their directory
+    fn file_path(&self) -> Option<&Path> {
+        Some(Path::new(self.name))
+    }
+}
+
+/// Every migration, oldest first
+pub const MIGRATIONS: &[EmbeddedMigration] = include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub name: &'static str,
+    pub applied: bool,
+}
+
+/// Connect to the database outside of the pool, for commands and the startup check
+pub fn connect(database_url: &str) -> Result<MysqlConnection, ApiError> {
+    MysqlConnection::establish(database_url).map_err(|error| ApiError::PoolError(error.to_string()))
+}
+
+/// Every migration and whether it has been applied, oldest first
+pub fn status(conn: &MysqlConnection) -> Result<Vec<MigrationStatus>, ApiError> {
+    setup_database(conn)?;
+    let applied = conn.previously_run_migration_versions()?;
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            name: migration.name,
+            applied: applied.contains(migration.version),
+        })
+        .collect())
+}
+
+/// The names of the migrations that have not been applied yet, oldest first
+pub fn pending(conn: &MysqlConnection) -> Result<Vec<&'static str>, ApiError> {
+    Ok(status(conn)?
+        .into_iter()
+        .filter(|migration| !migration.applied)
+        .map(|migration| migration.name)
+        .collect())
+}
+
+/// Apply the pending migrations, returns their names
+/// MySQL commits DDL right away, so a failing migration leaves the earlier ones applied
+pub fn run_pending(conn: &MysqlConnection) -> Result<Vec<&'static str>, ApiError> {
+    let pending = pending(conn)?;
+    let migrations = MIGRATIONS.iter().map(|migration| migration as &dyn Migration);
+    run_migrations(conn, migrations, &mut std::io::sink()).map_err(migration_error)?;
+    Ok(pending)
+}
+
+/// Revert the latest applied migration, returns its name or None when nothing is applied
+pub fn revert_latest(conn: &MysqlConnection) -> Result<Option<&'static str>, ApiError> {
+    setup_database(conn)?;
+    let latest = match conn.latest_run_migration_version()? {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+    let migration = MIGRATIONS
+        .iter()
+        .find(|migration| migration.version == latest)
+        .ok_or_else(|| migration_error(format!("version {} is not embedded in this binary", latest)))?;
+    conn.transaction::<_, ApiError, _>(|| {
+        migration.revert(conn).map_err(migration_error)?;
+        diesel::sql_query("DELETE FROM __diesel_schema_migrations WHERE version = ?")
+            .bind::<Text, _>(migration.version)
+            .execute(conn)?;
+        Ok(())
+    })?;
+    Ok(Some(migration.name))
+}
+
+/// Apply pending migrations when AUTO_MIGRATE is set, refuse to start with pending ones otherwise
+pub fn migrate_on_startup(conn: &MysqlConnection, auto_migrate: bool) -> Result<Vec<&'static str>, String> {
+    if auto_migrate {
+        return run_pending(conn).map_err(|error| format!("Could not run migrations: {:?}", error));
+    }
+    let pending = pending(conn).map_err(|error| format!("Could not check migrations: {:?}", error))?;
+    if pending.is_empty() {
+        Ok(vec![])
+    } else {
+        Err(format!(
+            "{} pending migration(s): {}. Run `migrate run` or set AUTO_MIGRATE=true",
+            pending.len(),
+            pending.join(", ")
+        ))
+    }
+}
+
+fn migration_error(error: impl Display) -> ApiError {
+    ApiError::InternalServerError(format!("Migration failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CONFIG;
+
+    #[test]
+    fn it_embeds_every_migration_in_order() {
+        assert_eq!(MIGRATIONS[0].name, "2020-03-04-115154_create_users");
+        assert_eq!(MIGRATIONS[0].version, "20200304115154");
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[0].name < pair[1].name));
+        assert!(MIGRATIONS.iter().all(|migration| !migration.up.is_empty() && !migration.down.is_empty()));
+    }
+
+    #[test]
+    fn it_has_no_pending_migrations() {
+        let conn = connect(&CONFIG.database_url).unwrap();
+        assert_eq!(migrate_on_startup(&conn, true).map(|_| ()), Ok(()));
+        assert_eq!(migrate_on_startup(&conn, false), Ok(vec![]));
+        let migrations = status(&conn).unwrap();
+        assert_eq!(migrations.len(), MIGRATIONS.len());
+        assert!(migrations.iter().all(|migration| migration.applied));
+    }
+}
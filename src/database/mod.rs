@@ -1,2 +1,7 @@
 pub mod schema;
-pub mod connection;
\ No newline at end of file
+pub mod connection;
+pub mod migrations;
+pub mod routing;
+pub mod seed;
+pub mod transaction;
+pub mod types;
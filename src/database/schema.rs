@@ -1,19 +1,82 @@
 table! {
+    use diesel::sql_types::*;
+    use crate::database::types::BinaryUuid;
+
+    audit_log (id) {
+        id -> Bigint,
+        actor_id -> Nullable<BinaryUuid>,
+        action -> Varchar,
+        target_type -> Varchar,
+        target_id -> Nullable<BinaryUuid>,
+        changes -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        request_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::database::types::BinaryUuid;
+
     users (id) {
-        id -> Varchar,
+        id -> BinaryUuid,
         first_name -> Varchar,
         last_name -> Varchar,
         email -> Varchar,
         password -> Varchar,
         salt1 -> Varchar,
         salt2 -> Varchar,
-        created_by -> Varchar,
+        created_by -> BinaryUuid,
         created_at -> Timestamp,
-        updated_by -> Varchar,
+        updated_by -> BinaryUuid,
         updated_at -> Timestamp,
+        is_admin -> Bool,
+        deleted_at -> Nullable<Timestamp>,
+        deleted_by -> Nullable<BinaryUuid>,
+        version -> Integer,
+        email_normalized -> Varchar,
+        tenant_id -> BinaryUuid,
     }
 }
 
+table! {
+    use diesel::sql_types::*;
+    use crate::database::types::BinaryUuid;
+
+    outbox (id) {
+        id -> Bigint,
+        event_type -> Varchar,
+        aggregate_id -> BinaryUuid,
+        payload -> Text,
+        created_at -> Timestamp,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::database::types::BinaryUuid;
+
+    tenants (id) {
+        id -> BinaryUuid,
+        slug -> Varchar,
+        name -> Varchar,
+        created_by -> BinaryUuid,
+        created_at -> Timestamp,
+        updated_by -> BinaryUuid,
+        updated_at -> Timestamp,
+    }
+}
+
+joinable!(users -> tenants (tenant_id));
+
 allow_tables_to_appear_in_same_query!(
+    audit_log,
+    outbox,
+    tenants,
     users,
 );
@@ -0,0 +1,157 @@
+//! Fixture users for development and tests
+//!
+//! Fixtures are read from a YAML or JSON file (by extension) and hashed like any other password.
+//! Seeding is idempotent: users whose email is already in use are skipped, never overwritten.
+
+use crate::database::connection::{Conn, PoolType};
+use crate::database::transaction::unit_of_work;
+use crate::models::tenant::TenantId;
+use crate::models::user::{create_all, emails_in_use, normalize_email, NewUser, User};
+use crate::server_helpers::errors::ApiError;
+use std::path::Path;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// A fixed id lets tests refer to the user in the default tenant, a random one is generated otherwise
+    pub id: Option<Uuid>,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeedReport {
+    pub created: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+/// Read fixtures from a .json file or a .yaml/.yml file
+pub fn load_fixtures(path: &Path) -> Result<Vec<Fixture>, ApiError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|error| ApiError::BadRequest(format!("Cannot read {}: {}", path.display(), error)))?;
+    parse_fixtures(&text, path.extension().and_then(|extension| extension.to_str()))
+}
+
+fn parse_fixtures(text: &str, extension: Option<&str>) -> Result<Vec<Fixture>, ApiError> {
+    match extension {
+        Some("json") => serde_json::from_str(text).map_err(|error| ApiError::BadRequest(error.to_string())),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(text).map_err(|error| ApiError::BadRequest(error.to_string())),
+        _ => Err(ApiError::BadRequest("Fixtures must be a .json, .yaml or .yml file".into())),
+    }
+}
+
+/// Build the user for a fixture in a tenant, hashing its password
+/// Ids are unique across tenants, so fixed ids are only kept in the default tenant
+/// and the same fixtures can be seeded into every tenant.
+pub fn fixture_user(fixture: &Fixture, tenant: TenantId) -> User {
+    let id = fixture.id.filter(|_| tenant == TenantId::DEFAULT);
+    let mut user: User = NewUser {
+        id: id.unwrap_or_else(Uuid::new_v4),
+        tenant_id: tenant,
+        first_name: fixture.first_name.clone(),
+        last_name: fixture.last_name.clone(),
+        email: fixture.email.clone(),
+        password: fixture.password.clone(),
+        created_by: Uuid::nil(),
+        updated_by: Uuid::nil(),
+    }
+    .into();
+    user.is_admin = fixture.is_admin;
+    user
+}
+
+/// Create the fixture users whose email is not in use in the tenant yet
+pub fn seed(pool: &PoolType, tenant: TenantId, fixtures: &[Fixture]) -> Result<SeedReport, ApiError> {
+    unit_of_work(pool, |conn| seed_with(conn, tenant, fixtures))
+}
+
+fn seed_with(conn: &Conn, tenant: TenantId, fixtures: &[Fixture]) -> Result<SeedReport, ApiError> {
+    let emails = fixtures.iter().map(|fixture| normalize_email(&fixture.email)).collect::<Vec<_>>();
+    let mut taken = emails_in_use(conn, tenant, &emails)?;
+    let mut report = SeedReport::default();
+    let mut users = vec![];
+    for (fixture, email) in fixtures.iter().zip(emails) {
+        if taken.contains(&email) {
+            report.skipped.push(fixture.email.clone());
+        } else {
+            users.push(fixture_user(fixture, tenant));
+            report.created.push(fixture.email.clone());
+            taken.push(email);
+        }
+    }
+    if !users.is_empty() {
+        create_all(conn, &users)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::hash;
+    use crate::models::tenant::tests::create_tenant;
+    use crate::tests::helpers::tests::{get_conn, get_pool};
+
+    #[test]
+    fn it_parses_yaml_and_json_fixtures() {
+        let yaml = "- first_name: Hal\n  last_name: Finney\n  email: hal@example.com\n  password: secret\n  is_admin: true\n";
+        let json = r#"[{"first_name": "Hal", "last_name": "Finney", "email": "hal@example.com", "password": "secret", "is_admin": true}]"#;
+        let fixtures = parse_fixtures(yaml, Some("yaml")).unwrap();
+        assert_eq!(fixtures, parse_fixtures(json, Some("json")).unwrap());
+        assert!(fixtures[0].is_admin);
+        assert!(parse_fixtures(yaml, Some("txt")).is_err());
+        assert!(parse_fixtures("- email: hal@example.com\n", Some("yml")).is_err());
+    }
+
+    #[test]
+    fn it_hashes_fixture_passwords() {
+        let fixture = parse_fixtures("[{\"first_name\": \"A\", \"last_name\": \"B\", \"email\": \" A@Example.com\", \"password\": \"secret\"}]", Some("json")).unwrap();
+        let user = fixture_user(&fixture[0], TenantId::DEFAULT);
+        assert_eq!(user.password, hash("secret", &user.salt1));
+        assert_eq!(user.email_normalized, "a@example.com");
+        assert!(!user.is_admin);
+    }
+
+    #[test]
+    fn it_seeds_idempotently() {
+        let fixtures = vec![Fixture {
+            id: None,
+            first_name: "Seed".into(),
+            last_name: "Test".into(),
+            email: format!("seed-test-{}@nothing.org", Uuid::new_v4()),
+            password: "123456".into(),
+            is_admin: false,
+        }];
+        let report = seed(&get_pool(), TenantId::DEFAULT, &fixtures).unwrap();
+        assert_eq!(report.created, vec![fixtures[0].email.clone()]);
+        let report = seed(&get_pool(), TenantId::DEFAULT, &fixtures).unwrap();
+        assert_eq!(report.skipped, vec![fixtures[0].email.clone()]);
+        assert!(report.created.is_empty());
+    }
+
+    fn bundled_fixtures() -> Vec<Fixture> {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/users.yaml"));
+        load_fixtures(path).unwrap()
+    }
+
+    #[test]
+    fn it_loads_the_bundled_fixtures() {
+        assert!(!bundled_fixtures().is_empty());
+    }
+
+    #[test]
+    fn it_seeds_the_bundled_fixtures_into_another_tenant() {
+        let fixtures = bundled_fixtures();
+        seed(&get_pool(), TenantId::DEFAULT, &fixtures).unwrap();
+        let tenant = create_tenant(&get_conn());
+        let report = seed(&get_pool(), tenant, &fixtures).unwrap();
+        let emails = fixtures.iter().map(|fixture| fixture.email.clone()).collect::<Vec<_>>();
+        assert_eq!(report.created, emails);
+        assert!(report.skipped.is_empty());
+    }
+}
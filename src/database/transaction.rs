@@ -0,0 +1,93 @@
+//! Units of work: several model calls on one connection, committed or rolled back together
+//!
+//! Model functions that write take a `&Conn`, so that they can be combined:
+//!
+//! ```ignore
+//! block(move || unit_of_work(&pool, |conn| {
+//!     let updated = update(conn, &update_user, &expected)?;
+//!     record(conn, &entry)?;
+//!     Ok(updated)
+//! }))
+//! ```
+//!
+//! Both functions block, call them from inside `web::block`.
+
+use crate::database::connection::{Conn, PoolType};
+use crate::server_helpers::errors::ApiError;
+use diesel::Connection;
+
+/// Run the work in a transaction on one connection to the primary
+/// It is committed when the work returns Ok and rolled back when it returns an error
+pub fn unit_of_work<T, F>(pool: &PoolType, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&Conn) -> Result<T, ApiError>,
+{
+    let conn = pool.get()?;
+    conn.transaction(|| work(&conn))
+}
+
+/// Run the work in a savepoint of the surrounding transaction
+/// When the work returns an error only its own changes are rolled back, the transaction goes on.
+/// Savepoints nest, outside of a transaction this is a transaction of its own.
+pub fn savepoint<T, F>(conn: &Conn, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&Conn) -> Result<T, ApiError>,
+{
+    conn.transaction(|| work(conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tenant::TenantId;
+    use crate::models::user::{create, find_with, NewUser, User};
+    use crate::tests::helpers::tests::get_pool;
+    use uuid::Uuid;
+
+    fn new_user() -> User {
+        NewUser {
+            id: Uuid::new_v4(),
+            tenant_id: TenantId::DEFAULT,
+            first_name: "Unit".into(),
+            last_name: "Work".into(),
+            email: format!("unit-of-work-{}@nothing.org", Uuid::new_v4()),
+            password: "123456".into(),
+            created_by: Uuid::new_v4(),
+            updated_by: Uuid::new_v4(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_rolls_back_the_whole_unit_on_error() {
+        let pool = get_pool();
+        let user = new_user();
+        let result: Result<(), ApiError> = unit_of_work(&pool, |conn| {
+            create(conn, &user)?;
+            Err(ApiError::BadRequest("fail".into()))
+        });
+        assert!(result.is_err());
+        assert!(find_with(&pool.get().unwrap(), TenantId::DEFAULT, user.id).is_err());
+    }
+
+    #[test]
+    fn it_rolls_back_only_the_failed_savepoint() {
+        let pool = get_pool();
+        let (kept, dropped, nested) = (new_user(), new_user(), new_user());
+        unit_of_work(&pool, |conn| {
+            create(conn, &kept)?;
+            let failed: Result<(), ApiError> = savepoint(conn, |conn| {
+                create(conn, &dropped)?;
+                savepoint(conn, |conn| create(conn, &nested))?;
+                Err(ApiError::BadRequest("fail".into()))
+            });
+            assert!(failed.is_err());
+            Ok(())
+        })
+        .unwrap();
+        let conn = pool.get().unwrap();
+        assert!(find_with(&conn, TenantId::DEFAULT, kept.id).is_ok());
+        assert!(find_with(&conn, TenantId::DEFAULT, dropped.id).is_err());
+        assert!(find_with(&conn, TenantId::DEFAULT, nested.id).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Custom SQL types for the schema
+//!
+//! `BinaryUuid` stores a UUID as its 16 bytes in a `BINARY(16)` column on MySQL.
+//! Diesel only maps `Uuid` to Postgres' native `UUID` type, which a Postgres backend would use instead.
+//! The orphan rules keep us from binding `Uuid` directly, so values are wrapped in `DbUuid`
+//! when written or compared, and read back into plain `Uuid` fields with `deserialize_as`.
+
+use diesel::deserialize::{self, FromSql, Queryable};
+use diesel::mysql::Mysql;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Nullable;
+use std::fmt;
+use std::io::Write;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, Default, QueryId, SqlType)]
+#[mysql_type = "Blob"]
+pub struct BinaryUuid;
+
+/// A UUID bound to or read from a `BinaryUuid` column
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[serde(transparent)]
+#[sql_type = "BinaryUuid"]
+pub struct DbUuid(pub Uuid);
+
+impl ToSql<BinaryUuid, Mysql> for DbUuid {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        out.write_all(self.0.as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<BinaryUuid, Mysql> for DbUuid {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let bytes = bytes.ok_or("Unexpected null for a non-null UUID column")?;
+        Ok(DbUuid(Uuid::from_slice(bytes)?))
+    }
+}
+
+impl fmt::Display for DbUuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<Uuid> for DbUuid {
+    fn from(uuid: Uuid) -> Self {
+        DbUuid(uuid)
+    }
+}
+
+impl From<DbUuid> for Uuid {
+    fn from(uuid: DbUuid) -> Self {
+        uuid.0
+    }
+}
+
+/// Reads a `Nullable<BinaryUuid>` column into an `Option<Uuid>` field with `deserialize_as`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NullableDbUuid(pub Option<Uuid>);
+
+impl Queryable<Nullable<BinaryUuid>, Mysql> for NullableDbUuid {
+    type Row = Option<DbUuid>;
+
+    fn build(row: Self::Row) -> Self {
+        NullableDbUuid(row.map(Uuid::from))
+    }
+}
+
+impl From<NullableDbUuid> for Option<Uuid> {
+    fn from(uuid: NullableDbUuid) -> Self {
+        uuid.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_uuids_from_bytes() {
+        let user_id = Uuid::new_v4();
+        let read = <DbUuid as FromSql<BinaryUuid, Mysql>>::from_sql(Some(user_id.as_bytes())).unwrap();
+        assert_eq!(Uuid::from(read), user_id);
+        assert!(<DbUuid as FromSql<BinaryUuid, Mysql>>::from_sql(Some(b"too short")).is_err());
+        assert!(<DbUuid as FromSql<BinaryUuid, Mysql>>::from_sql(None).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::database::routing::ReadPool;
+use crate::models::audit::{list, AuditEntry, AuditListParams};
+use crate::models::tenant::OperatorUser;
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::{Page, Paginated};
+use crate::server_helpers::response::respond_json;
+use crate::server_helpers::telemetry::block;
+use crate::validate::validate;
+use actix_web::web::{HttpRequest, Json, Query};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEntryResponse {
+    pub id: i64,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    /// Changed fields as {"field": {"before": .., "after": ..}}
+    pub changes: Option<Value>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+pub struct AuditQuery {
+    pub actor_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+
+    #[validate(range(min = 1, message = "limit must be a positive number"))]
+    pub limit: Option<i64>,
+
+    #[validate(range(min = 0, message = "offset must not be negative"))]
+    pub offset: Option<i64>,
+}
+
+/// Get a page of the audit log, newest first
+/// The log covers every tenant, so only operators (admins of the default tenant) may read it.
+pub async fn get_audit_log(
+    pool: ReadPool,
+    query: Query<AuditQuery>,
+    req: HttpRequest,
+    _operator: OperatorUser,
+) -> Result<Json<Paginated<AuditEntryResponse>>, ApiError> {
+    let query = Json(query.into_inner());
+    validate(&query)?;
+
+    let query = query.into_inner();
+    let params = AuditListParams {
+        actor_id: query.actor_id,
+        action: query.action,
+        target_type: query.target_type,
+        target_id: query.target_id,
+        from: query.from,
+        to: query.to,
+        page: Page::new(query.limit, query.offset),
+    };
+    let page = params.page;
+    let (entries, total) = block(move || list(&pool, &params)).await?;
+    let entries = entries.into_iter().map(AuditEntryResponse::from).collect();
+    respond_json(Paginated::new(entries, total, page, &req))
+}
+
+impl From<AuditEntry> for AuditEntryResponse {
+    fn from(entry: AuditEntry) -> Self {
+        AuditEntryResponse {
+            id: entry.id,
+            actor_id: entry.actor_id,
+            action: entry.action,
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            changes: entry.changes.and_then(|changes| serde_json::from_str(&changes).ok()),
+            ip: entry.ip,
+            request_id: entry.request_id,
+            created_at: entry.created_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_read_pool;
+    use actix_web::test;
+
+    #[actix_rt::test]
+    async fn it_gets_the_audit_log() {
+        let operator = OperatorUser { id: Uuid::nil() };
+        let req = test::TestRequest::with_uri("/api/v1/audit").to_http_request();
+        let response = get_audit_log(get_read_pool(), Query(AuditQuery::default()), req, operator).await;
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn it_parses_the_changes() {
+        let entry = AuditEntry {
+            id: 1,
+            actor_id: Some(Uuid::nil()),
+            action: "user.update".into(),
+            target_type: "user".into(),
+            target_id: None,
+            changes: Some(r#"{"first_name":{"after":"Hal","before":"Satoshi"}}"#.into()),
+            ip: None,
+            request_id: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        let response = AuditEntryResponse::from(entry);
+        assert_eq!(response.actor_id, Some(Uuid::nil()));
+        assert_eq!(response.changes.unwrap()["first_name"]["before"], "Satoshi");
+    }
+}
@@ -1,13 +1,17 @@
-use crate::auth::{create_jwt, PrivateClaim};
+use crate::auth::{create_jwt, invalidate_sessions, session_epoch, PrivateClaim, SESSION_EPOCH_KEY, SESSION_TENANT_KEY};
 use crate::database::connection::PoolType;
 use crate::server_helpers::errors::ApiError;
 use crate::handlers::user::UserResponse;
 use actix_session::Session;
+use crate::server_helpers::cache::Cache;
 use crate::server_helpers::response::{respond_json, respond_ok};
-use crate::models::user::find_by_auth;
+use crate::server_helpers::telemetry::block;
+use crate::models::audit::{record, AuditContext, Changes};
+use crate::models::tenant::TenantId;
+use crate::models::user::{change_password, find_by_auth, AuthUser};
 use crate::validate::validate;
 use actix_identity::Identity;
-use actix_web::web::{block, Data, HttpResponse, Json};
+use actix_web::web::{Data, HttpResponse, Json};
 use serde::Serialize;
 use validator::Validate;
 use uuid::Uuid;
@@ -24,22 +28,44 @@ pub struct LoginRequest {
     pub password: String,
 }
 
-/// Login a user
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct ChangePasswordRequest {
+    #[validate(length(min = 1, message = "current_password is required"))]
+    pub current_password: String,
+
+    #[validate(length(min = 1, message = "new_password is required"))]
+    pub new_password: String,
+}
+
+/// Login a user to the tenant of the request
 /// Create and remember their JWT
 pub async fn login(
     id: Identity,
     pool: Data<PoolType>,
     params: Json<LoginRequest>,
-    session: Session
+    session: Session,
+    redis: Cache,
+    tenant: TenantId,
 ) -> Result<Json<UserResponse>, ApiError> {
     validate(&params)?;
 
     // Validate that the email + password matches
-    let user = block(move || find_by_auth(&pool, &params.email, &params.password)).await?;
+    let user = block(move || find_by_auth(&pool, tenant, &params.email, &params.password)).await?;
+    start_session(&id, &session, redis, &user, tenant).await?;
+    respond_json(user.into())
+}
 
+/// Remember a user that just proved who they are, also used for auto-login after registration
+pub async fn start_session(
+    id: &Identity,
+    session: &Session,
+    redis: Cache,
+    user: &UserResponse,
+    tenant: TenantId,
+) -> Result<(), ApiError> {
     //JWT cookie session
     // Create a JWT
-    let private_claim = PrivateClaim::new(user.id, user.email.clone());
+    let private_claim = PrivateClaim::new(user.id, user.email.clone(), tenant);
     let jwt = create_jwt(private_claim)?;
     // Remember the token
     id.remember(jwt);
@@ -51,8 +77,47 @@ pub async fn login(
         Ok(_0) => (),
         Err(e) => return Err(ApiError::InternalServerError(String::from("Could not set session var")))
     }
+    session
+        .set(SESSION_TENANT_KEY, tenant)
+        .map_err(|_| ApiError::InternalServerError("Could not set session var".into()))?;
+    // Stamp the session so that a password change can invalidate it
+    let epoch = session_epoch(redis, user.id).await?;
+    session
+        .set(SESSION_EPOCH_KEY, epoch)
+        .map_err(|_| ApiError::InternalServerError("Could not set session var".into()))?;
     session.renew();
-    respond_json(user.into())
+    Ok(())
+}
+
+/// Change the password of the logged in user
+/// Invalidates all of the user's other sessions and renews the current one
+pub async fn update_password(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<ChangePasswordRequest>,
+    session: Session,
+    redis: Cache,
+    audit: AuditContext,
+    tenant: TenantId,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+
+    let user_id = user.id;
+    block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            change_password(conn, tenant, user_id, &params.current_password, &params.new_password)?;
+            let changes = Changes::redacted(&["password", "salt1"]);
+            record(conn, &audit.entry("user.change_password", "user", Some(user_id), changes))
+        })
+    })
+    .await?;
+
+    let epoch = invalidate_sessions(redis, user_id).await?;
+    session
+        .set(SESSION_EPOCH_KEY, epoch)
+        .map_err(|_| ApiError::InternalServerError("Could not set session var".into()))?;
+    session.renew();
+    respond_ok()
 }
 
 /// Logout a user
@@ -68,8 +133,10 @@ pub async fn logout(id: Identity, session: Session) -> Result<HttpResponse, ApiE
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::tests::helpers::tests::get_data_pool;
+    use crate::config::CONFIG;
+    use crate::tests::helpers::tests::{ensure_test_user, get_data_pool, TEST_USER_EMAIL};
     use actix_identity::Identity;
+    use actix_redis::RedisActor;
     use actix_web::{test, FromRequest};
 
     async fn get_identity() -> Identity {
@@ -82,18 +149,28 @@ pub mod tests {
         identity
     }
 
+    async fn get_session() -> Session {
+        let (request, mut payload) = test::TestRequest::default().to_http_parts();
+        Session::from_request(&request, &mut payload).await.unwrap()
+    }
+
+    fn get_cache() -> Cache {
+        Data::new(RedisActor::start(&CONFIG.redis_url))
+    }
+
     async fn login_user() -> Result<Json<UserResponse>, ApiError> {
+        ensure_test_user();
         let params = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
+            email: TEST_USER_EMAIL.into(),
             password: "123456".into(),
         };
         let identity = get_identity().await;
-        login(identity, get_data_pool(), Json(params)).await
+        login(identity, get_data_pool(), Json(params), get_session().await, get_cache(), TenantId::DEFAULT).await
     }
 
     async fn logout_user() -> Result<HttpResponse, ApiError> {
         let identity = get_identity().await;
-        logout(identity).await
+        logout(identity, get_session().await).await
     }
 
     #[actix_rt::test]
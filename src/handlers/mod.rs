@@ -1,3 +1,7 @@
+pub mod audit;
 pub mod auth;
 pub mod health;
-pub mod user;
\ No newline at end of file
+pub mod registration;
+pub mod tenant;
+pub mod user;
+pub mod user_bulk;
\ No newline at end of file
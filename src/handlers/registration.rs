@@ -0,0 +1,230 @@
+//! Public self-registration, mounted only when REGISTRATION_ENABLED is set
+//!
+//! Registration can be limited to email domains and to invited addresses.
+//! Invites are single use and stored in Redis until they expire.
+
+use crate::auth::check_password_strength;
+use crate::config::CONFIG;
+use crate::database::connection::PoolType;
+use crate::database::transaction::unit_of_work;
+use crate::handlers::auth::start_session;
+use crate::handlers::user::{CreateUserRequest, UserResponse};
+use crate::models::audit::{record, AuditContext, Changes};
+use crate::models::tenant::TenantId;
+use crate::models::user::{create, AdminUser, NewUser, User};
+use crate::server_helpers::cache::{delete, set_ex, Cache};
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::response::respond_json;
+use crate::server_helpers::telemetry::block;
+use crate::validate::validate;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::web::{Data, Json, Query};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RegisterQuery {
+    /// Code of the invite, required in invite-only mode
+    pub invite: Option<String>,
+}
+
+/// What registering needs besides the body, see the extractor
+/// Redis holds the invites, the identity and session are for the auto-login.
+pub struct RegistrationContext {
+    pub identity: Identity,
+    pub session: Session,
+    pub redis: Cache,
+    pub tenant: TenantId,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct InviteRequest {
+    #[validate(email(message = "email must be a valid email"))]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct InviteResponse {
+    pub email: String,
+    pub code: String,
+    pub expires_in: u64,
+}
+
+/// Register a new user in the tenant of the request
+/// Logs the user in as well when REGISTRATION_AUTO_LOGIN is set
+pub async fn register(
+    pool: Data<PoolType>,
+    params: Json<CreateUserRequest>,
+    query: Query<RegisterQuery>,
+    audit: AuditContext,
+    context: RegistrationContext,
+) -> Result<Json<UserResponse>, ApiError> {
+    let RegistrationContext {
+        identity,
+        session,
+        redis,
+        tenant,
+    } = context;
+    validate(&params)?;
+    check_email_domain(&params.email, &CONFIG.registration_email_domains)?;
+    let email_name = params.email.split('@').next().unwrap_or("");
+    check_password_strength(
+        "password",
+        &params.password,
+        &[email_name, &params.first_name, &params.last_name],
+    )?;
+
+    let invite = match (&query.invite, CONFIG.registration_invite_only) {
+        (Some(code), true) => Some(use_invite(redis.clone(), tenant, code, &params.email).await?),
+        (None, true) => {
+            return Err(ApiError::ValidationError(vec![
+                "registration requires an invite".into(),
+            ]))
+        }
+        (_, false) => None,
+    };
+
+    // Self-registered users are created by themselves
+    let user_id = Uuid::new_v4();
+    let new_user: User = NewUser {
+        id: user_id,
+        tenant_id: tenant,
+        first_name: params.first_name.to_string(),
+        last_name: params.last_name.to_string(),
+        email: params.email.to_string(),
+        password: params.password.to_string(),
+        created_by: user_id,
+        updated_by: user_id,
+    }
+    .into();
+    let mut entry = audit.entry("user.register", "user", Some(user_id), Changes::created(&new_user));
+    entry.actor_id = Some(user_id.into());
+    let user = match block(move || {
+        let created = unit_of_work(&pool, |conn| {
+            let created = create(conn, &new_user)?;
+            record(conn, &entry)?;
+            Ok(created)
+        })?;
+        pool.note_write(user_id);
+        Ok(created)
+    })
+    .await
+    {
+        Ok(user) => user,
+        Err(error) => {
+            // Give the invite back, it was not used up
+            if let Some(key) = invite {
+                store_invite(redis, &key).await?;
+            }
+            return Err(error.into());
+        }
+    };
+
+    if CONFIG.registration_auto_login {
+        start_session(&identity, &session, redis, &user, tenant).await?;
+    }
+    respond_json(user)
+}
+
+/// Invite an email address to register, admins only
+pub async fn create_invite(
+    params: Json<InviteRequest>,
+    pool: Data<PoolType>,
+    redis: Cache,
+    _admin: AdminUser,
+    audit: AuditContext,
+    tenant: TenantId,
+) -> Result<Json<InviteResponse>, ApiError> {
+    validate(&params)?;
+    let invite = new_invite(redis, tenant, &params.email).await?;
+    let entry = audit.entry("invite.create", "invite", None, Changes::created(&invite));
+    block(move || audit.unit_of_work(&pool, |conn| record(conn, &entry))).await?;
+    respond_json(invite)
+}
+
+/// Store an invite for an email address to register in a tenant, checking the allowed domains first
+pub async fn new_invite(redis: Cache, tenant: TenantId, email: &str) -> Result<InviteResponse, ApiError> {
+    check_email_domain(email, &CONFIG.registration_email_domains)?;
+    let code = thread_rng().sample_iter(&Alphanumeric).take(32).collect::<String>();
+    store_invite(redis, &invite_key(tenant, &code, email)).await?;
+    Ok(InviteResponse {
+        email: email.to_string(),
+        code,
+        expires_in: CONFIG.registration_invite_ttl.as_secs(),
+    })
+}
+
+/// Take back an invite whose code was not handed out
+pub async fn revoke_invite(redis: Cache, tenant: TenantId, invite: &InviteResponse) -> Result<(), ApiError> {
+    delete(redis, &invite_key(tenant, &invite.code, &invite.email)).await?;
+    Ok(())
+}
+
+/// Check the domain of an email against the allowed ones, an empty list allows all
+pub fn check_email_domain(email: &str, allowed: &[String]) -> Result<(), ApiError> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let domain = email.rsplit('@').next().unwrap_or("").to_lowercase();
+    if allowed.contains(&domain) {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(vec![format!(
+            "registration is not open for {}",
+            domain
+        )]))
+    }
+}
+
+/// The tenant and the invited email are part of the key, so an invite only works for its address in its tenant
+fn invite_key(tenant: TenantId, code: &str, email: &str) -> String {
+    format!("invite:{}:{}:{}", tenant, code, email.to_lowercase())
+}
+
+async fn store_invite(redis: Cache, key: &str) -> Result<(), ApiError> {
+    set_ex(redis, key, "1", CONFIG.registration_invite_ttl).await
+}
+
+/// Use up an invite, returns its key so that it can be given back
+/// Deleting is atomic, so an invite cannot be used twice
+async fn use_invite(redis: Cache, tenant: TenantId, code: &str, email: &str) -> Result<String, ApiError> {
+    let key = invite_key(tenant, code, email);
+    if delete(redis, &key).await? {
+        Ok(key)
+    } else {
+        Err(ApiError::ValidationError(vec![
+            "invite is invalid or has expired".into(),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allows_every_domain_without_a_list() {
+        assert!(check_email_domain("satoshi@nakamotoinstitute.org", &[]).is_ok());
+    }
+
+    #[test]
+    fn it_checks_the_email_domain() {
+        let allowed = vec!["example.com".to_string()];
+        assert!(check_email_domain("satoshi@Example.com", &allowed).is_ok());
+        let expected_error = ApiError::ValidationError(vec![
+            "registration is not open for example.org".to_string(),
+        ]);
+        assert_eq!(check_email_domain("satoshi@example.org", &allowed), Err(expected_error));
+    }
+
+    #[test]
+    fn it_binds_invites_to_an_email() {
+        assert_eq!(
+            invite_key(TenantId::DEFAULT, "abc", "Satoshi@Example.com"),
+            "invite:00000000-0000-0000-0000-000000000000:abc:satoshi@example.com"
+        );
+    }
+}
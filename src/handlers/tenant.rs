@@ -0,0 +1,127 @@
+//! Tenant management for operators, served by the generic resource handlers
+
+use crate::database::connection::Conn;
+use crate::models::tenant::{self, is_valid_slug, OperatorUser, Tenant};
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::Page;
+use crate::server_helpers::resource::Resource;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::ValidationError;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct TenantRequest {
+    #[validate(custom(
+        function = "validate_slug",
+        message = "slug must be lowercase letters, digits and inner hyphens, at most 63 characters"
+    ))]
+    pub slug: String,
+
+    #[validate(length(min = 1, max = 100, message = "name is required and must be at most 100 characters"))]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct TenantResponse {
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<Tenant> for TenantResponse {
+    fn from(tenant: Tenant) -> Self {
+        TenantResponse {
+            id: tenant.id,
+            slug: tenant.slug,
+            name: tenant.name,
+            created_at: tenant.created_at,
+            updated_at: tenant.updated_at,
+        }
+    }
+}
+
+fn validate_slug(slug: &str) -> Result<(), ValidationError> {
+    if is_valid_slug(slug) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("slug"))
+    }
+}
+
+/// Tenants at /api/v1/tenant, operators only
+pub struct Tenants;
+
+impl Resource for Tenants {
+    const NAME: &'static str = "tenant";
+
+    type Model = Tenant;
+    type Create = TenantRequest;
+    type Update = TenantRequest;
+    type Response = TenantResponse;
+    type Reader = OperatorUser;
+    type Writer = OperatorUser;
+
+    fn list(conn: &Conn, page: Page) -> Result<(Vec<Tenant>, i64), ApiError> {
+        tenant::list(conn, page)
+    }
+
+    fn find(conn: &Conn, id: Uuid) -> Result<Option<Tenant>, ApiError> {
+        tenant::find(conn, id)
+    }
+
+    fn create(conn: &Conn, id: Uuid, params: TenantRequest, actor_id: Uuid) -> Result<(), ApiError> {
+        tenant::create(conn, id, &params.slug, &params.name, actor_id)
+    }
+
+    fn update(conn: &Conn, id: Uuid, params: TenantRequest, actor_id: Uuid) -> Result<(), ApiError> {
+        tenant::update(conn, id, &params.slug, &params.name, actor_id)
+    }
+
+    fn delete(conn: &Conn, id: Uuid) -> Result<(), ApiError> {
+        tenant::delete(conn, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::audit::AuditContext;
+    use crate::server_helpers::resource::{create, delete, get};
+    use crate::tests::helpers::tests::{get_data_pool, get_read_pool};
+    use actix_web::web::{Json, Path};
+    use validator::Validate;
+
+    fn get_operator() -> OperatorUser {
+        OperatorUser { id: Uuid::nil() }
+    }
+
+    #[actix_rt::test]
+    async fn it_manages_tenants() {
+        let params = TenantRequest {
+            slug: format!("acme-{}", Uuid::new_v4().to_simple()),
+            name: "Acme".into(),
+        };
+        let created = create::<Tenants>(get_data_pool(), Json(params.clone()), get_operator(), AuditContext::default())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.slug, params.slug);
+
+        let duplicate = create::<Tenants>(get_data_pool(), Json(params), get_operator(), AuditContext::default()).await;
+        assert!(duplicate.is_err());
+
+        let id = || Path::from(created.id);
+        let fetched = get::<Tenants>(id(), get_read_pool(), get_operator()).await.unwrap();
+        assert_eq!(fetched.into_inner(), created);
+        delete::<Tenants>(id(), get_data_pool(), get_operator(), AuditContext::default()).await.unwrap();
+    }
+
+    #[test]
+    fn it_validates_the_slug() {
+        let params = TenantRequest { slug: "Acme Inc".into(), name: "Acme".into() };
+        assert!(params.validate().is_err());
+    }
+}
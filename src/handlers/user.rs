@@ -1,11 +1,28 @@
+use crate::config::CONFIG;
 use crate::database::connection::PoolType;
+use crate::database::routing::ReadPool;
+use crate::server_helpers::conditional::{ExpectedVersion, Tagged};
 use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::{Page, Paginated};
 use crate::server_helpers::response::{respond_json, respond_ok};
-use crate::models::user::{create, delete, find, get_all, update, NewUser, UpdateUser, User};
+use crate::server_helpers::telemetry::block;
+use crate::models::audit::{record, AuditContext, Changes};
+use crate::models::tenant::TenantId;
+#[cfg(feature = "async-db")]
+use crate::models::user_async;
+use crate::models::user::{
+    create, delete, find, find_with, normalize_email, patch, purge, restore, search, search_words, update, AdminUser,
+    AuthUser, NewUser, PatchUser, UpdateUser, User, UserListParams, UserSearchParams, UserSort,
+};
+#[cfg(not(feature = "async-db"))]
+use crate::models::user::list;
 use crate::validate::validate;
-use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use actix_web::web::{Data, HttpRequest, HttpResponse, Json, Path, Query};
+use chrono::{NaiveDateTime, Utc};
 use rayon::prelude::*;
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 use validator::Validate;
 
@@ -15,11 +32,59 @@ pub struct UserResponse {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
+    /// Sent as the ETag header instead of in the body
+    #[serde(skip)]
+    pub version: i32,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct UsersResponse(pub Vec<UserResponse>);
 
+/// A user found by the search, with the matching words of each field highlighted
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct UserSearchResult {
+    #[serde(flatten)]
+    pub user: UserResponse,
+    /// Matched fields, HTML escaped with the matches wrapped in <mark>
+    pub highlights: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PurgeResponse {
+    pub purged: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+pub struct UserListQuery {
+    /// Matches anywhere in the first or last name
+    pub name: Option<String>,
+    /// Matches anywhere in the email address
+    pub email: Option<String>,
+    pub created_from: Option<NaiveDateTime>,
+    pub created_to: Option<NaiveDateTime>,
+    /// Column to sort by, prefix with "-" for descending order
+    pub sort: Option<String>,
+
+    #[validate(range(min = 1, message = "limit must be a positive number"))]
+    pub limit: Option<i64>,
+
+    #[validate(range(min = 0, message = "offset must not be negative"))]
+    pub offset: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+pub struct UserSearchQuery {
+    /// Partial name or email
+    #[validate(length(min = 1, max = 100, message = "q is required and must be at most 100 characters"))]
+    pub q: String,
+
+    #[validate(range(min = 1, message = "limit must be a positive number"))]
+    pub limit: Option<i64>,
+
+    #[validate(range(min = 0, message = "offset must not be negative"))]
+    pub offset: Option<i64>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 pub struct CreateUserRequest {
     #[validate(length(
@@ -62,82 +127,405 @@ pub struct UpdateUserRequest {
     pub email: String,
 }
 
-/// Get a user
+/// Partial update, every field is optional and only validated when present
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+pub struct PatchUserRequest {
+    #[validate(length(
+        min = 3,
+        message = "first_name must be at least 3 characters"
+    ))]
+    pub first_name: Option<String>,
+
+    #[validate(length(
+        min = 3,
+        message = "last_name must be at least 3 characters"
+    ))]
+    pub last_name: Option<String>,
+
+    #[validate(email(message = "email must be a valid email"))]
+    pub email: Option<String>,
+}
+
+/// Get a user, tagged with its version
+/// Responds with 304 when If-None-Match already has the current version
 pub async fn get_user(
     user_id: Path<Uuid>,
-    pool: Data<PoolType>,
-) -> Result<Json<UserResponse>, ApiError> {
-    let user = block(move || find(&pool, *user_id)).await?;
-    respond_json(user)
+    pool: ReadPool,
+    tenant: TenantId,
+) -> Result<Tagged<UserResponse>, ApiError> {
+    #[cfg(feature = "async-db")]
+    let user = user_async::find(&pool, tenant, *user_id).await?;
+    #[cfg(not(feature = "async-db"))]
+    let user = block(move || find(&pool, tenant, *user_id)).await?;
+    respond_tagged(user)
 }
 
-/// Get all users
-pub async fn get_users(pool: Data<PoolType>) -> Result<Json<UsersResponse>, ApiError> {
-    let users = block(move || get_all(&pool)).await?;
-    respond_json(users)
+/// Get a page of users, filtered and sorted by the query parameters
+pub async fn get_users(
+    pool: ReadPool,
+    query: Query<UserListQuery>,
+    req: HttpRequest,
+    tenant: TenantId,
+) -> Result<Json<Paginated<UserResponse>>, ApiError> {
+    let params = UserListParams::try_from_query(query.into_inner())?;
+    let page = params.page;
+    #[cfg(feature = "async-db")]
+    let (users, total) = user_async::list(&pool, tenant, &params).await?;
+    #[cfg(not(feature = "async-db"))]
+    let (users, total) = block(move || list(&pool, tenant, &params)).await?;
+    respond_json(Paginated::new(users.0, total, page, &req))
 }
 
-/// Create a user
+/// Search users by partial name or email, best matches first, admins only
+pub async fn search_users(
+    pool: ReadPool,
+    query: Query<UserSearchQuery>,
+    req: HttpRequest,
+    _admin: AdminUser,
+    tenant: TenantId,
+) -> Result<Json<Paginated<UserSearchResult>>, ApiError> {
+    let query = Json(query.into_inner());
+    validate(&query)?;
+    if query.q.trim().is_empty() {
+        return Err(ApiError::ValidationError(vec![
+            "q is required and must be at most 100 characters".into(),
+        ]));
+    }
+
+    let words = search_words(&query.q);
+    let params = UserSearchParams {
+        query: query.q.trim().to_string(),
+        fulltext: CONFIG.user_search_fulltext,
+        page: Page::new(query.limit, query.offset),
+    };
+    let page = params.page;
+    let (users, total) = block(move || search(&pool, tenant, &params)).await?;
+    let results = users.0.into_iter().map(|user| UserSearchResult::new(user, &words)).collect();
+    respond_json(Paginated::new(results, total, page, &req))
+}
+
+/// Create a user in the tenant of the request
 pub async fn create_user(
     pool: Data<PoolType>,
     params: Json<CreateUserRequest>,
+    user: AuthUser,
+    audit: AuditContext,
+    tenant: TenantId,
 ) -> Result<Json<UserResponse>, ApiError> {
     validate(&params)?;
 
-    // temporarily use the new user's id for created_at/updated_at
-    // update when auth is added
     let user_id = Uuid::new_v4();
     let new_user: User = NewUser {
-        id: user_id.to_string(),
+        id: user_id,
+        tenant_id: tenant,
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
         password: params.password.to_string(),
-        created_by: user_id.to_string(),
-        updated_by: user_id.to_string(),
+        created_by: user.id,
+        updated_by: user.id,
     }
     .into();
-    let user = block(move || create(&pool, &new_user)).await?;
-    respond_json(user.into())
+    let user = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let created = create(conn, &new_user)?;
+            record(conn, &audit.entry("user.create", "user", Some(user_id), Changes::created(&new_user)))?;
+            Ok(created)
+        })
+    })
+    .await?;
+    respond_json(user)
 }
 
 /// Update a user
+/// Responds with 412 when If-Match does not have the current version
 pub async fn update_user(
     user_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdateUserRequest>,
-) -> Result<Json<UserResponse>, ApiError> {
+    user: AuthUser,
+    audit: AuditContext,
+    expected: ExpectedVersion,
+    tenant: TenantId,
+) -> Result<Tagged<UserResponse>, ApiError> {
     validate(&params)?;
 
-    // temporarily use the user's id for updated_at
-    // update when auth is added
+    let user_id = user_id.into_inner();
     let update_user = UpdateUser {
-        id: user_id.to_string(),
+        id: user_id.into(),
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
-        email: params.email.to_string(),
-        updated_by: user_id.to_string(),
+        email: params.email.trim().to_string(),
+        email_normalized: normalize_email(&params.email),
+        updated_by: user.id.into(),
     };
-    let user = block(move || update(&pool, &update_user)).await?;
-    respond_json(user.into())
+    let user = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let before = find_with(conn, tenant, user_id)?;
+            let updated = update(conn, tenant, &update_user, &expected)?;
+            record(conn, &audit.entry("user.update", "user", Some(user_id), Changes::updated(&before, &updated)))?;
+            Ok(updated)
+        })
+    })
+    .await?;
+    respond_tagged(user)
+}
+
+/// Partially update a user
+///
+/// Accepts JSON with optional fields as well as JSON Merge Patch (RFC 7396)
+/// documents, only the supplied columns are written.
+/// Responds with 412 when If-Match does not have the current version.
+pub async fn patch_user(
+    user_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<Value>,
+    user: AuthUser,
+    audit: AuditContext,
+    expected: ExpectedVersion,
+    tenant: TenantId,
+) -> Result<Tagged<UserResponse>, ApiError> {
+    let params = Json(PatchUserRequest::from_merge_patch(params.into_inner())?);
+    validate(&params)?;
+
+    let user_id = user_id.into_inner();
+    let pool = pool.primary_only();
+    if params.is_empty() {
+        let user = block(move || find(&pool, tenant, user_id)).await?;
+        if !expected.matches(user.version) {
+            return Err(ApiError::PreconditionFailed(format!(
+                "User {} has been modified in the meantime",
+                user_id
+            )));
+        }
+        return respond_tagged(user);
+    }
+
+    let patch_user = PatchUser {
+        first_name: params.first_name.clone(),
+        last_name: params.last_name.clone(),
+        email: params.email.as_deref().map(|email| email.trim().to_string()),
+        email_normalized: params.email.as_deref().map(normalize_email),
+        updated_by: user.id.into(),
+        updated_at: Utc::now().naive_utc(),
+    };
+    let user = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let before = find_with(conn, tenant, user_id)?;
+            let patched = patch(conn, tenant, user_id, &patch_user, &expected)?;
+            record(conn, &audit.entry("user.patch", "user", Some(user_id), Changes::updated(&before, &patched)))?;
+            Ok(patched)
+        })
+    })
+    .await?;
+    respond_tagged(user)
 }
 
 /// Delete a user
+/// The user is only marked as deleted, it can be restored until it is purged
+/// Responds with 412 when If-Match does not have the current version
 pub async fn delete_user(
     user_id: Path<Uuid>,
     pool: Data<PoolType>,
+    user: AuthUser,
+    audit: AuditContext,
+    expected: ExpectedVersion,
+    tenant: TenantId,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *user_id)).await?;
+    let user_id = user_id.into_inner();
+    block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let before = find_with(conn, tenant, user_id)?;
+            delete(conn, tenant, user_id, user.id, &expected)?;
+            record(conn, &audit.entry("user.delete", "user", Some(user_id), Changes::deleted(&before)))
+        })
+    })
+    .await?;
     respond_ok()
 }
 
+/// Restore a deleted user, admins only
+pub async fn restore_user(
+    user_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    admin: AdminUser,
+    audit: AuditContext,
+    tenant: TenantId,
+) -> Result<Json<UserResponse>, ApiError> {
+    let user_id = user_id.into_inner();
+    let user = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let restored = restore(conn, tenant, user_id, admin.id)?;
+            record(conn, &audit.entry("user.restore", "user", Some(user_id), Changes::created(&restored)))?;
+            Ok(restored)
+        })
+    })
+    .await?;
+    respond_json(user)
+}
+
+/// Permanently remove the users of the tenant deleted longer than USER_RETENTION ago, admins only
+pub async fn purge_users(
+    pool: Data<PoolType>,
+    _admin: AdminUser,
+    audit: AuditContext,
+    tenant: TenantId,
+) -> Result<Json<PurgeResponse>, ApiError> {
+    let deleted_before = retention_cutoff()?;
+    let purged = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let purged = purge(conn, tenant, deleted_before)?;
+            let changes = Changes::created(&PurgeResponse { purged });
+            record(conn, &audit.entry("user.purge", "user", None, changes))?;
+            Ok(purged)
+        })
+    })
+    .await?;
+    respond_json(PurgeResponse { purged })
+}
+
+/// Tag a user response with the user's version
+fn respond_tagged(user: UserResponse) -> Result<Tagged<UserResponse>, ApiError> {
+    let version = user.version;
+    Ok(Tagged::new(user, version))
+}
+
+/// Users deleted before this moment are past the retention period
+pub fn retention_cutoff() -> Result<NaiveDateTime, ApiError> {
+    let retention = chrono::Duration::from_std(CONFIG.user_retention)
+        .map_err(|error| ApiError::InternalServerError(error.to_string()))?;
+    Ok(Utc::now().naive_utc() - retention)
+}
+
+impl UserListParams {
+    /// Validate the query parameters and resolve the sort column
+    fn try_from_query(query: UserListQuery) -> Result<Self, ApiError> {
+        validate(&Json(query.clone()))?;
+        let sort = query.sort.as_deref().unwrap_or("created_at");
+        let (descending, column) = match sort.strip_prefix('-') {
+            Some(column) => (true, column),
+            None => (false, sort),
+        };
+        let sort = UserSort::from_name(column).ok_or_else(|| {
+            ApiError::ValidationError(vec![format!("sort must be one of {}", UserSort::ALLOWED)])
+        })?;
+        Ok(UserListParams {
+            name: query.name.filter(|name| !name.is_empty()),
+            email: query.email.filter(|email| !email.is_empty()),
+            created_from: query.created_from,
+            created_to: query.created_to,
+            sort,
+            descending,
+            page: Page::new(query.limit, query.offset),
+        })
+    }
+}
+
+impl PatchUserRequest {
+    /// Read a merge patch document. The user columns are not nullable,
+    /// so removing a member (null) is rejected instead of clearing it.
+    fn from_merge_patch(patch: Value) -> Result<Self, ApiError> {
+        let members = match patch {
+            Value::Object(members) => members,
+            _ => {
+                return Err(ApiError::ValidationError(vec![
+                    "patch must be a JSON object".into(),
+                ]))
+            }
+        };
+        let mut request = PatchUserRequest::default();
+        let mut errors = vec![];
+        for (key, value) in members {
+            let field = match key.as_str() {
+                "first_name" => &mut request.first_name,
+                "last_name" => &mut request.last_name,
+                "email" => &mut request.email,
+                _ => {
+                    errors.push(format!("{} cannot be patched", key));
+                    continue;
+                }
+            };
+            match value {
+                Value::String(value) => *field = Some(value),
+                Value::Null => errors.push(format!("{} cannot be removed", key)),
+                _ => errors.push(format!("{} must be a string", key)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(request)
+        } else {
+            Err(ApiError::ValidationError(errors))
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first_name.is_none() && self.last_name.is_none() && self.email.is_none()
+    }
+}
+
+impl UserSearchResult {
+    fn new(user: UserResponse, words: &[String]) -> Self {
+        let fields = [
+            ("first_name", &user.first_name),
+            ("last_name", &user.last_name),
+            ("email", &user.email),
+        ];
+        let highlights = fields
+            .iter()
+            .filter_map(|(field, value)| highlight(value, words).map(|marked| (field.to_string(), marked)))
+            .collect();
+        UserSearchResult { user, highlights }
+    }
+}
+
+/// Wrap the words of the value that start with one of the search words in <mark>
+/// The rest is HTML escaped, so the result can be rendered as is. None when nothing matches.
+fn highlight(value: &str, words: &[String]) -> Option<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let lowered: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+    let mut marked = vec![false; chars.len()];
+    for word in words {
+        let word: Vec<char> = word.chars().collect();
+        for start in 0..chars.len() {
+            let starts_word = start == 0 || !chars[start - 1].is_alphanumeric();
+            if starts_word && lowered[start..].starts_with(&word) {
+                marked[start..start + word.len()].iter_mut().for_each(|mark| *mark = true);
+            }
+        }
+    }
+    if !marked.contains(&true) {
+        return None;
+    }
+
+    let mut highlighted = String::new();
+    for (index, c) in chars.iter().enumerate() {
+        let is_marked = marked[index];
+        if is_marked && (index == 0 || !marked[index - 1]) {
+            highlighted.push_str("<mark>");
+        }
+        match c {
+            '&' => highlighted.push_str("&amp;"),
+            '<' => highlighted.push_str("&lt;"),
+            '>' => highlighted.push_str("&gt;"),
+            '"' => highlighted.push_str("&quot;"),
+            '\'' => highlighted.push_str("&#39;"),
+            _ => highlighted.push(*c),
+        }
+        if is_marked && (index + 1 == chars.len() || !marked[index + 1]) {
+            highlighted.push_str("</mark>");
+        }
+    }
+    Some(highlighted)
+}
+
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
         UserResponse {
-            id: Uuid::parse_str(&user.id).unwrap(),
+            id: user.id,
             first_name: user.first_name.to_string(),
             last_name: user.last_name.to_string(),
             email: user.email.to_string(),
+            version: user.version,
         }
     }
 }
@@ -151,23 +539,32 @@ impl From<Vec<User>> for UsersResponse {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::models::user::get_all;
     use crate::models::user::tests::create_user as model_create_user;
-    use crate::tests::helpers::tests::{get_data_pool, get_pool};
+    use crate::tests::helpers::tests::{get_data_pool, get_pool, get_read_pool};
+    use actix_web::test;
+    use serde_json::json;
 
     pub fn get_all_users() -> UsersResponse {
         let pool = get_pool();
-        get_all(&pool).unwrap()
+        get_all(&pool, TenantId::DEFAULT).unwrap()
     }
 
     pub fn get_first_users_id() -> Uuid {
         get_all_users().0[0].id
     }
 
+    pub fn get_auth_user() -> AuthUser {
+        AuthUser {
+            id: get_first_users_id(),
+        }
+    }
+
     #[actix_rt::test]
     async fn it_gets_a_user() {
         let first_user = &get_all_users().0[0];
         let user_id: Path<Uuid> = get_first_users_id().into();
-        let response = get_user(user_id, get_data_pool()).await.unwrap();
+        let response = get_user(user_id, get_read_pool(), TenantId::DEFAULT).await.unwrap();
         assert_eq!(response.into_inner(), *first_user);
     }
 
@@ -175,7 +572,7 @@ pub mod tests {
     async fn it_doesnt_find_a_user() {
         let uuid = Uuid::new_v4();
         let user_id: Path<Uuid> = uuid.into();
-        let response = get_user(user_id, get_data_pool()).await;
+        let response = get_user(user_id, get_read_pool(), TenantId::DEFAULT).await;
         let expected_error = ApiError::NotFound(format!("User {} not found", uuid.to_string()));
         assert!(response.is_err());
         assert_eq!(response.unwrap_err(), expected_error);
@@ -183,9 +580,43 @@ pub mod tests {
 
     #[actix_rt::test]
     async fn it_gets_all_users() {
-        let response = get_users(get_data_pool()).await;
+        let query = Query(UserListQuery::default());
+        let req = test::TestRequest::with_uri("/api/v1/user").to_http_request();
+        let response = get_users(get_read_pool(), query, req, TenantId::DEFAULT).await;
         assert!(response.is_ok());
-        assert_eq!(response.unwrap().into_inner().0[0], get_all_users().0[0]);
+        assert!(response.unwrap().into_inner().total > 0);
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_an_unknown_sort_column() {
+        let query = Query(UserListQuery {
+            sort: Some("-password".into()),
+            ..Default::default()
+        });
+        let req = test::TestRequest::with_uri("/api/v1/user?sort=-password").to_http_request();
+        let response = get_users(get_read_pool(), query, req, TenantId::DEFAULT).await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_searches_users() {
+        let created = model_create_user().unwrap();
+        let query = Query(UserSearchQuery {
+            q: created.email.clone(),
+            ..Default::default()
+        });
+        let req = test::TestRequest::with_uri("/api/v1/user/search").to_http_request();
+        let admin = AdminUser { id: Uuid::nil(), tenant_id: TenantId::DEFAULT };
+        let response = search_users(get_read_pool(), query, req, admin, TenantId::DEFAULT).await.unwrap().into_inner();
+        assert_eq!(response.data[0].user.id, created.id);
+        assert!(response.data[0].highlights["email"].starts_with("<mark>model</mark>-<mark>test</mark>"));
+    }
+
+    #[test]
+    fn it_highlights_matching_words() {
+        let words = search_words("sat nak");
+        assert_eq!(highlight("Satoshi <Nakamoto>", &words), Some("<mark>Sat</mark>oshi &lt;<mark>Nak</mark>amoto&gt;".into()));
+        assert_eq!(highlight("Hal Finney", &words), None);
     }
 
     #[actix_rt::test]
@@ -193,11 +624,17 @@ pub mod tests {
         let params = Json(CreateUserRequest {
             first_name: "Satoshi".into(),
             last_name: "Nakamoto".into(),
-            email: "satoshi@nakamotoinstitute.org".into(),
+            email: format!("satoshi-{}@nakamotoinstitute.org", Uuid::new_v4()),
             password: "123456".into(),
         });
-        let response = create_user(get_data_pool(), Json(params.clone()))
-            .await
+        let response = create_user(
+            get_data_pool(),
+            Json(params.clone()),
+            get_auth_user(),
+            AuditContext::default(),
+            TenantId::DEFAULT,
+        )
+        .await
             .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
     }
@@ -211,21 +648,83 @@ pub mod tests {
             last_name: first_user.last_name.clone(),
             email: first_user.email.clone(),
         });
-        let response = update_user(user_id, get_data_pool(), Json(params.clone()))
-            .await
-            .unwrap();
+        let response = update_user(
+            user_id,
+            get_data_pool(),
+            Json(params.clone()),
+            get_auth_user(),
+            AuditContext::default(),
+            ExpectedVersion::default(),
+            TenantId::DEFAULT,
+        )
+        .await
+        .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
     }
 
+    #[actix_rt::test]
+    async fn it_patches_a_user() {
+        let created = model_create_user().unwrap();
+        let user_id: Path<Uuid> = created.id.into();
+        let params = Json(json!({ "first_name": "Patched" }));
+        let response = patch_user(
+            user_id,
+            get_data_pool(),
+            params,
+            get_auth_user(),
+            AuditContext::default(),
+            ExpectedVersion::default(),
+            TenantId::DEFAULT,
+        )
+        .await
+        .unwrap();
+        let patched = response.into_inner();
+        assert_eq!(patched.first_name, "Patched");
+        assert_eq!(patched.email, created.email);
+    }
+
+    #[actix_rt::test]
+    async fn it_refuses_a_patch_with_a_stale_etag() {
+        let created = model_create_user().unwrap();
+        let user_id: Path<Uuid> = created.id.into();
+        let params = Json(json!({ "first_name": "Patched" }));
+        let expected = ExpectedVersion(Some(vec![created.version + 1]));
+        let audit = AuditContext::default();
+        let response = patch_user(user_id, get_data_pool(), params, get_auth_user(), audit, expected, TenantId::DEFAULT)
+            .await;
+        let expected_error = ApiError::PreconditionFailed(format!("User {} has been modified in the meantime", created.id));
+        assert_eq!(response.unwrap_err(), expected_error);
+    }
+
+    #[test]
+    fn it_rejects_removing_a_required_field() {
+        let patch = json!({ "first_name": null, "id": "00000000-0000-0000-0000-000000000000" });
+        let response = PatchUserRequest::from_merge_patch(patch);
+        let expected_error = ApiError::ValidationError(vec![
+            "first_name cannot be removed".to_string(),
+            "id cannot be patched".to_string(),
+        ]);
+        assert_eq!(response.unwrap_err(), expected_error);
+    }
+
     #[actix_rt::test]
     async fn it_deletes_a_user() {
         let created = model_create_user();
         let user_id = created.unwrap().id;
         let user_id_path: Path<Uuid> = user_id.into();
-        let user = find(&get_pool(), user_id);
+        let user = find(&get_pool(), TenantId::DEFAULT, user_id);
         assert!(user.is_ok());
-        delete_user(user_id_path, get_data_pool()).await.unwrap();
-        let user = find(&get_pool(), user_id);
+        delete_user(
+            user_id_path,
+            get_data_pool(),
+            get_auth_user(),
+            AuditContext::default(),
+            ExpectedVersion::default(),
+            TenantId::DEFAULT,
+        )
+        .await
+        .unwrap();
+        let user = find(&get_pool(), TenantId::DEFAULT, user_id);
         assert!(user.is_err());
     }
 }
@@ -0,0 +1,580 @@
+//! Bulk import and export of users as CSV or JSON Lines, admins only
+//!
+//! Imports validate every row first and report the problems per row.
+//! Atomic imports create nothing unless every row is valid, best effort imports create the valid rows.
+//! Invites are checked like users, an atomic import takes back its invites when one cannot be stored.
+//! Exports are streamed in batches, so they do not hold every user in memory.
+
+use crate::config::CONFIG;
+use crate::database::connection::PoolType;
+use crate::database::routing::ReadPool;
+use crate::database::transaction::savepoint;
+use crate::handlers::registration::{check_email_domain, new_invite, revoke_invite, InviteResponse};
+use crate::models::audit::{record, AuditContext, Changes};
+use crate::models::tenant::TenantId;
+use crate::models::user::{batch_after, create, create_all, emails_in_use, normalize_email, AdminUser, NewUser, User};
+use crate::server_helpers::cache::Cache;
+use crate::server_helpers::csv::{csv_line, parse_csv};
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::response::respond_json;
+use crate::server_helpers::telemetry::block;
+use crate::validate::collect_errors;
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{Bytes, Data, HttpResponse, Json, Query},
+};
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use uuid::Uuid;
+use validator::Validate;
+
+/// Largest accepted import body
+pub const IMPORT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// Users loaded per query while exporting
+const EXPORT_BATCH: i64 = 500;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    Csv,
+    #[default]
+    Ndjson,
+}
+
+impl DataFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "text/csv" => Some(DataFormat::Csv),
+            "application/x-ndjson" | "application/jsonl" | "application/json-lines" => Some(DataFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            DataFormat::Csv => "text/csv; charset=utf-8",
+            DataFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            DataFormat::Csv => "csv",
+            DataFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Create nothing unless every row is valid
+    #[default]
+    Atomic,
+    /// Create the valid rows, report the others
+    BestEffort,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImportQuery {
+    /// Validate only, nothing is created
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub mode: ImportMode,
+    /// Invite the addresses to register instead of creating accounts
+    #[serde(default)]
+    pub invite: bool,
+}
+
+/// A row of an import, the password is not needed for invites
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportUserRow {
+    #[validate(length(
+        min = 3,
+        message = "first_name is required and must be at least 3 characters"
+    ))]
+    pub first_name: String,
+
+    #[validate(length(
+        min = 3,
+        message = "last_name is required and must be at least 3 characters"
+    ))]
+    pub last_name: String,
+
+    #[validate(email(message = "email must be a valid email"))]
+    pub email: String,
+
+    #[validate(length(
+        min = 6,
+        message = "password must be at least 6 characters"
+    ))]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ImportRowError {
+    /// 1-based number of the row, not counting the CSV header
+    pub row: usize,
+    pub email: Option<String>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub mode: ImportMode,
+    pub rows: usize,
+    /// Rows that passed validation
+    pub valid: usize,
+    pub created: usize,
+    pub invites: Vec<InviteResponse>,
+    pub errors: Vec<ImportRowError>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: DataFormat,
+    /// Comma separated fields, all of them by default
+    pub fields: Option<String>,
+}
+
+/// A row of the body with its 1-based number, rows that cannot be read carry their errors
+type ParsedRow = (usize, Result<ImportUserRow, Vec<String>>);
+
+/// Fields that can be exported, secrets never are
+const EXPORT_FIELDS: [&str; 6] = ["id", "first_name", "last_name", "email", "created_at", "updated_at"];
+
+/// Import users into the tenant of the request from CSV (text/csv) or JSON Lines (application/x-ndjson)
+///
+/// CSV needs a header row naming the columns, JSON Lines has one object per line.
+/// With `invite` the addresses get invites to register instead of accounts.
+pub async fn import_users(
+    body: Bytes,
+    format: DataFormat,
+    query: Query<ImportQuery>,
+    pool: Data<PoolType>,
+    redis: Cache,
+    admin: AdminUser,
+    audit: AuditContext,
+) -> Result<Json<ImportReport>, ApiError> {
+    let tenant = admin.tenant_id;
+    if query.invite && !CONFIG.registration_enabled {
+        return Err(ApiError::ValidationError(vec![
+            "invites require REGISTRATION_ENABLED".into(),
+        ]));
+    }
+    let text = std::str::from_utf8(&body)
+        .map_err(|_| ApiError::BadRequest("The import must be UTF-8 encoded".into()))?;
+    let parsed = parse_rows(format, text)?;
+    let rows = parsed.len();
+
+    // Validate every row, including emails taken by existing users or by earlier rows
+    let mut errors = vec![];
+    let mut valid = vec![];
+    let emails = parsed
+        .iter()
+        .filter_map(|(_, row)| row.as_ref().ok().map(|row| normalize_email(&row.email)))
+        .collect::<Vec<_>>();
+    let in_use: HashSet<String> = block({
+        let pool = pool.clone();
+        move || {
+            let conn = pool.get()?;
+            emails_in_use(&conn, tenant, &emails)
+        }
+    })
+    .await?
+    .into_iter()
+    .collect();
+    let mut seen = HashSet::new();
+    for (number, row) in parsed {
+        let row = match row {
+            Ok(row) => row,
+            Err(messages) => {
+                errors.push(ImportRowError { row: number, email: None, errors: messages });
+                continue;
+            }
+        };
+        let messages = validate_row(&row, query.invite, &in_use, &mut seen);
+        if messages.is_empty() {
+            valid.push((number, row));
+        } else {
+            errors.push(ImportRowError { row: number, email: Some(row.email), errors: messages });
+        }
+    }
+
+    let mut report = ImportReport {
+        dry_run: query.dry_run,
+        mode: query.mode,
+        rows,
+        valid: valid.len(),
+        created: 0,
+        invites: vec![],
+        errors,
+    };
+    if query.dry_run || (query.mode == ImportMode::Atomic && !report.errors.is_empty()) {
+        return respond_json(report);
+    }
+
+    if query.invite {
+        for (number, row) in valid {
+            match new_invite(redis.clone(), tenant, row.email.trim()).await {
+                Ok(invite) => report.invites.push(invite),
+                Err(error) if query.mode == ImportMode::Atomic => {
+                    // None of the codes were handed out yet, so the stored invites can be taken back
+                    for invite in report.invites.drain(..) {
+                        if let Err(revoke_error) = revoke_invite(redis.clone(), tenant, &invite).await {
+                            log::warn!("Could not revoke the invite for {}: {}", invite.email, revoke_error);
+                        }
+                    }
+                    return Err(error);
+                }
+                Err(error) => report.errors.push(ImportRowError {
+                    row: number,
+                    email: Some(row.email),
+                    errors: error_messages(error),
+                }),
+            }
+        }
+        let changes = Changes::created(&report.invites.iter().map(|invite| &invite.email).collect::<Vec<_>>());
+        let entry = audit.entry("invite.import", "invite", None, changes);
+        block(move || audit.unit_of_work(&pool, |conn| record(conn, &entry))).await?;
+        return respond_json(report);
+    }
+
+    let new_users = valid
+        .into_iter()
+        .map(|(number, row)| (number, row.into_user(tenant, admin.id)))
+        .collect::<Vec<_>>();
+    let mode = query.mode;
+    let (created, failed) = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let mut failed = vec![];
+            let created = match mode {
+                ImportMode::Atomic => {
+                    let users = new_users.iter().map(|(_, user)| user.clone()).collect::<Vec<_>>();
+                    create_all(conn, &users)?;
+                    users
+                }
+                ImportMode::BestEffort => {
+                    // A failed row only rolls back its own savepoint
+                    let mut created = vec![];
+                    for (number, user) in new_users {
+                        match savepoint(conn, |conn| create(conn, &user)) {
+                            Ok(_) => created.push(user),
+                            Err(error) => failed.push((number, user.email, error)),
+                        }
+                    }
+                    created
+                }
+            };
+            for user in &created {
+                record(conn, &audit.entry("user.import", "user", Some(user.id), Changes::created(user)))?;
+            }
+            Ok((created.len(), failed))
+        })
+    })
+    .await?;
+    report.created = created;
+    for (number, email, error) in failed {
+        report.errors.push(ImportRowError {
+            row: number,
+            email: Some(email),
+            errors: error_messages(error),
+        });
+    }
+    respond_json(report)
+}
+
+/// Export the users of the tenant as CSV or JSON Lines, streamed in batches
+pub async fn export_users(
+    query: Query<ExportQuery>,
+    pool: ReadPool,
+    _admin: AdminUser,
+    tenant: TenantId,
+) -> Result<HttpResponse, ApiError> {
+    let format = query.format;
+    let fields = export_fields(query.fields.as_deref())?;
+    let header = match format {
+        DataFormat::Csv => Some(Ok::<_, ApiError>(Bytes::from(csv_line(&fields)))),
+        DataFormat::Ndjson => None,
+    };
+
+    // The state is the id to continue after, None once the export is done or failed
+    let batches = stream::unfold(Some(None), move |after: Option<Option<Uuid>>| {
+        let pool = pool.clone();
+        let fields = fields.clone();
+        async move {
+            let after = after?;
+            match block(move || batch_after(&pool, tenant, after, EXPORT_BATCH)).await {
+                Ok(batch) if batch.is_empty() => None,
+                Ok(batch) => {
+                    let last_id = batch.last().map(|user| user.id);
+                    let chunk = batch.iter().map(|user| export_line(format, user, &fields)).collect::<String>();
+                    Some((Ok(Bytes::from(chunk)), Some(last_id)))
+                }
+                Err(error) => Some((Err(error.into()), None)),
+            }
+        }
+    });
+
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("users.{}", format.extension()))],
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .set(disposition)
+        .streaming(Box::pin(stream::iter(header).chain(batches))))
+}
+
+/// Parse the body into numbered rows
+fn parse_rows(format: DataFormat, text: &str) -> Result<Vec<ParsedRow>, ApiError> {
+    match format {
+        DataFormat::Csv => {
+            let mut records = parse_csv(text).map_err(ApiError::BadRequest)?.into_iter();
+            let header = records
+                .next()
+                .ok_or_else(|| ApiError::BadRequest("The CSV needs a header row".into()))?;
+            let unknown = header
+                .iter()
+                .filter(|column| !["first_name", "last_name", "email", "password"].contains(&column.as_str()))
+                .map(|column| format!("unknown column {}", column))
+                .collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                return Err(ApiError::ValidationError(unknown));
+            }
+            let rows = records
+                .enumerate()
+                .map(|(index, record)| {
+                    if record.len() != header.len() {
+                        return (index + 1, Err(vec![format!("expected {} fields, got {}", header.len(), record.len())]));
+                    }
+                    let fields = header
+                        .iter()
+                        .cloned()
+                        .zip(record)
+                        .filter(|(_, value)| !value.is_empty())
+                        .map(|(column, value)| (column, Value::String(value)))
+                        .collect::<Map<_, _>>();
+                    (index + 1, read_row(Value::Object(fields)))
+                })
+                .collect();
+            Ok(rows)
+        }
+        DataFormat::Ndjson => {
+            let rows = text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .enumerate()
+                .map(|(index, line)| {
+                    let row = serde_json::from_str::<Value>(line)
+                        .map_err(|error| vec![format!("invalid JSON: {}", error)])
+                        .and_then(read_row);
+                    (index + 1, row)
+                })
+                .collect();
+            Ok(rows)
+        }
+    }
+}
+
+fn read_row(fields: Value) -> Result<ImportUserRow, Vec<String>> {
+    serde_json::from_value(fields).map_err(|error| vec![error.to_string()])
+}
+
+/// Validate a row on its own and against the emails in use or seen in earlier rows
+fn validate_row(row: &ImportUserRow, invite: bool, in_use: &HashSet<String>, seen: &mut HashSet<String>) -> Vec<String> {
+    let mut messages = match row.validate() {
+        Ok(()) => vec![],
+        Err(error) => collect_errors(error),
+    };
+    if !invite && row.password.is_none() {
+        messages.push("password is required".into());
+    }
+    if invite {
+        if let Err(error) = check_email_domain(row.email.trim(), &CONFIG.registration_email_domains) {
+            messages.extend(error_messages(error));
+        }
+    }
+    let email = normalize_email(&row.email);
+    if in_use.contains(&email) {
+        messages.push("email already in use".into());
+    } else if !seen.insert(email) {
+        messages.push("email is duplicated in the import".into());
+    }
+    messages
+}
+
+fn error_messages(error: ApiError) -> Vec<String> {
+    match error {
+        ApiError::ValidationError(messages) => messages,
+        error => vec![error.to_string()],
+    }
+}
+
+/// Resolve the requested export fields, every field when none are given
+fn export_fields(fields: Option<&str>) -> Result<Vec<String>, ApiError> {
+    let fields = match fields {
+        Some(fields) if !fields.trim().is_empty() => fields
+            .split(',')
+            .map(|field| field.trim().to_string())
+            .collect::<Vec<_>>(),
+        _ => return Ok(EXPORT_FIELDS.iter().map(|field| field.to_string()).collect()),
+    };
+    let unknown = fields
+        .iter()
+        .filter(|field| !EXPORT_FIELDS.contains(&field.as_str()))
+        .map(|field| format!("{} cannot be exported, fields must be some of {}", field, EXPORT_FIELDS.join(", ")))
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        Ok(fields)
+    } else {
+        Err(ApiError::ValidationError(unknown))
+    }
+}
+
+/// Write the selected fields of a user as a line in the export format
+fn export_line(format: DataFormat, user: &User, fields: &[String]) -> String {
+    let values = fields.iter().map(|field| {
+        let value = match field.as_str() {
+            "id" => Value::String(user.id.to_string()),
+            "first_name" => Value::String(user.first_name.clone()),
+            "last_name" => Value::String(user.last_name.clone()),
+            "email" => Value::String(user.email.clone()),
+            "created_at" => serde_json::to_value(user.created_at).unwrap_or(Value::Null),
+            "updated_at" => serde_json::to_value(user.updated_at).unwrap_or(Value::Null),
+            _ => Value::Null,
+        };
+        (field.clone(), value)
+    });
+    match format {
+        DataFormat::Csv => {
+            let values = values
+                .map(|(_, value)| match value {
+                    Value::String(value) => spreadsheet_safe(value),
+                    value => value.to_string(),
+                })
+                .collect::<Vec<_>>();
+            csv_line(&values)
+        }
+        DataFormat::Ndjson => format!("{}\n", Value::Object(values.collect())),
+    }
+}
+
+/// Keep spreadsheets from running a CSV value as a formula, by prefixing it with a quote
+fn spreadsheet_safe(value: String) -> String {
+    if value.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        format!("'{}", value)
+    } else {
+        value
+    }
+}
+
+impl ImportUserRow {
+    /// Imported users are created by the admin running the import
+    fn into_user(self, tenant: TenantId, admin_id: Uuid) -> User {
+        NewUser {
+            id: Uuid::new_v4(),
+            tenant_id: tenant,
+            first_name: self.first_name,
+            last_name: self.last_name,
+            email: self.email,
+            password: self.password.unwrap_or_default(),
+            created_by: admin_id,
+            updated_by: admin_id,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user::tests::create_user as model_create_user;
+
+    #[test]
+    fn it_parses_csv_rows() {
+        let text = "first_name,last_name,email,password\nSatoshi,Nakamoto,satoshi@example.com,123456\nHal,Finney\n";
+        let rows = parse_rows(DataFormat::Csv, text).unwrap();
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[0].1.as_ref().unwrap().email, "satoshi@example.com");
+        assert_eq!(rows[1].1, Err(vec!["expected 4 fields, got 2".to_string()]));
+    }
+
+    #[test]
+    fn it_rejects_unknown_csv_columns() {
+        let response = parse_rows(DataFormat::Csv, "email,salt1\n");
+        let expected_error = ApiError::ValidationError(vec!["unknown column salt1".to_string()]);
+        assert_eq!(response.unwrap_err(), expected_error);
+    }
+
+    #[test]
+    fn it_validates_rows() {
+        let existing = model_create_user().unwrap();
+        let in_use: HashSet<String> = vec![existing.email.clone()].into_iter().collect();
+        let mut seen = HashSet::new();
+        let row = ImportUserRow {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto".into(),
+            email: "Satoshi@Example.com".into(),
+            password: None,
+        };
+        assert_eq!(validate_row(&row, false, &in_use, &mut seen), vec!["password is required".to_string()]);
+        assert_eq!(validate_row(&row, true, &in_use, &mut seen), vec!["email is duplicated in the import".to_string()]);
+        let taken = ImportUserRow {
+            email: existing.email,
+            ..row
+        };
+        assert_eq!(validate_row(&taken, true, &in_use, &mut seen), vec!["email already in use".to_string()]);
+    }
+
+    #[test]
+    fn it_reads_ndjson_rows() {
+        let text = "{\"first_name\":\"Hal\",\"last_name\":\"Finney\",\"email\":\"hal@example.com\"}\n\nnot json\n";
+        let rows = parse_rows(DataFormat::Ndjson, text).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].1.is_ok());
+        assert!(rows[1].1.is_err());
+    }
+
+    #[test]
+    fn it_exports_selected_fields() {
+        let user: User = ImportUserRow {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto, Jr.".into(),
+            email: "satoshi@example.com".into(),
+            password: Some("123456".into()),
+        }
+        .into_user(TenantId::DEFAULT, Uuid::nil());
+        let fields = export_fields(Some("last_name,email")).unwrap();
+        assert_eq!(export_line(DataFormat::Csv, &user, &fields), "\"Nakamoto, Jr.\",satoshi@example.com\r\n");
+        assert_eq!(
+            export_line(DataFormat::Ndjson, &user, &fields),
+            "{\"email\":\"satoshi@example.com\",\"last_name\":\"Nakamoto, Jr.\"}\n"
+        );
+        assert!(export_fields(Some("password")).is_err());
+    }
+
+    #[test]
+    fn it_exports_csv_without_formulas() {
+        let user: User = ImportUserRow {
+            first_name: "=HYPERLINK(\"http://evil.example\")".into(),
+            last_name: "-Nakamoto".into(),
+            email: "@satoshi@example.com".into(),
+            password: Some("123456".into()),
+        }
+        .into_user(TenantId::DEFAULT, Uuid::nil());
+        let fields = export_fields(Some("first_name,last_name,email")).unwrap();
+        assert_eq!(
+            export_line(DataFormat::Csv, &user, &fields),
+            "\"'=HYPERLINK(\"\"http://evil.example\"\")\",'-Nakamoto,'@satoshi@example.com\r\n"
+        );
+        assert_eq!(spreadsheet_safe("Satoshi".into()), "Satoshi");
+    }
+}
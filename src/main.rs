@@ -1,3 +1,6 @@
+// diesel 1.4 expands table! and its derives to impls inside consts, which newer compilers flag
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 #[macro_use]
@@ -9,14 +12,20 @@ extern crate serde_derive;
 #[macro_use]
 extern crate validator_derive;
 
+use crate::cli::{Command, OPTS};
+use crate::commands::{create_admin, migrate, purge_deleted_users, seed_users};
+use crate::config::check_config;
 use crate::server::server;
 
 mod auth;
+mod cli;
+mod commands;
 mod config;
 mod database;
 pub mod handlers;
 mod middleware;
 mod models;
+mod outbox;
 mod routes;
 mod server_helpers;
 mod server;
@@ -25,5 +34,22 @@ mod validate;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    if OPTS.check_config {
+        check_config();
+    }
+    match &OPTS.command {
+        Some(Command::Migrate(command)) => migrate(*command),
+        Some(Command::Seed { file, tenant }) => seed_users(file, tenant),
+        Some(Command::CreateAdmin {
+            email,
+            first_name,
+            last_name,
+            tenant,
+        }) => create_admin(email, first_name, last_name, tenant),
+        None => {}
+    }
+    if OPTS.purge_deleted_users {
+        purge_deleted_users();
+    }
     server().await
 }
@@ -0,0 +1,198 @@
+//! Access logging
+//!
+//! Writes one line per request to the `access` log target. In text mode the
+//! line mirrors the actix `Logger` default format plus the request id, in
+//! json mode it is a single JSON object.
+
+use crate::auth::identity_user_id;
+use crate::config::logging::LogFormat;
+use crate::middleware::request_id::RequestId;
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, HeaderMap},
+    Error,
+};
+use chrono::Utc;
+use futures::{Future, future::{ok, Ready}};
+use log::info;
+use serde_json::{json, Map, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+/// Headers whose values must never end up in the logs
+const REDACTED_HEADERS: [&str; 5] = [
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-api-key",
+];
+const REDACTED: &str = "[REDACTED]";
+
+pub struct AccessLog {
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat) -> Self {
+        AccessLog { format }
+    }
+}
+
+impl<S, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware {
+            service,
+            format: self.format,
+        })
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    format: LogFormat,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let format = self.format;
+        // Read the identity before the handler runs, login/logout change it.
+        // With the JWT policy the identity is the bearer token, only its user id is logged.
+        let user_id = RequestIdentity::get_identity(&req)
+            .as_deref()
+            .and_then(identity_user_id);
+        let headers = redact_headers(req.headers());
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let latency = started.elapsed();
+            let request = res.request();
+            let request_id = request
+                .extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.0.clone());
+            let remote_addr = request
+                .connection_info()
+                .realip_remote_addr()
+                .map(|addr| addr.to_string());
+
+            match format {
+                LogFormat::Text => info!(
+                    target: ACCESS_LOG_TARGET,
+                    "{} \"{} {} {:?}\" {} \"{}\" \"{}\" {:.6} {}",
+                    remote_addr.as_deref().unwrap_or("-"),
+                    request.method(),
+                    request.uri(),
+                    request.version(),
+                    res.status().as_u16(),
+                    header_or_dash(request.headers(), header::REFERER),
+                    header_or_dash(request.headers(), header::USER_AGENT),
+                    latency.as_secs_f64(),
+                    request_id.as_deref().unwrap_or("-"),
+                ),
+                LogFormat::Json => info!(
+                    target: ACCESS_LOG_TARGET,
+                    "{}",
+                    json!({
+                        "timestamp": Utc::now().to_rfc3339(),
+                        "request_id": request_id,
+                        "method": request.method().as_str(),
+                        "route": request.match_pattern().unwrap_or_else(|| request.path().to_string()),
+                        "path": request.path(),
+                        "status": res.status().as_u16(),
+                        "latency_ms": latency.as_secs_f64() * 1000.0,
+                        "user_id": user_id,
+                        "remote_addr": remote_addr,
+                        "headers": headers,
+                    })
+                ),
+            }
+            Ok(res)
+        })
+    }
+}
+
+fn header_or_dash(headers: &HeaderMap, name: header::HeaderName) -> &str {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+}
+
+/// Collect the request headers into a JSON object, masking sensitive values
+fn redact_headers(headers: &HeaderMap) -> Map<String, Value> {
+    let mut redacted = Map::new();
+    for (name, value) in headers.iter() {
+        let name = name.as_str();
+        let value = if REDACTED_HEADERS.contains(&name) {
+            REDACTED
+        } else {
+            value.to_str().unwrap_or("[binary]")
+        };
+        match redacted.get_mut(name) {
+            Some(Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            _ => {
+                redacted.insert(name.to_string(), value.into());
+            }
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, COOKIE};
+
+    #[test]
+    fn it_redacts_sensitive_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        headers.insert(COOKIE, HeaderValue::from_static("auth=secret"));
+        headers.insert(
+            HeaderName::from_static("x-api-key"),
+            HeaderValue::from_static("secret"),
+        );
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let redacted = redact_headers(&headers);
+        assert_eq!(redacted["authorization"], REDACTED);
+        assert_eq!(redacted["cookie"], REDACTED);
+        assert_eq!(redacted["x-api-key"], REDACTED);
+        assert_eq!(redacted["accept"], "application/json");
+    }
+}
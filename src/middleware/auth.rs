@@ -1,4 +1,5 @@
-use crate::auth::{decode_jwt, PrivateClaim};
+use crate::auth::{decode_jwt, identity_user_id, session_epoch, PrivateClaim, SESSION_EPOCH_KEY};
+use crate::server_helpers::cache::Cache;
 use crate::server_helpers::errors::ApiError;
 use actix_identity::RequestIdentity;
 use actix_session::UserSession;
@@ -8,14 +9,16 @@ use actix_web::{
     Error, HttpResponse,
 };
 use futures::{Future, future::{ok, Ready}};
+use std::cell::RefCell;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
 pub struct Auth;
 
 impl<S, B> Transform<S> for Auth
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Request = ServiceRequest;
@@ -26,16 +29,19 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddleware { service })
+        ok(AuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
     }
 }
 pub struct AuthMiddleware<S> {
-    service: S,
+    // Shared so that the session check can finish before the request is passed on
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for AuthMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Request = ServiceRequest;
@@ -44,10 +50,11 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
     fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {        
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let _span = tracing::info_span!("middleware::auth").entered();
         let identity = RequestIdentity::get_identity(&req);
         // WIZ_OPT: jwt check
         /*
@@ -65,11 +72,36 @@ where
             })
         }
 
-        let fut = self.service.call(req);
+        let service = self.service.clone();
 
         Box::pin(async move {
+            if !is_current_session(&req, identity).await? {
+                req.get_session().purge();
+                return Ok(req.into_response(HttpResponse::Unauthorized().finish().into_body()));
+            }
+            // Release the borrow before awaiting, other requests on this worker need the service meanwhile
+            let fut = service.borrow_mut().call(req);
             let res = fut.await?;
             Ok(res)
         })
     }
 }
+
+/// Check that the session was not invalidated, e.g. by a password change
+/// Sessions are valid while their epoch matches the user's current one
+async fn is_current_session(req: &ServiceRequest, identity: Option<String>) -> Result<bool, ApiError> {
+    let user_id = match identity.as_deref().and_then(identity_user_id) {
+        Some(user_id) => user_id,
+        None => return Ok(true),
+    };
+    let redis = match req.app_data::<Cache>() {
+        Some(redis) => redis.clone(),
+        None => return Ok(true),
+    };
+    let epoch = req
+        .get_session()
+        .get::<i64>(SESSION_EPOCH_KEY)
+        .unwrap_or(None)
+        .unwrap_or(0);
+    Ok(epoch == session_epoch(redis, user_id).await?)
+}
@@ -1,3 +1,6 @@
+pub mod access_log;
 pub mod auth;
 pub mod redis_identity;
-pub mod redirect_https;
\ No newline at end of file
+pub mod redirect_https;
+pub mod request_id;
+pub mod telemetry;
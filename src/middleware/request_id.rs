@@ -0,0 +1,126 @@
+//! Assign every request an id that can be used to correlate log lines.
+//!
+//! An incoming `X-Request-Id` header is reused when it looks sane,
+//! otherwise a new v4 uuid is generated. The id is stored in the request
+//! extensions (see the `RequestId` extractor) and echoed back in the
+//! response headers.
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures::{Future, future::{ok, Ready}};
+use log::error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The id of the current request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn new() -> Self {
+        RequestId(Uuid::new_v4().to_string())
+    }
+}
+
+pub struct RequestIdentifier;
+
+impl<S, B> Transform<S> for RequestIdentifier
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdentifierMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdentifierMiddleware { service })
+    }
+}
+
+pub struct RequestIdentifierMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdentifierMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(|value| RequestId(value.to_string()))
+            .unwrap_or_else(RequestId::new);
+        req.extensions_mut().insert(request_id.clone());
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(error) = res.response().error() {
+                error!(
+                    "request_id={} status={} error={:?}",
+                    request_id.0,
+                    res.status().as_u16(),
+                    error
+                );
+            }
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Only accept client supplied ids that are short and printable, so they
+/// cannot be used to inject anything into the logs
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_accepts_a_uuid_request_id() {
+        assert!(is_valid_request_id(&RequestId::new().0));
+    }
+
+    #[test]
+    fn it_rejects_an_invalid_request_id() {
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("abc\"} injected"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+}
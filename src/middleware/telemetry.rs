@@ -0,0 +1,181 @@
+//! Open a tracing span for every request
+//!
+//! The span continues the trace of an incoming W3C `traceparent` header and
+//! the trace context is written back to the response headers. Everything
+//! below this middleware (other middleware, handlers, queries) is recorded
+//! as a child of the request span.
+
+use crate::middleware::request_id::RequestId;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    http::HeaderMap,
+    Error,
+};
+use futures::{Future, future::{ok, Ready}};
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context as OtelContext;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{field, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub struct Telemetry;
+
+impl<S, B> Transform<S> for Telemetry
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TelemetryMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TelemetryMiddleware { service })
+    }
+}
+
+pub struct TelemetryMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for TelemetryMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let parent = remote_parent(req.headers());
+        let span = info_span!(
+            "HTTP request",
+            http.method = %req.method(),
+            http.target = %req.path(),
+            http.route = field::Empty,
+            http.status_code = field::Empty,
+            request_id = field::Empty,
+        );
+        span.set_parent(parent);
+
+        let fut = span.in_scope(|| self.service.call(req));
+        let request_span = span.clone();
+
+        Box::pin(
+            async move {
+                let mut res = fut.await?;
+                span.record("http.status_code", res.status().as_u16());
+                if let Some(route) = res.request().match_pattern() {
+                    span.record("http.route", route.as_str());
+                }
+                if let Some(request_id) = res.request().extensions().get::<RequestId>() {
+                    span.record("request_id", request_id.0.as_str());
+                }
+                global::get_text_map_propagator(|propagator| {
+                    propagator.inject_context(&span.context(), &mut HeaderInjector(res.headers_mut()))
+                });
+                Ok(res)
+            }
+            .instrument(request_span),
+        )
+    }
+}
+
+/// The trace context of an incoming `traceparent` header, a new trace starts without one
+///
+/// The propagator stores a remote span context, the request span is parented on it.
+fn remote_parent(headers: &HeaderMap) -> OtelContext {
+    let extracted = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    match extracted.remote_span_context() {
+        Some(span_context) => OtelContext::new().with_remote_span_context(span_context.clone()),
+        None => OtelContext::new(),
+    }
+}
+
+/// Read trace context from actix request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Write trace context into actix response headers
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::TracerProvider as SdkTracerProvider;
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn it_propagates_a_traceparent_header() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("traceparent"),
+            HeaderValue::from_static(TRACEPARENT),
+        );
+        let parent = remote_parent(&headers);
+        assert!(parent.remote_span_context().unwrap().is_remote());
+
+        // Like TRACE_EXPORTER=none: spans get ids, nothing exports them
+        let provider = SdkTracerProvider::builder().build();
+        let tracer = provider.get_tracer("test", None);
+        let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let mut response_headers = HeaderMap::new();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("HTTP request");
+            span.set_parent(parent);
+            global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(&span.context(), &mut HeaderInjector(&mut response_headers))
+            });
+        });
+
+        let traceparent = response_headers.get("traceparent").unwrap().to_str().unwrap();
+        // Same trace, the request span is the new parent
+        assert_eq!(&traceparent[..36], &TRACEPARENT[..36]);
+        assert_ne!(traceparent, TRACEPARENT);
+    }
+
+    #[test]
+    fn it_starts_a_new_trace_without_a_traceparent_header() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        assert!(remote_parent(&HeaderMap::new()).remote_span_context().is_none());
+    }
+}
@@ -0,0 +1,264 @@
+//! Audit trail of every mutation: who changed what, from where and in which request
+
+use crate::database::connection::{Conn, PoolType};
+use crate::database::schema::audit_log;
+use crate::database::transaction::unit_of_work;
+use crate::database::types::{DbUuid, NullableDbUuid};
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::Page;
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::Mysql;
+use diesel::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tracing::instrument;
+use uuid::Uuid;
+
+/// Fields whose values never reach the audit log, only the fact that they changed
+const REDACTED_FIELDS: [&str; 4] = ["password", "salt1", "salt2", "code"];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable)]
+pub struct AuditEntry {
+    pub id: i64,
+    #[diesel(deserialize_as = "NullableDbUuid")]
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: String,
+    #[diesel(deserialize_as = "NullableDbUuid")]
+    pub target_id: Option<Uuid>,
+    pub changes: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry {
+    pub actor_id: Option<DbUuid>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<DbUuid>,
+    pub changes: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Who made a request and from where, see the extractor
+#[derive(Clone, Debug, Default)]
+pub struct AuditContext {
+    pub actor_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AuditContext {
+    /// Describe a mutation made in this request
+    pub fn entry(&self, action: &str, target_type: &str, target_id: Option<Uuid>, changes: Changes) -> NewAuditEntry {
+        NewAuditEntry {
+            actor_id: self.actor_id.map(DbUuid),
+            action: action.into(),
+            target_type: target_type.into(),
+            target_id: target_id.map(DbUuid),
+            changes: changes.into_column(),
+            ip: self.ip.clone(),
+            request_id: self.request_id.clone(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Run the request's writes as a unit of work, see `transaction::unit_of_work`
+    /// Once committed, the actor reads from the primary for READ_YOUR_WRITES_WINDOW
+    pub fn unit_of_work<T, F>(&self, pool: &PoolType, work: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&Conn) -> Result<T, ApiError>,
+    {
+        let result = unit_of_work(pool, work)?;
+        if let Some(actor_id) = self.actor_id {
+            pool.note_write(actor_id);
+        }
+        Ok(result)
+    }
+}
+
+/// Changed fields with their values before and after a mutation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes(Map<String, Value>);
+
+impl Changes {
+    pub fn created<T: Serialize>(after: &T) -> Self {
+        Changes::diff(Map::new(), snapshot(after))
+    }
+
+    pub fn updated<B: Serialize, A: Serialize>(before: &B, after: &A) -> Self {
+        Changes::diff(snapshot(before), snapshot(after))
+    }
+
+    pub fn deleted<T: Serialize>(before: &T) -> Self {
+        Changes::diff(snapshot(before), Map::new())
+    }
+
+    /// Mark fields as changed without recording their values
+    pub fn redacted(fields: &[&str]) -> Self {
+        let changes = fields
+            .iter()
+            .map(|field| (field.to_string(), json!({ "before": REDACTED, "after": REDACTED })))
+            .collect();
+        Changes(changes)
+    }
+
+    fn diff(before: Map<String, Value>, after: Map<String, Value>) -> Self {
+        let mut changes = Map::new();
+        let fields = before.keys().chain(after.keys().filter(|field| !before.contains_key(*field)));
+        for field in fields {
+            let old = before.get(field).unwrap_or(&Value::Null);
+            let new = after.get(field).unwrap_or(&Value::Null);
+            if old != new {
+                let change = json!({ "before": redact(field, old), "after": redact(field, new) });
+                changes.insert(field.clone(), change);
+            }
+        }
+        Changes(changes)
+    }
+
+    fn into_column(self) -> Option<String> {
+        if self.0.is_empty() {
+            None
+        } else {
+            Some(Value::Object(self.0).to_string())
+        }
+    }
+}
+
+fn snapshot<T: Serialize>(value: &T) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    }
+}
+
+fn redact(field: &str, value: &Value) -> Value {
+    if REDACTED_FIELDS.contains(&field) && !value.is_null() {
+        json!(REDACTED)
+    } else {
+        value.clone()
+    }
+}
+
+/// Filters and page for querying the audit log
+#[derive(Clone, Debug)]
+pub struct AuditListParams {
+    pub actor_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub page: Page,
+}
+
+/// Record a mutation, in the same unit of work as the mutation itself
+#[instrument(name = "audit::record", skip(conn, entry), fields(action = %entry.action), err)]
+pub fn record(conn: &Conn, entry: &NewAuditEntry) -> Result<(), ApiError> {
+    use crate::database::schema::audit_log::dsl::audit_log;
+
+    diesel::insert_into(audit_log).values(entry).execute(conn)?;
+    Ok(())
+}
+
+/// Get a page of audit entries, newest first, along with the total count of matching entries
+#[instrument(name = "audit::list", skip(pool), err)]
+pub fn list(pool: &PoolType, params: &AuditListParams) -> Result<(Vec<AuditEntry>, i64), ApiError> {
+    use crate::database::schema::audit_log::dsl::{created_at, id};
+
+    let conn = pool.read()?;
+    let total = filtered(params).count().get_result::<i64>(&conn)?;
+    let entries = filtered(params)
+        .order((created_at.desc(), id.desc()))
+        .limit(params.page.limit)
+        .offset(params.page.offset)
+        .load::<AuditEntry>(&conn)?;
+    Ok((entries, total))
+}
+
+/// Build the filtered audit query, shared by the page and count queries
+fn filtered(params: &AuditListParams) -> audit_log::BoxedQuery<'static, Mysql> {
+    use crate::database::schema::audit_log::dsl::*;
+
+    let mut query = audit_log.into_boxed();
+    if let Some(actor) = params.actor_id {
+        query = query.filter(actor_id.eq(DbUuid(actor)));
+    }
+    if let Some(entry_action) = &params.action {
+        query = query.filter(action.eq(entry_action.clone()));
+    }
+    if let Some(entry_target_type) = &params.target_type {
+        query = query.filter(target_type.eq(entry_target_type.clone()));
+    }
+    if let Some(target) = params.target_id {
+        query = query.filter(target_id.eq(DbUuid(target)));
+    }
+    if let Some(from) = params.from {
+        query = query.filter(created_at.ge(from));
+    }
+    if let Some(to) = params.to {
+        query = query.filter(created_at.le(to));
+    }
+    query
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::{get_conn, get_pool};
+
+    #[test]
+    fn it_records_only_changed_fields() {
+        let before = json!({ "first_name": "Satoshi", "email": "satoshi@nakamotoinstitute.org" });
+        let after = json!({ "first_name": "Hal", "email": "satoshi@nakamotoinstitute.org" });
+        let changes = Changes::updated(&before, &after).into_column().unwrap();
+        assert_eq!(changes, r#"{"first_name":{"after":"Hal","before":"Satoshi"}}"#);
+    }
+
+    #[test]
+    fn it_redacts_secrets() {
+        let after = json!({ "email": "satoshi@nakamotoinstitute.org", "password": "hash", "salt1": "salt" });
+        let changes: Value = serde_json::from_str(&Changes::created(&after).into_column().unwrap()).unwrap();
+        assert_eq!(changes["password"], json!({ "before": null, "after": REDACTED }));
+        assert_eq!(changes["salt1"]["after"], REDACTED);
+        assert_eq!(changes["email"]["after"], "satoshi@nakamotoinstitute.org");
+    }
+
+    #[test]
+    fn it_skips_empty_changes() {
+        let user = json!({ "first_name": "Satoshi" });
+        assert_eq!(Changes::updated(&user, &user).into_column(), None);
+    }
+
+    #[test]
+    fn it_records_and_lists_entries() {
+        let target_id = Uuid::new_v4();
+        let context = AuditContext {
+            actor_id: Some(Uuid::new_v4()),
+            ip: Some("127.0.0.1".into()),
+            request_id: Some("test-request".into()),
+        };
+        let entry = context.entry("user.test", "user", Some(target_id), Changes::redacted(&["password"]));
+        record(&get_conn(), &entry).unwrap();
+        let params = AuditListParams {
+            actor_id: None,
+            action: None,
+            target_type: Some("user".into()),
+            target_id: Some(target_id),
+            from: None,
+            to: None,
+            page: Page::new(None, None),
+        };
+        let (entries, total) = list(&get_pool(), &params).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(entries[0].action, "user.test");
+        assert_eq!(entries[0].request_id, Some("test-request".into()));
+    }
+}
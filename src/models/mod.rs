@@ -1 +1,6 @@
-pub mod user;
\ No newline at end of file
+pub mod audit;
+pub mod outbox;
+pub mod tenant;
+pub mod user;
+#[cfg(feature = "async-db")]
+pub mod user_async;
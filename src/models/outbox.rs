@@ -0,0 +1,213 @@
+//! Transactional outbox: events are written in the same transaction as the changes they describe
+//! and published afterwards by the outbox publisher, see `crate::outbox`
+
+use crate::database::connection::Conn;
+use crate::database::schema::outbox;
+use crate::database::transaction::savepoint;
+use crate::database::types::DbUuid;
+use crate::server_helpers::errors::ApiError;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::instrument;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable)]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub event_type: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub aggregate_id: Uuid,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "outbox"]
+pub struct NewOutboxEvent {
+    pub event_type: String,
+    pub aggregate_id: DbUuid,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub next_attempt_at: NaiveDateTime,
+}
+
+impl NewOutboxEvent {
+    /// Describe a change to an aggregate, eg. "user.updated" with the user's new state
+    pub fn new<T: Serialize>(event_type: &str, aggregate_id: Uuid, data: &T) -> Result<Self, ApiError> {
+        let payload = serde_json::to_string(data).map_err(|error| ApiError::InternalServerError(error.to_string()))?;
+        let now = Utc::now().naive_utc();
+        Ok(NewOutboxEvent {
+            event_type: event_type.into(),
+            aggregate_id: DbUuid(aggregate_id),
+            payload,
+            created_at: now,
+            next_attempt_at: now,
+        })
+    }
+}
+
+impl OutboxEvent {
+    /// The event as sinks publish it
+    /// Delivery is at least once, consumers drop duplicates by id.
+    pub fn envelope(&self) -> Value {
+        let data = serde_json::from_str(&self.payload).unwrap_or(Value::Null);
+        json!({
+            "id": self.id,
+            "type": self.event_type,
+            "aggregate_id": self.aggregate_id,
+            "created_at": self.created_at,
+            "data": data,
+        })
+    }
+}
+
+/// Write events in the caller's unit of work, they are only published once it commits
+#[instrument(name = "outbox::enqueue", skip(conn, events), fields(count = events.len()), err)]
+pub fn enqueue(conn: &Conn, events: &[NewOutboxEvent]) -> Result<(), ApiError> {
+    use crate::database::schema::outbox::dsl::outbox;
+
+    diesel::insert_into(outbox).values(events).execute(conn)?;
+    Ok(())
+}
+
+/// Claim a batch of due events, oldest first
+/// They are not due again for the lease, so that other publishers leave them alone while they are delivered.
+/// Events whose publisher dies before marking them are retried once the lease is over.
+#[instrument(name = "outbox::claim", skip(conn), err)]
+pub fn claim(conn: &Conn, limit: i64, lease: Duration) -> Result<Vec<OutboxEvent>, ApiError> {
+    use crate::database::schema::outbox::dsl::{delivered_at, id, next_attempt_at, outbox};
+
+    savepoint(conn, |conn| {
+        let now = Utc::now().naive_utc();
+        let due = outbox
+            .filter(delivered_at.is_null())
+            .filter(next_attempt_at.le(now))
+            .order(id.asc())
+            .limit(limit)
+            .for_update()
+            .load::<OutboxEvent>(conn)?;
+        let ids = due.iter().map(|event| event.id).collect::<Vec<_>>();
+        if !ids.is_empty() {
+            diesel::update(outbox.filter(id.eq_any(ids)))
+                .set(next_attempt_at.eq(now + lease))
+                .execute(conn)?;
+        }
+        Ok(due)
+    })
+}
+
+/// Mark events as delivered to every sink
+#[instrument(name = "outbox::mark_delivered", skip(conn, ids), fields(count = ids.len()), err)]
+pub fn mark_delivered(conn: &Conn, ids: &[i64]) -> Result<(), ApiError> {
+    use crate::database::schema::outbox::dsl::{attempts, delivered_at, id, last_error, outbox};
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+    diesel::update(outbox.filter(id.eq_any(ids)))
+        .set((
+            attempts.eq(attempts + 1),
+            last_error.eq(None::<String>),
+            delivered_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Note a failed delivery, the event is due again at retry_at
+#[instrument(name = "outbox::mark_failed", skip(conn, error), err)]
+pub fn mark_failed(conn: &Conn, event_id: i64, error: &str, retry_at: NaiveDateTime) -> Result<(), ApiError> {
+    use crate::database::schema::outbox::dsl::{attempts, id, last_error, next_attempt_at, outbox};
+
+    diesel::update(outbox.filter(id.eq(event_id)))
+        .set((
+            attempts.eq(attempts + 1),
+            last_error.eq(error),
+            next_attempt_at.eq(retry_at),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Delete the events delivered before the cutoff
+/// Returns the number of deleted events
+#[instrument(name = "outbox::remove_delivered", skip(conn), err)]
+pub fn remove_delivered(conn: &Conn, delivered_before: NaiveDateTime) -> Result<usize, ApiError> {
+    use crate::database::schema::outbox::dsl::{delivered_at, outbox};
+
+    let removed = diesel::delete(outbox.filter(delivered_at.lt(delivered_before))).execute(conn)?;
+    Ok(removed)
+}
+
+/// Get the events of an aggregate, oldest first
+#[allow(dead_code)]
+#[instrument(name = "outbox::for_aggregate", skip(conn), err)]
+pub fn for_aggregate(conn: &Conn, aggregate: Uuid) -> Result<Vec<OutboxEvent>, ApiError> {
+    use crate::database::schema::outbox::dsl::{aggregate_id, id, outbox};
+
+    let events = outbox
+        .filter(aggregate_id.eq(DbUuid(aggregate)))
+        .order(id.asc())
+        .load::<OutboxEvent>(conn)?;
+    Ok(events)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_conn;
+
+    #[test]
+    fn it_wraps_events_in_an_envelope() {
+        let aggregate = Uuid::new_v4();
+        let new_event = NewOutboxEvent::new("user.test", aggregate, &json!({ "first_name": "Satoshi" })).unwrap();
+        let event = OutboxEvent {
+            id: 7,
+            event_type: new_event.event_type,
+            aggregate_id: aggregate,
+            payload: new_event.payload,
+            created_at: new_event.created_at,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: new_event.next_attempt_at,
+            delivered_at: None,
+        };
+        let envelope = event.envelope();
+        assert_eq!(envelope["id"], 7);
+        assert_eq!(envelope["type"], "user.test");
+        assert_eq!(envelope["aggregate_id"], json!(aggregate));
+        assert_eq!(envelope["data"]["first_name"], "Satoshi");
+    }
+
+    #[test]
+    fn it_tracks_delivery_attempts() {
+        let conn = get_conn();
+        let aggregate = Uuid::new_v4();
+        // Not due, so that publishers in other tests leave it alone
+        let mut new_event = NewOutboxEvent::new("user.test", aggregate, &json!({})).unwrap();
+        new_event.next_attempt_at += Duration::hours(1);
+        enqueue(&conn, &[new_event]).unwrap();
+        let event = for_aggregate(&conn, aggregate).unwrap().remove(0);
+        assert_eq!((event.attempts, event.delivered_at), (0, None));
+
+        let retry_at = Utc::now().naive_utc() + Duration::minutes(5);
+        mark_failed(&conn, event.id, "webhook: 503", retry_at).unwrap();
+        let failed = for_aggregate(&conn, aggregate).unwrap().remove(0);
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(failed.last_error.as_deref(), Some("webhook: 503"));
+        assert!(failed.next_attempt_at > Utc::now().naive_utc());
+
+        mark_delivered(&conn, &[event.id]).unwrap();
+        let delivered = for_aggregate(&conn, aggregate).unwrap().remove(0);
+        assert_eq!(delivered.attempts, 2);
+        assert!(delivered.delivered_at.is_some());
+        assert!(remove_delivered(&conn, Utc::now().naive_utc() + Duration::seconds(1)).unwrap() >= 1);
+        assert!(for_aggregate(&conn, aggregate).unwrap().is_empty());
+    }
+}
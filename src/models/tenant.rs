@@ -0,0 +1,230 @@
+//! Tenants: the customers served by one deployment, every user belongs to exactly one
+//!
+//! The `TenantId` extractor resolves the tenant of a request, `models::user` scopes every query to it.
+//! The built-in default tenant holds the users from before tenants existed, its admins manage the tenants.
+
+use crate::database::connection::Conn;
+use crate::database::schema::tenants;
+use crate::database::types::DbUuid;
+use crate::models::user::AuthUser;
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::Page;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use std::fmt;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// The tenant a request acts in, see the extractor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TenantId(pub Uuid);
+
+impl TenantId {
+    /// The built-in tenant, created by the migration that introduced tenants
+    pub const DEFAULT: TenantId = TenantId(Uuid::nil());
+}
+
+/// Tokens from before tenants existed belong to the default tenant
+impl Default for TenantId {
+    fn default() -> Self {
+        TenantId::DEFAULT
+    }
+}
+
+impl fmt::Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<TenantId> for DbUuid {
+    fn from(tenant: TenantId) -> Self {
+        DbUuid(tenant.0)
+    }
+}
+
+/// An admin of the default tenant, who manages the tenants, see the extractor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OperatorUser {
+    pub id: Uuid,
+}
+
+impl From<OperatorUser> for AuthUser {
+    fn from(operator: OperatorUser) -> Self {
+        AuthUser { id: operator.id }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable)]
+pub struct Tenant {
+    #[diesel(deserialize_as = "DbUuid")]
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub updated_by: Uuid,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A tenant as it is inserted, with the ids bound as BINARY(16)
+#[derive(Insertable)]
+#[table_name = "tenants"]
+struct TenantRow<'a> {
+    id: DbUuid,
+    slug: &'a str,
+    name: &'a str,
+    created_by: DbUuid,
+    created_at: NaiveDateTime,
+    updated_by: DbUuid,
+    updated_at: NaiveDateTime,
+}
+
+/// Get a page of tenants ordered by slug, along with the total count
+#[instrument(name = "tenants::list", skip(conn), err)]
+pub fn list(conn: &Conn, page: Page) -> Result<(Vec<Tenant>, i64), ApiError> {
+    use crate::database::schema::tenants::dsl::{slug, tenants};
+
+    let total = tenants.count().get_result(conn)?;
+    let page = tenants
+        .order(slug.asc())
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<Tenant>(conn)?;
+    Ok((page, total))
+}
+
+/// Find a tenant by its id
+#[instrument(name = "tenants::find", skip(conn), err)]
+pub fn find(conn: &Conn, tenant_id: Uuid) -> Result<Option<Tenant>, ApiError> {
+    use crate::database::schema::tenants::dsl::tenants;
+
+    Ok(tenants.find(DbUuid(tenant_id)).first::<Tenant>(conn).optional()?)
+}
+
+/// Find a tenant by the slug used in subdomains and the tenant header
+#[instrument(name = "tenants::find_by_slug", skip(conn), err)]
+pub fn find_by_slug(conn: &Conn, tenant_slug: &str) -> Result<Option<Tenant>, ApiError> {
+    use crate::database::schema::tenants::dsl::{slug, tenants};
+
+    Ok(tenants.filter(slug.eq(tenant_slug.to_lowercase())).first::<Tenant>(conn).optional()?)
+}
+
+/// Get the ids of all tenants, for jobs that run in every tenant
+#[instrument(name = "tenants::all_ids", skip(conn), err)]
+pub fn all_ids(conn: &Conn) -> Result<Vec<TenantId>, ApiError> {
+    use crate::database::schema::tenants::dsl::{id, tenants};
+
+    let ids = tenants.select(id).order(id.asc()).load::<DbUuid>(conn)?;
+    Ok(ids.into_iter().map(|tenant_id| TenantId(tenant_id.0)).collect())
+}
+
+/// Create a tenant
+#[instrument(name = "tenants::create", skip(conn), err)]
+pub fn create(conn: &Conn, tenant_id: Uuid, tenant_slug: &str, tenant_name: &str, actor_id: Uuid) -> Result<(), ApiError> {
+    use crate::database::schema::tenants::dsl::tenants;
+
+    let now = Utc::now().naive_utc();
+    let row = TenantRow {
+        id: DbUuid(tenant_id),
+        slug: &tenant_slug.to_lowercase(),
+        name: tenant_name,
+        created_by: DbUuid(actor_id),
+        created_at: now,
+        updated_by: DbUuid(actor_id),
+        updated_at: now,
+    };
+    diesel::insert_into(tenants).values(&row).execute(conn)?;
+    Ok(())
+}
+
+/// Rename a tenant or change its slug
+#[instrument(name = "tenants::update", skip(conn), err)]
+pub fn update(conn: &Conn, tenant_id: Uuid, tenant_slug: &str, tenant_name: &str, actor_id: Uuid) -> Result<(), ApiError> {
+    use crate::database::schema::tenants::dsl::{id, name, slug, tenants, updated_at, updated_by};
+
+    diesel::update(tenants.filter(id.eq(DbUuid(tenant_id))))
+        .set((
+            slug.eq(tenant_slug.to_lowercase()),
+            name.eq(tenant_name),
+            updated_by.eq(DbUuid(actor_id)),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Delete a tenant without users, the default tenant stays
+#[instrument(name = "tenants::delete", skip(conn), err)]
+pub fn delete(conn: &Conn, tenant_id: Uuid) -> Result<(), ApiError> {
+    use crate::database::schema::tenants::dsl::{id, tenants};
+    use crate::database::schema::users::dsl::{tenant_id as user_tenant_id, users};
+
+    if TenantId(tenant_id) == TenantId::DEFAULT {
+        return Err(ApiError::BadRequest("The default tenant cannot be deleted".into()));
+    }
+    // Soft deleted users count as well, they are only gone once purged
+    let user_count = users
+        .filter(user_tenant_id.eq(DbUuid(tenant_id)))
+        .count()
+        .get_result::<i64>(conn)?;
+    if user_count > 0 {
+        return Err(ApiError::BadRequest(format!("Tenant {} still has {} users", tenant_id, user_count)));
+    }
+    diesel::delete(tenants.filter(id.eq(DbUuid(tenant_id)))).execute(conn)?;
+    Ok(())
+}
+
+/// Check a slug: lowercase letters, digits and inner hyphens, like a DNS label
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 63
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_conn;
+
+    /// Create a tenant with a unique slug
+    pub fn create_tenant(conn: &Conn) -> TenantId {
+        let tenant_id = Uuid::new_v4();
+        let slug = format!("test-{}", tenant_id.to_simple());
+        create(conn, tenant_id, &slug, "Test", Uuid::new_v4()).unwrap();
+        TenantId(tenant_id)
+    }
+
+    #[test]
+    fn it_checks_slugs() {
+        assert!(is_valid_slug("acme-2"));
+        assert!(!is_valid_slug("Acme"));
+        assert!(!is_valid_slug("-acme"));
+        assert!(!is_valid_slug("acme.example"));
+        assert!(!is_valid_slug(""));
+    }
+
+    #[test]
+    fn it_finds_tenants_by_slug() {
+        let conn = get_conn();
+        let tenant = create_tenant(&conn);
+        let found = find(&conn, tenant.0).unwrap().unwrap();
+        assert_eq!(find_by_slug(&conn, &found.slug.to_uppercase()).unwrap(), Some(found));
+        assert_eq!(find_by_slug(&conn, "nothing-here").unwrap(), None);
+        assert!(all_ids(&conn).unwrap().contains(&TenantId::DEFAULT));
+    }
+
+    #[test]
+    fn it_keeps_the_default_tenant() {
+        let conn = get_conn();
+        assert!(delete(&conn, TenantId::DEFAULT.0).is_err());
+        let tenant = create_tenant(&conn);
+        delete(&conn, tenant.0).unwrap();
+        assert_eq!(find(&conn, tenant.0).unwrap(), None);
+    }
+}
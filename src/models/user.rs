@@ -1,75 +1,433 @@
-use crate::auth::hash;
-use crate::database::connection::PoolType;
+use crate::auth::{check_password_strength, hash};
+use crate::database::connection::{Conn, PoolType};
+use crate::database::types::{DbUuid, NullableDbUuid};
+use crate::server_helpers::conditional::ExpectedVersion;
 use crate::server_helpers::errors::ApiError;
 use crate::handlers::user::{UserResponse, UsersResponse};
 use crate::database::schema::users;
+use crate::database::transaction::savepoint;
+use crate::models::outbox::{enqueue, NewOutboxEvent};
+use crate::models::tenant::TenantId;
+use crate::server_helpers::pagination::Page;
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::sql;
+use diesel::mysql::Mysql;
 use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Double, Text};
+use tracing::instrument;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable)]
+#[cfg_attr(feature = "async-db", derive(sqlx::FromRow))]
 pub struct User {
-    pub id: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub password: String,
     pub salt1: String,
     pub salt2: String,
-    pub created_by: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub created_by: Uuid,
     pub created_at: NaiveDateTime,
-    pub updated_by: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub updated_by: Uuid,
     pub updated_at: NaiveDateTime,
+    pub is_admin: bool,
+    pub deleted_at: Option<NaiveDateTime>,
+    #[diesel(deserialize_as = "NullableDbUuid")]
+    pub deleted_by: Option<Uuid>,
+    pub version: i32,
+    pub email_normalized: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub tenant_id: Uuid,
+}
+
+/// A user as it is inserted, with the ids bound as BINARY(16)
+#[derive(Insertable)]
+#[table_name = "users"]
+struct UserRow<'a> {
+    id: DbUuid,
+    first_name: &'a str,
+    last_name: &'a str,
+    email: &'a str,
+    password: &'a str,
+    salt1: &'a str,
+    salt2: &'a str,
+    created_by: DbUuid,
+    created_at: NaiveDateTime,
+    updated_by: DbUuid,
+    updated_at: NaiveDateTime,
+    is_admin: bool,
+    deleted_at: Option<NaiveDateTime>,
+    deleted_by: Option<DbUuid>,
+    version: i32,
+    email_normalized: &'a str,
+    tenant_id: DbUuid,
+}
+
+impl<'a> From<&'a User> for UserRow<'a> {
+    fn from(user: &'a User) -> Self {
+        UserRow {
+            id: user.id.into(),
+            first_name: &user.first_name,
+            last_name: &user.last_name,
+            email: &user.email,
+            password: &user.password,
+            salt1: &user.salt1,
+            salt2: &user.salt2,
+            created_by: user.created_by.into(),
+            created_at: user.created_at,
+            updated_by: user.updated_by.into(),
+            updated_at: user.updated_at,
+            is_admin: user.is_admin,
+            deleted_at: user.deleted_at,
+            deleted_by: user.deleted_by.map(DbUuid),
+            version: user.version,
+            email_normalized: &user.email_normalized,
+            tenant_id: user.tenant_id.into(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewUser {
-    pub id: String,
+    pub id: Uuid,
+    pub tenant_id: TenantId,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub password: String,
-    pub created_by: String,
-    pub updated_by: String,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
 #[table_name = "users"]
 pub struct UpdateUser {
-    pub id: String,
+    pub id: DbUuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub email_normalized: String,
+    pub updated_by: DbUuid,
+}
+
+/// Changeset for partial updates, only the Some(_) fields are written
+#[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
+#[table_name = "users"]
+pub struct PatchUser {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    pub email_normalized: Option<String>,
+    pub updated_by: DbUuid,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Outbox event types of the user mutations, see `models::outbox`
+pub const USER_CREATED: &str = "user.created";
+pub const USER_UPDATED: &str = "user.updated";
+pub const USER_DELETED: &str = "user.deleted";
+pub const USER_RESTORED: &str = "user.restored";
+
+/// A user as events carry it, without the password and salts
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserEvent {
+    pub id: Uuid,
+    pub tenant_id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
-    pub updated_by: String,
+    pub is_admin: bool,
+    pub version: i32,
+    pub updated_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
+impl From<&User> for UserEvent {
+    fn from(user: &User) -> Self {
+        UserEvent {
+            id: user.id,
+            tenant_id: user.tenant_id,
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            email: user.email.clone(),
+            is_admin: user.is_admin,
+            version: user.version,
+            updated_at: user.updated_at,
+            deleted_at: user.deleted_at,
+        }
+    }
+}
+
+/// The logged in user, see the extractor
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AuthUser {
-    pub id: String,
-    pub email: String,
+    pub id: Uuid,
+}
+
+/// A logged in user with the admin flag, see the extractor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminUser {
+    pub id: Uuid,
+    /// The tenant the user is an admin of
+    pub tenant_id: TenantId,
+}
+
+impl From<AdminUser> for AuthUser {
+    fn from(admin: AdminUser) -> Self {
+        AuthUser { id: admin.id }
+    }
+}
+
+/// Columns the user list can be sorted by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserSort {
+    FirstName,
+    LastName,
+    Email,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl UserSort {
+    pub const ALLOWED: &'static str = "first_name, last_name, email, created_at, updated_at";
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first_name" => Some(UserSort::FirstName),
+            "last_name" => Some(UserSort::LastName),
+            "email" => Some(UserSort::Email),
+            "created_at" => Some(UserSort::CreatedAt),
+            "updated_at" => Some(UserSort::UpdatedAt),
+            _ => None,
+        }
+    }
+}
+
+/// Filters, sorting and page for listing users
+#[derive(Clone, Debug)]
+pub struct UserListParams {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub created_from: Option<NaiveDateTime>,
+    pub created_to: Option<NaiveDateTime>,
+    pub sort: UserSort,
+    pub descending: bool,
+    pub page: Page,
+}
+
+/// Search terms and page for the user search
+#[derive(Clone, Debug)]
+pub struct UserSearchParams {
+    pub query: String,
+    /// Use the FULLTEXT index, a prefix LIKE on the whole query otherwise
+    pub fulltext: bool,
+    pub page: Page,
+}
+
+/// Shortest word in the FULLTEXT index (innodb_ft_min_token_size)
+const FULLTEXT_MIN_WORD: usize = 3;
+
+/// Get a filtered and sorted page of users, along with the total count of matching users
+/// With the async-db feature the handler uses `user_async::list` instead
+#[cfg_attr(feature = "async-db", allow(dead_code))]
+#[instrument(name = "users::list", skip(pool), err)]
+pub fn list(pool: &PoolType, tenant: TenantId, params: &UserListParams) -> Result<(UsersResponse, i64), ApiError> {
+    use crate::database::schema::users::dsl::*;
+
+    let conn = pool.read()?;
+    let filtered = || filtered(tenant, params);
+    let total = filtered().count().get_result::<i64>(&conn)?;
+    let query = match (params.sort, params.descending) {
+        (UserSort::FirstName, false) => filtered().order(first_name.asc()),
+        (UserSort::FirstName, true) => filtered().order(first_name.desc()),
+        (UserSort::LastName, false) => filtered().order(last_name.asc()),
+        (UserSort::LastName, true) => filtered().order(last_name.desc()),
+        (UserSort::Email, false) => filtered().order(email.asc()),
+        (UserSort::Email, true) => filtered().order(email.desc()),
+        (UserSort::CreatedAt, false) => filtered().order(created_at.asc()),
+        (UserSort::CreatedAt, true) => filtered().order(created_at.desc()),
+        (UserSort::UpdatedAt, false) => filtered().order(updated_at.asc()),
+        (UserSort::UpdatedAt, true) => filtered().order(updated_at.desc()),
+    };
+    // Sort by id last so that pages are stable when the sort column has duplicates
+    let page = query
+        .then_order_by(id.asc())
+        .limit(params.page.limit)
+        .offset(params.page.offset)
+        .load::<User>(&conn)?;
+
+    Ok((page.into(), total))
+}
+
+/// The users of a tenant, every query starts from here so that other tenants' users are out of reach
+/// Soft deleted users are included, filter them out where they don't belong.
+fn scoped(tenant: TenantId) -> users::BoxedQuery<'static, Mysql> {
+    use crate::database::schema::users::dsl::{tenant_id, users};
+
+    users.filter(tenant_id.eq(DbUuid::from(tenant))).into_boxed()
+}
+
+/// Build the filtered users query, shared by the page and count queries
+fn filtered(tenant: TenantId, params: &UserListParams) -> users::BoxedQuery<'static, Mysql> {
+    use crate::database::schema::users::dsl::*;
+
+    let mut query = scoped(tenant).filter(deleted_at.is_null());
+    if let Some(name) = &params.name {
+        let pattern = like_pattern(name);
+        query = query.filter(first_name.like(pattern.clone()).or(last_name.like(pattern)));
+    }
+    if let Some(user_email) = &params.email {
+        query = query.filter(email.like(like_pattern(user_email)));
+    }
+    if let Some(created_from) = params.created_from {
+        query = query.filter(created_at.ge(created_from));
+    }
+    if let Some(created_to) = params.created_to {
+        query = query.filter(created_at.le(created_to));
+    }
+    query
+}
+
+/// Escape LIKE wildcards in user input and match anywhere in the column
+pub fn like_pattern(value: &str) -> String {
+    format!("%{}%", escape_like(value))
+}
+
+/// Escape LIKE wildcards in user input and match the start of the column
+fn prefix_pattern(value: &str) -> String {
+    format!("{}%", escape_like(value))
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Split a search query into lowercase words, like the FULLTEXT parser does
+pub fn search_words(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Search users by partial name or email, best matches first, along with the total count of matches
+///
+/// Every word has to match the start of a word in the first name, last name or email (FULLTEXT in boolean mode).
+/// A prefix LIKE on the whole query is used instead when fulltext search is off, a word is shorter
+/// than the index keeps or the index finds nothing, eg. because a word is a stopword.
+#[instrument(name = "users::search", skip(pool), err)]
+pub fn search(pool: &PoolType, tenant: TenantId, params: &UserSearchParams) -> Result<(UsersResponse, i64), ApiError> {
+    let conn = pool.read()?;
+    let words = search_words(&params.query);
+    if params.fulltext && !words.is_empty() && words.iter().all(|word| word.chars().count() >= FULLTEXT_MIN_WORD) {
+        let (page, total) = fulltext_search(&conn, tenant, &words, params.page)?;
+        if total > 0 {
+            return Ok((page.into(), total));
+        }
+    }
+    let (page, total) = prefix_search(&conn, tenant, params.query.trim(), params.page)?;
+    Ok((page.into(), total))
+}
+
+fn fulltext_search(conn: &Conn, tenant: TenantId, words: &[String], page: Page) -> Result<(Vec<User>, i64), ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id};
+
+    // Only the words are passed on, so the query cannot contain boolean operators
+    let against = words.iter().map(|word| format!("+{}*", word)).collect::<Vec<_>>().join(" ");
+    let matches = || {
+        sql::<Bool>("MATCH (first_name, last_name, email) AGAINST (")
+            .bind::<Text, _>(against.clone())
+            .sql(" IN BOOLEAN MODE)")
+    };
+    let score = sql::<Double>("MATCH (first_name, last_name, email) AGAINST (")
+        .bind::<Text, _>(against.clone())
+        .sql(" IN BOOLEAN MODE)");
+    let total = scoped(tenant)
+        .filter(deleted_at.is_null())
+        .filter(matches())
+        .count()
+        .get_result::<i64>(conn)?;
+    let found = scoped(tenant)
+        .filter(deleted_at.is_null())
+        .filter(matches())
+        .order((score.desc(), id.asc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<User>(conn)?;
+    Ok((found, total))
+}
+
+fn prefix_search(conn: &Conn, tenant: TenantId, query: &str, page: Page) -> Result<(Vec<User>, i64), ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, email, first_name, id, last_name};
+
+    let pattern = prefix_pattern(query);
+    let matches = || {
+        first_name
+            .like(pattern.clone())
+            .or(last_name.like(pattern.clone()))
+            .or(email.like(pattern.clone()))
+    };
+    // Users matching in more columns rank higher
+    let score = sql::<BigInt>("(first_name LIKE ")
+        .bind::<Text, _>(pattern.clone())
+        .sql(") + (last_name LIKE ")
+        .bind::<Text, _>(pattern.clone())
+        .sql(") + (email LIKE ")
+        .bind::<Text, _>(pattern.clone())
+        .sql(")");
+    let total = scoped(tenant)
+        .filter(deleted_at.is_null())
+        .filter(matches())
+        .count()
+        .get_result::<i64>(conn)?;
+    let found = scoped(tenant)
+        .filter(deleted_at.is_null())
+        .filter(matches())
+        .order((score.desc(), id.asc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<User>(conn)?;
+    Ok((found, total))
 }
 
 /// Get all users
-pub fn get_all(pool: &PoolType) -> Result<UsersResponse, ApiError> {
-    use crate::database::schema::users::dsl::users;
+#[allow(dead_code)]
+#[instrument(name = "users::get_all", skip(pool), err)]
+pub fn get_all(pool: &PoolType, tenant: TenantId) -> Result<UsersResponse, ApiError> {
+    use crate::database::schema::users::dsl::deleted_at;
 
-    let conn = pool.get()?;
-    let all_users = users.load(&conn)?;
+    let conn = pool.read()?;
+    let all_users = scoped(tenant).filter(deleted_at.is_null()).load(&conn)?;
 
     Ok(all_users.into())
 }
 
 /// Find a user by the user's id or error out
-pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
-    use crate::database::schema::users::dsl::{id, users};
+#[instrument(name = "users::find", skip(pool), err)]
+pub fn find(pool: &PoolType, tenant: TenantId, user_id: Uuid) -> Result<UserResponse, ApiError> {
+    let conn = pool.read()?;
+    find_with(&conn, tenant, user_id)
+}
+
+/// Find a user on a given connection, eg. in a unit of work where replicas would not see its writes
+pub fn find_with(conn: &Conn, tenant: TenantId, user_id: Uuid) -> Result<UserResponse, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id};
 
     let not_found = format!("User {} not found", user_id);
-    let conn = pool.get()?;
-    let user = users
-        .filter(id.eq(user_id.to_string()))
-        .first::<User>(&conn)
+    let user = scoped(tenant)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(deleted_at.is_null())
+        .first::<User>(conn)
         .map_err(|_| ApiError::NotFound(not_found))?;
 
     Ok(user.into())
@@ -77,16 +435,19 @@ pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
 
 /// Find a user by the user's authentication information (email + password)
 /// Return an Unauthorized error if it doesn't match
+#[instrument(name = "users::find_by_auth", skip(pool, user_email, user_password), err)]
 pub fn find_by_auth(
     pool: &PoolType,
+    tenant: TenantId,
     user_email: &str,
     user_password: &str,
 ) -> Result<UserResponse, ApiError> {
-    use crate::database::schema::users::dsl::{email, users};
+    use crate::database::schema::users::dsl::{deleted_at, email_normalized};
 
     let conn = pool.get()?;
-    let user = users
-        .filter(email.eq(user_email.to_string()))
+    let user = scoped(tenant)
+        .filter(email_normalized.eq(normalize_email(user_email)))
+        .filter(deleted_at.is_null())
         .first::<User>(&conn)
         .map_err(|_| ApiError::Unauthorized("Invalid login".into()))?;
     let hashed = hash(user_password,&user.salt1);
@@ -99,45 +460,315 @@ pub fn find_by_auth(
 }
 
 /// Create a new user
-pub fn create(pool: &PoolType, new_user: &User) -> Result<UserResponse, ApiError> {
+#[instrument(name = "users::create", skip(conn, new_user), fields(user_id = %new_user.id), err)]
+pub fn create(conn: &Conn, new_user: &User) -> Result<UserResponse, ApiError> {
     use crate::database::schema::users::dsl::users;
 
-    let conn = pool.get()?;
-    diesel::insert_into(users).values(new_user).execute(&conn)?;
+    savepoint(conn, |conn| {
+        diesel::insert_into(users).values(UserRow::from(new_user)).execute(conn)?;
+        enqueue(conn, &[NewOutboxEvent::new(USER_CREATED, new_user.id, &UserEvent::from(new_user))?])
+    })?;
     Ok(new_user.clone().into())
 }
 
-/// Update a user
-pub fn update(pool: &PoolType, update_user: &UpdateUser) -> Result<UserResponse, ApiError> {
-    use crate::database::schema::users::dsl::{id, users};
+/// Create many users with a single statement, either all of them are created or none
+#[instrument(name = "users::create_all", skip(conn, new_users), fields(count = new_users.len()), err)]
+pub fn create_all(conn: &Conn, new_users: &[User]) -> Result<usize, ApiError> {
+    use crate::database::schema::users::dsl::users;
 
-    let conn = pool.get()?;
-    diesel::update(users)
-        .filter(id.eq(update_user.id.clone()))
-        .set(update_user)
-        .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_user.id)?)
+    let rows = new_users.iter().map(UserRow::from).collect::<Vec<_>>();
+    let events = new_users
+        .iter()
+        .map(|user| NewOutboxEvent::new(USER_CREATED, user.id, &UserEvent::from(user)))
+        .collect::<Result<Vec<_>, _>>()?;
+    savepoint(conn, |conn| {
+        let created = diesel::insert_into(users).values(&rows).execute(conn)?;
+        enqueue(conn, &events)?;
+        Ok(created)
+    })
+}
+
+/// Find which of the normalised emails are in use in a tenant, soft deleted users still hold theirs
+#[instrument(name = "users::emails_in_use", skip(conn, emails), err)]
+pub fn emails_in_use(conn: &Conn, tenant: TenantId, emails: &[String]) -> Result<Vec<String>, ApiError> {
+    use crate::database::schema::users::dsl::email_normalized;
+
+    let in_use = scoped(tenant)
+        .select(email_normalized)
+        .filter(email_normalized.eq_any(emails))
+        .load::<String>(conn)?;
+    Ok(in_use)
+}
+
+/// Get a batch of users ordered by id, starting after the given id
+/// Used to walk through every user without offsets that get slower with each page
+#[instrument(name = "users::batch_after", skip(pool), err)]
+pub fn batch_after(pool: &PoolType, tenant: TenantId, after_id: Option<Uuid>, limit: i64) -> Result<Vec<User>, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id};
+
+    let conn = pool.read()?;
+    let mut query = scoped(tenant).filter(deleted_at.is_null());
+    if let Some(after_id) = after_id {
+        query = query.filter(id.gt(DbUuid(after_id)));
+    }
+    let batch = query.order(id.asc()).limit(limit).load::<User>(&conn)?;
+    Ok(batch)
+}
+
+/// Update a user, only if its version is one of the expected ones
+#[instrument(name = "users::update", skip(conn, update_user), fields(user_id = %update_user.id), err)]
+pub fn update(
+    conn: &Conn,
+    tenant: TenantId,
+    update_user: &UpdateUser,
+    expected: &ExpectedVersion,
+) -> Result<UserResponse, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id, tenant_id, users, version};
+
+    let user_id = Uuid::from(update_user.id);
+    let mut query = diesel::update(users)
+        .filter(id.eq(update_user.id))
+        .filter(tenant_id.eq(DbUuid::from(tenant)))
+        .filter(deleted_at.is_null())
+        .set((update_user, version.eq(version + 1)))
+        .into_boxed();
+    if let Some(versions) = expected.versions() {
+        query = query.filter(version.eq_any(versions.to_vec()));
+    }
+    savepoint(conn, |conn| {
+        if query.execute(conn)? == 0 {
+            return Err(not_written(conn, tenant, user_id));
+        }
+        publish(conn, tenant, USER_UPDATED, user_id).map(UserResponse::from)
+    })
+}
+
+/// Partially update a user, leaving the columns that are not in the changeset untouched
+/// Only applies if the user's version is one of the expected ones
+#[instrument(name = "users::patch", skip(conn, patch_user), err)]
+pub fn patch(
+    conn: &Conn,
+    tenant: TenantId,
+    user_id: Uuid,
+    patch_user: &PatchUser,
+    expected: &ExpectedVersion,
+) -> Result<UserResponse, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id, tenant_id, users, version};
+
+    let mut query = diesel::update(users)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(tenant_id.eq(DbUuid::from(tenant)))
+        .filter(deleted_at.is_null())
+        .set((patch_user, version.eq(version + 1)))
+        .into_boxed();
+    if let Some(versions) = expected.versions() {
+        query = query.filter(version.eq_any(versions.to_vec()));
+    }
+    savepoint(conn, |conn| {
+        if query.execute(conn)? == 0 {
+            return Err(not_written(conn, tenant, user_id));
+        }
+        publish(conn, tenant, USER_UPDATED, user_id).map(UserResponse::from)
+    })
+}
+
+/// Change a user's password after checking the current one
+/// A new salt is generated along with the new hash
+#[instrument(name = "users::change_password", skip(conn, current_password, new_password), err)]
+pub fn change_password(
+    conn: &Conn,
+    tenant: TenantId,
+    user_id: Uuid,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), ApiError> {
+    use crate::database::schema::users::dsl::{
+        deleted_at, id, password, salt1, tenant_id, updated_at, updated_by, users, version,
+    };
+
+    let not_found = format!("User {} not found", user_id);
+    let user = scoped(tenant)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(deleted_at.is_null())
+        .first::<User>(conn)
+        .map_err(|_| ApiError::NotFound(not_found))?;
+    if !hash(current_password, &user.salt1).eq(&user.password) {
+        return Err(ApiError::ValidationError(vec!["current_password is incorrect".into()]));
+    }
+    if current_password == new_password {
+        return Err(ApiError::ValidationError(vec![
+            "new_password must differ from the current password".into(),
+        ]));
+    }
+    let email_name = user.email.split('@').next().unwrap_or("");
+    check_password_strength("new_password", new_password, &[email_name, &user.first_name, &user.last_name])?;
+
+    let new_salt = new_salt();
+    savepoint(conn, |conn| {
+        diesel::update(users)
+            .filter(id.eq(DbUuid(user_id)))
+            .filter(tenant_id.eq(DbUuid::from(tenant)))
+            .set((
+                password.eq(hash(new_password, &new_salt)),
+                salt1.eq(&new_salt),
+                updated_by.eq(DbUuid(user_id)),
+                updated_at.eq(Utc::now().naive_utc()),
+                version.eq(version + 1),
+            ))
+            .execute(conn)?;
+        publish(conn, tenant, USER_UPDATED, user_id)?;
+        Ok(())
+    })
+}
+
+/// Soft delete a user, the row stays until it is purged
+/// Only applies if the user's version is one of the expected ones
+#[instrument(name = "users::delete", skip(conn), err)]
+pub fn delete(
+    conn: &Conn,
+    tenant: TenantId,
+    user_id: Uuid,
+    actor_id: Uuid,
+    expected: &ExpectedVersion,
+) -> Result<(), ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, deleted_by, id, tenant_id, users, version};
+
+    let mut query = diesel::update(users)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(tenant_id.eq(DbUuid::from(tenant)))
+        .filter(deleted_at.is_null())
+        .set((
+            deleted_at.eq(Utc::now().naive_utc()),
+            deleted_by.eq(DbUuid(actor_id)),
+            version.eq(version + 1),
+        ))
+        .into_boxed();
+    if let Some(versions) = expected.versions() {
+        query = query.filter(version.eq_any(versions.to_vec()));
+    }
+    savepoint(conn, |conn| {
+        if query.execute(conn)? == 0 {
+            return Err(not_written(conn, tenant, user_id));
+        }
+        publish(conn, tenant, USER_DELETED, user_id)?;
+        Ok(())
+    })
 }
 
-/// Delete a user
-pub fn delete(pool: &PoolType, user_id: Uuid) -> Result<(), ApiError> {
-    use crate::database::schema::users::dsl::{id, users};
+/// Restore a soft deleted user
+#[instrument(name = "users::restore", skip(conn), err)]
+pub fn restore(conn: &Conn, tenant: TenantId, user_id: Uuid, actor_id: Uuid) -> Result<UserResponse, ApiError> {
+    use crate::database::schema::users::dsl::{
+        deleted_at, deleted_by, id, tenant_id, updated_at, updated_by, users, version,
+    };
+
+    savepoint(conn, |conn| {
+        let restored = diesel::update(users)
+            .filter(id.eq(DbUuid(user_id)))
+            .filter(tenant_id.eq(DbUuid::from(tenant)))
+            .filter(deleted_at.is_not_null())
+            .set((
+                deleted_at.eq(None::<NaiveDateTime>),
+                deleted_by.eq(None::<DbUuid>),
+                updated_at.eq(Utc::now().naive_utc()),
+                updated_by.eq(DbUuid(actor_id)),
+                version.eq(version + 1),
+            ))
+            .execute(conn)?;
+        if restored == 0 {
+            return Err(ApiError::NotFound(format!("Deleted user {} not found", user_id)));
+        }
+        publish(conn, tenant, USER_RESTORED, user_id).map(UserResponse::from)
+    })
+}
+
+/// Hard delete the users of a tenant that were soft deleted before the cutoff
+/// Returns the number of purged users
+#[instrument(name = "users::purge", skip(conn), err)]
+pub fn purge(conn: &Conn, tenant: TenantId, deleted_before: NaiveDateTime) -> Result<usize, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, tenant_id, users};
+
+    let purged = diesel::delete(users)
+        .filter(tenant_id.eq(DbUuid::from(tenant)))
+        .filter(deleted_at.lt(deleted_before))
+        .execute(conn)?;
+    Ok(purged)
+}
+
+/// Check whether a user exists and has the admin flag
+#[instrument(name = "users::is_admin", skip(pool), err)]
+pub fn is_admin(pool: &PoolType, tenant: TenantId, user_id: Uuid) -> Result<bool, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, id, is_admin};
 
     let conn = pool.get()?;
-    diesel::delete(users)
-        .filter(id.eq(user_id.to_string()))
-        .execute(&conn)?;
-    Ok(())
+    let admin = scoped(tenant)
+        .select(is_admin)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(deleted_at.is_null())
+        .first::<bool>(&conn)
+        .optional()?;
+    Ok(admin.unwrap_or(false))
+}
+
+/// Check whether a tenant has any admin, deleted admins don't count
+#[instrument(name = "users::admin_exists", skip(conn), err)]
+pub fn admin_exists(conn: &Conn, tenant: TenantId) -> Result<bool, ApiError> {
+    use crate::database::schema::users::dsl::{deleted_at, is_admin};
+
+    let admin = scoped(tenant)
+        .select(is_admin)
+        .filter(is_admin.eq(true))
+        .filter(deleted_at.is_null())
+        .first::<bool>(conn)
+        .optional()?;
+    Ok(admin.is_some())
+}
+
+/// Write an event with the user's state after a mutation, in the mutation's unit of work
+fn publish(conn: &Conn, tenant: TenantId, event_type: &str, user_id: Uuid) -> Result<User, ApiError> {
+    use crate::database::schema::users::dsl::id;
+
+    let user = scoped(tenant).filter(id.eq(DbUuid(user_id))).first::<User>(conn)?;
+    enqueue(conn, &[NewOutboxEvent::new(event_type, user_id, &UserEvent::from(&user))?])?;
+    Ok(user)
+}
+
+/// Explain a conditional write that matched no rows:
+/// either the user is gone or its version did not match If-Match
+fn not_written(conn: &Conn, tenant: TenantId, user_id: Uuid) -> ApiError {
+    use crate::database::schema::users::dsl::{deleted_at, id};
+
+    let exists = scoped(tenant)
+        .filter(id.eq(DbUuid(user_id)))
+        .filter(deleted_at.is_null())
+        .count()
+        .get_result::<i64>(conn);
+    match exists {
+        Ok(0) => ApiError::NotFound(format!("User {} not found", user_id)),
+        Ok(_) => ApiError::PreconditionFailed(format!("User {} has been modified in the meantime", user_id)),
+        Err(error) => error.into(),
+    }
+}
+
+/// Normalise an email for uniqueness and login: trimmed and case-folded.
+/// Provider specific rules like dots or "+tags" in Gmail addresses are not applied.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Generate a random per-user salt
+fn new_salt() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(32).collect::<String>()
 }
 
 impl From<NewUser> for User {
     fn from(user: NewUser) -> Self {
-        let salt1 = thread_rng().sample_iter(&Alphanumeric).take(32).collect::<String>();
+        let salt1 = new_salt();
         User {
             id: user.id,
             first_name: user.first_name,
             last_name: user.last_name,
-            email: user.email,
+            email: user.email.trim().to_string(),
             password: hash(&user.password,&salt1),
             salt1: salt1,
             salt2: "".to_string(),
@@ -145,6 +776,12 @@ impl From<NewUser> for User {
             created_at: Utc::now().naive_utc(),
             updated_by: user.updated_by,
             updated_at: Utc::now().naive_utc(),
+            is_admin: false,
+            deleted_at: None,
+            deleted_by: None,
+            version: 1,
+            email_normalized: normalize_email(&user.email),
+            tenant_id: user.tenant_id.0,
         }
     }
 }
@@ -152,26 +789,69 @@ impl From<NewUser> for User {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::tests::helpers::tests::get_pool;
+    use crate::models::tenant::tests::create_tenant;
+    use crate::tests::helpers::tests::{get_conn, get_pool};
 
     pub fn get_all_users() -> Result<UsersResponse, ApiError> {
         let pool = get_pool();
-        get_all(&pool)
+        get_all(&pool, TenantId::DEFAULT)
     }
 
     pub fn create_user() -> Result<UserResponse, ApiError> {
         let user_id = Uuid::new_v4();
         let new_user = NewUser {
-            id: user_id.to_string(),
+            id: user_id,
+            tenant_id: TenantId::DEFAULT,
             first_name: "Model".to_string(),
             last_name: "Test".to_string(),
-            email: "model-test@nothing.org".to_string(),
+            email: format!("model-test-{}@nothing.org", user_id),
             password: "123456".to_string(),
-            created_by: user_id.to_string(),
-            updated_by: user_id.to_string(),
+            created_by: user_id,
+            updated_by: user_id,
         };
         let user: User = new_user.into();
-        create(&get_pool(), &user)
+        create(&get_conn(), &user)
+    }
+
+    #[test]
+    fn it_escapes_like_patterns() {
+        assert_eq!(like_pattern("50%_off"), "%50\\%\\_off%");
+    }
+
+    #[test]
+    fn it_splits_search_words() {
+        assert_eq!(search_words(" Satoshi@Example.com +-"), vec!["satoshi", "example", "com"]);
+    }
+
+    #[test]
+    fn it_searches_users() {
+        let created = create_user().unwrap();
+        for fulltext in [true, false].iter() {
+            let params = UserSearchParams {
+                query: created.email.clone(),
+                fulltext: *fulltext,
+                page: Page::new(Some(100), None),
+            };
+            let (page, total) = search(&get_pool(), TenantId::DEFAULT, &params).unwrap();
+            assert!(total >= 1);
+            assert_eq!(page.0[0].id, created.id);
+        }
+    }
+
+    #[test]
+    fn it_lists_a_page_of_users() {
+        let params = UserListParams {
+            name: None,
+            email: None,
+            created_from: None,
+            created_to: None,
+            sort: UserSort::Email,
+            descending: false,
+            page: Page::new(Some(1), None),
+        };
+        let (page, total) = list(&get_pool(), TenantId::DEFAULT, &params).unwrap();
+        assert_eq!(page.0.len(), 1);
+        assert!(total >= 1);
     }
 
     #[test]
@@ -184,14 +864,14 @@ pub mod tests {
     fn test_find() {
         let users = get_all_users().unwrap();
         let user = &users.0[0];
-        let found_user = find(&get_pool(), user.id).unwrap();
+        let found_user = find(&get_pool(), TenantId::DEFAULT, user.id).unwrap();
         assert_eq!(user, &found_user);
     }
 
     #[test]
     fn it_doesnt_find_a_user() {
         let user_id = Uuid::new_v4();
-        let not_found_user = find(&get_pool(), user_id);
+        let not_found_user = find(&get_pool(), TenantId::DEFAULT, user_id);
         assert!(not_found_user.is_err());
     }
 
@@ -200,24 +880,111 @@ pub mod tests {
         let created = create_user();
         assert!(created.is_ok());
         let unwrapped = created.unwrap();
-        let found_user = find(&get_pool(), unwrapped.id.clone()).unwrap();
+        let found_user = find(&get_pool(), TenantId::DEFAULT, unwrapped.id).unwrap();
         assert_eq!(unwrapped, found_user);
     }
 
+    #[test]
+    fn it_refuses_a_duplicate_email() {
+        let created = create_user().unwrap();
+        let user_id = Uuid::new_v4();
+        let duplicate: User = NewUser {
+            id: user_id,
+            tenant_id: TenantId::DEFAULT,
+            first_name: "Model".to_string(),
+            last_name: "Test".to_string(),
+            email: format!("  {}  ", created.email.to_uppercase()),
+            password: "123456".to_string(),
+            created_by: user_id,
+            updated_by: user_id,
+        }
+        .into();
+        let response = create(&get_conn(), &duplicate);
+        let expected_error = ApiError::ValidationError(vec!["email already in use".to_string()]);
+        assert_eq!(response.unwrap_err(), expected_error);
+        assert!(find_by_auth(&get_pool(), TenantId::DEFAULT, &created.email.to_uppercase(), "123456").is_ok());
+    }
+
+    #[test]
+    fn it_keeps_tenants_apart() {
+        let conn = get_conn();
+        let tenant = create_tenant(&conn);
+        let existing = create_user().unwrap();
+        let user_id = Uuid::new_v4();
+        let other: User = NewUser {
+            id: user_id,
+            tenant_id: tenant,
+            first_name: "Tenant".to_string(),
+            last_name: "Test".to_string(),
+            email: existing.email.clone(),
+            password: "123456".to_string(),
+            created_by: user_id,
+            updated_by: user_id,
+        }
+        .into();
+        // The same email in another tenant is a different user
+        create(&conn, &other).unwrap();
+        assert_eq!(find(&get_pool(), tenant, user_id).unwrap().email, existing.email);
+        assert!(find(&get_pool(), TenantId::DEFAULT, user_id).is_err());
+        assert!(find(&get_pool(), tenant, existing.id).is_err());
+        assert_eq!(find_by_auth(&get_pool(), tenant, &existing.email, "123456").unwrap().id, user_id);
+        let response = delete(&conn, TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default());
+        assert!(response.is_err());
+        assert_eq!(get_all(&get_pool(), tenant).unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn it_normalizes_emails() {
+        assert_eq!(normalize_email(" Satoshi@Example.COM "), "satoshi@example.com");
+    }
+
+    #[test]
+    fn it_creates_users_all_or_nothing() {
+        let existing = create_user().unwrap();
+        let new_user = |email: String| -> User {
+            let user_id = Uuid::new_v4();
+            NewUser {
+                id: user_id,
+                tenant_id: TenantId::DEFAULT,
+                first_name: "Model".to_string(),
+                last_name: "Test".to_string(),
+                email,
+                password: "123456".to_string(),
+                created_by: user_id,
+                updated_by: user_id,
+            }
+            .into()
+        };
+        let fresh = new_user(format!("model-test-{}@nothing.org", Uuid::new_v4()));
+        let users = vec![fresh.clone(), new_user(existing.email.clone())];
+        assert!(create_all(&get_conn(), &users).is_err());
+        let in_use = emails_in_use(&get_conn(), TenantId::DEFAULT, &[fresh.email_normalized.clone(), existing.email.clone()]).unwrap();
+        assert_eq!(in_use, vec![existing.email]);
+        assert_eq!(create_all(&get_conn(), &[fresh]).unwrap(), 1);
+    }
+
+    #[test]
+    fn it_walks_users_in_batches() {
+        let first = batch_after(&get_pool(), TenantId::DEFAULT, None, 2).unwrap();
+        let next = batch_after(&get_pool(), TenantId::DEFAULT, Some(first[1].id), 2).unwrap();
+        assert!(next.iter().all(|user| user.id > first[1].id));
+    }
+
     #[test]
     fn it_updates_a_user() {
         let users = get_all_users().unwrap();
         let user = &users.0[1];
         let update_user = UpdateUser {
-            id: user.id.to_string(),
+            id: user.id.into(),
             first_name: "ModelUpdate".to_string(),
             last_name: "TestUpdate".to_string(),
-            email: "model-update-test@nothing.org".to_string(),
-            updated_by: user.id.to_string(),
+            email: format!("model-update-test-{}@nothing.org", user.id),
+            email_normalized: format!("model-update-test-{}@nothing.org", user.id),
+            updated_by: user.id.into(),
         };
-        let updated = update(&get_pool(), &update_user);
+        let updated = update(&get_conn(), TenantId::DEFAULT, &update_user, &ExpectedVersion::default());
         assert!(updated.is_ok());
-        let found_user = find(&get_pool(), user.id).unwrap();
+        let found_user = find(&get_pool(), TenantId::DEFAULT, user.id).unwrap();
         assert_eq!(updated.unwrap(), found_user);
     }
 
@@ -225,24 +992,135 @@ pub mod tests {
     fn it_fails_to_update_a_nonexistent_user() {
         let user_id = Uuid::new_v4();
         let update_user = UpdateUser {
-            id: user_id.to_string(),
+            id: user_id.into(),
             first_name: "ModelUpdateFailure".to_string(),
             last_name: "TestUpdateFailure".to_string(),
             email: "model-update-failure-test@nothing.org".to_string(),
-            updated_by: user_id.to_string(),
+            email_normalized: "model-update-failure-test@nothing.org".to_string(),
+            updated_by: user_id.into(),
         };
-        let updated = update(&get_pool(), &update_user);
+        let updated = update(&get_conn(), TenantId::DEFAULT, &update_user, &ExpectedVersion::default());
         assert!(updated.is_err());
     }
 
+    #[test]
+    fn it_patches_only_the_supplied_columns() {
+        let created = create_user().unwrap();
+        let patch_user = PatchUser {
+            first_name: Some("ModelPatch".to_string()),
+            last_name: None,
+            email: None,
+            email_normalized: None,
+            updated_by: created.id.into(),
+            updated_at: Utc::now().naive_utc(),
+        };
+        let patched = patch(&get_conn(), TenantId::DEFAULT, created.id, &patch_user, &ExpectedVersion::default()).unwrap();
+        assert_eq!(patched.first_name, "ModelPatch");
+        assert_eq!(patched.last_name, created.last_name);
+        assert_eq!(patched.email, created.email);
+    }
+
+    #[test]
+    fn it_refuses_a_stale_version() {
+        let created = create_user().unwrap();
+        assert_eq!(created.version, 1);
+        let patch_user = PatchUser {
+            first_name: Some("ModelPatch".to_string()),
+            last_name: None,
+            email: None,
+            email_normalized: None,
+            updated_by: created.id.into(),
+            updated_at: Utc::now().naive_utc(),
+        };
+        let stale = ExpectedVersion(Some(vec![1]));
+        let patched = patch(&get_conn(), TenantId::DEFAULT, created.id, &patch_user, &stale).unwrap();
+        assert_eq!(patched.version, 2);
+        let response = patch(&get_conn(), TenantId::DEFAULT, created.id, &patch_user, &stale);
+        let expected_error = ApiError::PreconditionFailed(format!("User {} has been modified in the meantime", created.id));
+        assert_eq!(response.unwrap_err(), expected_error);
+        let response = delete(&get_conn(), TenantId::DEFAULT, created.id, created.id, &stale);
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn it_changes_a_password() {
+        let created = create_user().unwrap();
+        let user_id = created.id;
+        let response = change_password(&get_conn(), TenantId::DEFAULT, user_id, "wrong", "Correct-Horse-Battery");
+        assert!(response.is_err());
+        change_password(&get_conn(), TenantId::DEFAULT, user_id, "123456", "Correct-Horse-Battery").unwrap();
+        let user = find_by_auth(&get_pool(), TenantId::DEFAULT, &created.email, "Correct-Horse-Battery");
+        assert!(user.is_ok());
+    }
+
     #[test]
     fn it_deletes_a_user() {
         let created = create_user();
         let user_id = created.unwrap().id;
-        let user = find(&get_pool(), user_id);
+        let user = find(&get_pool(), TenantId::DEFAULT, user_id);
         assert!(user.is_ok());
-        delete(&get_pool(), user_id).unwrap();
-        let user = find(&get_pool(), user_id);
+        delete(&get_conn(), TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default()).unwrap();
+        let user = find(&get_pool(), TenantId::DEFAULT, user_id);
         assert!(user.is_err());
     }
+
+    #[test]
+    fn it_restores_a_deleted_user() {
+        let user_id = create_user().unwrap().id;
+        delete(&get_conn(), TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default()).unwrap();
+        let restored = restore(&get_conn(), TenantId::DEFAULT, user_id, user_id).unwrap();
+        assert_eq!(restored.id, user_id);
+        assert!(restore(&get_conn(), TenantId::DEFAULT, user_id, user_id).is_err());
+    }
+
+    #[test]
+    fn it_writes_an_event_per_mutation() {
+        // Rolled back at the end, so that publishers in other tests never see these events
+        let conn = get_conn();
+        conn.begin_test_transaction().unwrap();
+        let user_id = Uuid::new_v4();
+        let user: User = NewUser {
+            id: user_id,
+            tenant_id: TenantId::DEFAULT,
+            first_name: "Outbox".into(),
+            last_name: "Test".into(),
+            email: format!("outbox-test-{}@nothing.org", user_id),
+            password: "123456".into(),
+            created_by: user_id,
+            updated_by: user_id,
+        }
+        .into();
+        create(&conn, &user).unwrap();
+        let patch_user = PatchUser {
+            first_name: Some("Patched".into()),
+            last_name: None,
+            email: None,
+            email_normalized: None,
+            updated_by: user_id.into(),
+            updated_at: Utc::now().naive_utc(),
+        };
+        patch(&conn, TenantId::DEFAULT, user_id, &patch_user, &ExpectedVersion::default()).unwrap();
+        delete(&conn, TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default()).unwrap();
+        restore(&conn, TenantId::DEFAULT, user_id, user_id).unwrap();
+
+        let events = crate::models::outbox::for_aggregate(&conn, user_id).unwrap();
+        let types = events.iter().map(|event| event.event_type.as_str()).collect::<Vec<_>>();
+        assert_eq!(types, vec![USER_CREATED, USER_UPDATED, USER_DELETED, USER_RESTORED]);
+        let updated: UserEvent = serde_json::from_str(&events[1].payload).unwrap();
+        assert_eq!((updated.first_name.as_str(), updated.version), ("Patched", 2));
+        assert!(!events[0].payload.contains(&user.password));
+        assert!(events[2].envelope()["data"]["deleted_at"].is_string());
+    }
+
+    #[test]
+    fn it_purges_only_users_deleted_before_the_cutoff() {
+        let user_id = create_user().unwrap().id;
+        delete(&get_conn(), TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default()).unwrap();
+        let an_hour_ago = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        purge(&get_conn(), TenantId::DEFAULT, an_hour_ago).unwrap();
+        assert!(restore(&get_conn(), TenantId::DEFAULT, user_id, user_id).is_ok());
+        delete(&get_conn(), TenantId::DEFAULT, user_id, user_id, &ExpectedVersion::default()).unwrap();
+        purge(&get_conn(), TenantId::DEFAULT, Utc::now().naive_utc() + chrono::Duration::seconds(1)).unwrap();
+        assert!(restore(&get_conn(), TenantId::DEFAULT, user_id, user_id).is_err());
+    }
 }
@@ -0,0 +1,167 @@
+//! Async versions of the user reads, with the async-db feature
+//!
+//! They run on sqlx instead of diesel, so a request does not wait for a blocking thread.
+//! Writes stay with the diesel models, they need units of work and the audit trail.
+
+use crate::database::connection::PoolType;
+use crate::handlers::user::{UserResponse, UsersResponse};
+use crate::models::tenant::TenantId;
+use crate::models::user::{like_pattern, User, UserListParams, UserSort};
+use crate::server_helpers::errors::ApiError;
+use chrono::NaiveDateTime;
+use sqlx::mysql::MySqlArguments;
+use sqlx::query::QueryAs;
+use sqlx::MySql;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// The users columns, in the order of the diesel schema
+const COLUMNS: &str = "id, first_name, last_name, email, password, salt1, salt2, created_by, created_at, \
+    updated_by, updated_at, is_admin, deleted_at, deleted_by, version, email_normalized, tenant_id";
+
+/// A value bound to a filter placeholder
+enum Bind {
+    Id(Uuid),
+    Text(String),
+    Time(NaiveDateTime),
+}
+
+/// Find a user of the tenant by the user's id or error out
+#[instrument(name = "users::find_async", skip(pool), err)]
+pub async fn find(pool: &PoolType, tenant: TenantId, user_id: Uuid) -> Result<UserResponse, ApiError> {
+    let mut conn = pool.read_async().await?;
+    let query = format!("SELECT {} FROM users WHERE id = ? AND tenant_id = ? AND deleted_at IS NULL", COLUMNS);
+    let user = sqlx::query_as::<_, User>(&query)
+        .bind(user_id)
+        .bind(tenant.0)
+        .fetch_optional(&mut conn)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", user_id)))?;
+    Ok(user.into())
+}
+
+/// Get a filtered and sorted page of the tenant's users, along with the total count of matching users
+#[instrument(name = "users::list_async", skip(pool), err)]
+pub async fn list(pool: &PoolType, tenant: TenantId, params: &UserListParams) -> Result<(UsersResponse, i64), ApiError> {
+    let (conditions, binds) = filters(tenant, params);
+    let mut conn = pool.read_async().await?;
+
+    let count = format!("SELECT COUNT(*) FROM users WHERE {}", conditions);
+    let mut total = sqlx::query_scalar::<_, i64>(&count);
+    for value in &binds {
+        total = match value {
+            Bind::Id(id) => total.bind(id),
+            Bind::Text(text) => total.bind(text),
+            Bind::Time(time) => total.bind(time),
+        };
+    }
+    let total = total.fetch_one(&mut conn).await?;
+
+    // Sort by id last so that pages are stable when the sort column has duplicates
+    let page = format!(
+        "SELECT {} FROM users WHERE {} ORDER BY {} {}, id ASC LIMIT ? OFFSET ?",
+        COLUMNS,
+        conditions,
+        sort_column(params.sort),
+        if params.descending { "DESC" } else { "ASC" }
+    );
+    let page = bind_all(sqlx::query_as::<_, User>(&page), &binds)
+        .bind(params.page.limit)
+        .bind(params.page.offset)
+        .fetch_all(&mut conn)
+        .await?;
+
+    Ok((page.into(), total))
+}
+
+/// The WHERE clause and its values, the same filters as the diesel list
+fn filters(tenant: TenantId, params: &UserListParams) -> (String, Vec<Bind>) {
+    let mut conditions = vec!["tenant_id = ?", "deleted_at IS NULL"];
+    let mut binds = vec![Bind::Id(tenant.0)];
+    if let Some(name) = &params.name {
+        conditions.push("(first_name LIKE ? OR last_name LIKE ?)");
+        binds.push(Bind::Text(like_pattern(name)));
+        binds.push(Bind::Text(like_pattern(name)));
+    }
+    if let Some(email) = &params.email {
+        conditions.push("email LIKE ?");
+        binds.push(Bind::Text(like_pattern(email)));
+    }
+    if let Some(created_from) = params.created_from {
+        conditions.push("created_at >= ?");
+        binds.push(Bind::Time(created_from));
+    }
+    if let Some(created_to) = params.created_to {
+        conditions.push("created_at <= ?");
+        binds.push(Bind::Time(created_to));
+    }
+    (conditions.join(" AND "), binds)
+}
+
+fn bind_all<'q>(
+    mut query: QueryAs<'q, MySql, User, MySqlArguments>,
+    binds: &'q [Bind],
+) -> QueryAs<'q, MySql, User, MySqlArguments> {
+    for value in binds {
+        query = match value {
+            Bind::Id(id) => query.bind(id),
+            Bind::Text(text) => query.bind(text),
+            Bind::Time(time) => query.bind(time),
+        };
+    }
+    query
+}
+
+/// Only whitelisted columns reach the ORDER BY
+fn sort_column(sort: UserSort) -> &'static str {
+    match sort {
+        UserSort::FirstName => "first_name",
+        UserSort::LastName => "last_name",
+        UserSort::Email => "email",
+        UserSort::CreatedAt => "created_at",
+        UserSort::UpdatedAt => "updated_at",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user::tests::create_user;
+    use crate::models::user::{find as find_blocking, list as list_blocking};
+    use crate::server_helpers::pagination::Page;
+    use crate::tests::helpers::tests::get_pool;
+
+    #[actix_rt::test]
+    async fn it_finds_what_the_blocking_model_finds() {
+        let created = create_user().unwrap();
+        let pool = get_pool();
+        let found = find(&pool, TenantId::DEFAULT, created.id).await.unwrap();
+        assert_eq!(found, find_blocking(&pool, TenantId::DEFAULT, created.id).unwrap());
+        assert!(find(&pool, TenantId::DEFAULT, Uuid::new_v4()).await.is_err());
+        assert!(find(&pool, TenantId(Uuid::new_v4()), created.id).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_lists_what_the_blocking_model_lists() {
+        let created = create_user().unwrap();
+        let params = UserListParams {
+            name: Some("Mod%".into()),
+            email: Some(created.email.clone()),
+            created_from: None,
+            created_to: None,
+            sort: UserSort::Email,
+            descending: true,
+            page: Page::new(Some(10), None),
+        };
+        let pool = get_pool();
+        let (page, total) = list(&pool, TenantId::DEFAULT, &params).await.unwrap();
+        let (blocking_page, blocking_total) = list_blocking(&pool, TenantId::DEFAULT, &params).unwrap();
+        assert_eq!((page.0, total), (blocking_page.0, blocking_total));
+        assert_eq!(total, 0);
+
+        let params = UserListParams { name: Some("Mod".into()), ..params };
+        let (page, total) = list(&pool, TenantId::DEFAULT, &params).await.unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(page.0[0].id, created.id);
+    }
+}
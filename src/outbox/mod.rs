@@ -0,0 +1,214 @@
+//! Publishes the transactional outbox, see `models::outbox`
+//!
+//! The publisher polls every OUTBOX_POLL_INTERVAL, claims up to OUTBOX_BATCH_SIZE due events and
+//! publishes each of them to every sink in OUTBOX_SINKS. An event is marked delivered once all sinks
+//! took it. Otherwise the attempt and its error are recorded and it is retried with an exponential backoff.
+//!
+//! Delivery is at least once: after a failing sink or a crash an event is published again,
+//! consumers drop duplicates by the event id. Retries can reorder the events of a user,
+//! their `data.version` tells which one is current.
+//! Delivered events are deleted after OUTBOX_RETENTION.
+
+use crate::config::Config;
+use crate::database::connection::PoolType;
+use crate::models::outbox::{claim, mark_delivered, mark_failed, remove_delivered};
+use crate::outbox::sinks::{configured_sinks, Sink};
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::telemetry::block;
+use actix::prelude::*;
+use chrono::{NaiveDateTime, Utc};
+use std::rc::Rc;
+use std::time::Duration;
+
+pub mod sinks;
+
+/// How long claimed events are left to their publisher before others may claim them
+const LEASE: Duration = Duration::from_secs(60);
+/// Wait before the first retry of an event, doubled for every further attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Polls the outbox and publishes due events to the sinks
+pub struct OutboxPublisher {
+    pool: PoolType,
+    sinks: Rc<Vec<Box<dyn Sink>>>,
+    poll_interval: Duration,
+    batch_size: i64,
+    retention: Duration,
+    /// Set while a batch is being published, so that slow sinks don't pile up batches
+    busy: bool,
+}
+
+impl OutboxPublisher {
+    pub fn new(pool: PoolType, config: &Config) -> Self {
+        OutboxPublisher {
+            pool,
+            sinks: Rc::new(configured_sinks(config)),
+            poll_interval: config.outbox_poll_interval,
+            batch_size: config.outbox_batch_size,
+            retention: config.outbox_retention,
+            busy: false,
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        if self.busy {
+            return;
+        }
+        self.busy = true;
+        let published = publish_due(self.pool.clone(), self.sinks.clone(), self.batch_size, self.retention);
+        ctx.spawn(published.into_actor(self).map(|result, publisher, _| {
+            publisher.busy = false;
+            if let Err(error) = result {
+                log::error!("Could not publish the outbox: {}", error);
+            }
+        }));
+    }
+}
+
+impl Actor for OutboxPublisher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if self.sinks.is_empty() {
+            log::info!("No outbox sinks configured, events are marked delivered without being published");
+        }
+        ctx.run_interval(self.poll_interval, |publisher, ctx| publisher.poll(ctx));
+    }
+}
+
+/// Publish one batch of due events, returns the number of delivered events
+pub async fn publish_due(
+    pool: PoolType,
+    sinks: Rc<Vec<Box<dyn Sink>>>,
+    batch_size: i64,
+    retention: Duration,
+) -> Result<usize, ApiError> {
+    let claim_pool = pool.clone();
+    let events = block(move || {
+        let conn = claim_pool.get()?;
+        claim(&conn, batch_size, chrono_duration(LEASE))
+    })
+    .await?;
+
+    let mut delivered = vec![];
+    let mut failed = vec![];
+    for event in &events {
+        let envelope = event.envelope();
+        let mut errors = vec![];
+        for sink in sinks.iter() {
+            if let Err(error) = sink.publish(&envelope).await {
+                errors.push(format!("{}: {}", sink.name(), error));
+            }
+        }
+        if errors.is_empty() {
+            delivered.push(event.id);
+        } else {
+            let error = errors.join("; ");
+            log::warn!("Could not publish outbox event {} (attempt {}): {}", event.id, event.attempts + 1, error);
+            failed.push((event.id, error, retry_at(event.attempts + 1)));
+        }
+    }
+
+    let count = delivered.len();
+    block(move || {
+        let conn = pool.get()?;
+        mark_delivered(&conn, &delivered)?;
+        for (event_id, error, retry_at) in &failed {
+            mark_failed(&conn, *event_id, error, *retry_at)?;
+        }
+        remove_delivered(&conn, Utc::now().naive_utc() - chrono_duration(retention))?;
+        Ok(())
+    })
+    .await?;
+    Ok(count)
+}
+
+/// When to retry an event after its nth failed attempt
+fn retry_at(attempts: i32) -> NaiveDateTime {
+    let backoff = RETRY_BACKOFF
+        .checked_mul(2u32.saturating_pow(attempts.max(1) as u32 - 1))
+        .map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF));
+    Utc::now().naive_utc() + chrono_duration(backoff)
+}
+
+fn chrono_duration(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::outbox::{enqueue, for_aggregate, NewOutboxEvent};
+    use crate::tests::helpers::tests::{get_conn, get_pool};
+    use futures::future::LocalBoxFuture;
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use uuid::Uuid;
+
+    /// Keeps what it is sent, or fails every publish
+    #[derive(Default)]
+    struct TestSink {
+        received: Rc<RefCell<Vec<Value>>>,
+        failing: bool,
+    }
+
+    impl Sink for TestSink {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn publish<'a>(&'a self, event: &'a Value) -> LocalBoxFuture<'a, Result<(), String>> {
+            let result = if self.failing {
+                Err("unavailable".into())
+            } else {
+                self.received.borrow_mut().push(event.clone());
+                Ok(())
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    fn enqueue_test_event() -> Uuid {
+        let aggregate = Uuid::new_v4();
+        let event = NewOutboxEvent::new("user.test", aggregate, &json!({ "version": 1 })).unwrap();
+        enqueue(&get_conn(), &[event]).unwrap();
+        aggregate
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let after = |attempts| (retry_at(attempts) - Utc::now().naive_utc()).num_seconds();
+        assert!((4..=5).contains(&after(1)));
+        assert!((19..=20).contains(&after(3)));
+        assert!((599..=600).contains(&after(40)));
+    }
+
+    // Publishers claim every due event, so both cases run in one test instead of racing each other
+    #[actix_rt::test]
+    async fn it_publishes_due_events_and_records_failures() {
+        let delivered = enqueue_test_event();
+        let sink = TestSink::default();
+        let received = sink.received.clone();
+        let sinks: Rc<Vec<Box<dyn Sink>>> = Rc::new(vec![Box::new(sink)]);
+        publish_due(get_pool(), sinks, 10_000, Duration::from_secs(3600)).await.unwrap();
+        let envelope = received
+            .borrow()
+            .iter()
+            .find(|event| event["aggregate_id"] == json!(delivered))
+            .cloned()
+            .unwrap();
+        assert_eq!(envelope["type"], "user.test");
+        assert_eq!(envelope["data"]["version"], 1);
+
+        let failed = enqueue_test_event();
+        let sink = TestSink { failing: true, ..TestSink::default() };
+        let sinks: Rc<Vec<Box<dyn Sink>>> = Rc::new(vec![Box::new(sink)]);
+        publish_due(get_pool(), sinks, 10_000, Duration::from_secs(3600)).await.unwrap();
+        let event = for_aggregate(&get_conn(), failed).unwrap().remove(0);
+        assert_eq!(event.attempts, 1);
+        assert_eq!(event.last_error.as_deref(), Some("test: unavailable"));
+        assert_eq!(event.delivered_at, None);
+        assert!(event.next_attempt_at > Utc::now().naive_utc());
+    }
+}
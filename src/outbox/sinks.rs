@@ -0,0 +1,182 @@
+//! Where outbox events are published to
+//!
+//! A sink gets the event envelope (see `OutboxEvent::envelope`) and reports whether it took it.
+//! Sinks must tolerate duplicates, an event is published again to every sink when one of them fails.
+
+use crate::config::Config;
+use actix::Addr;
+use actix_redis::{Command, RedisActor, RespValue};
+use actix_web::client::Client;
+use futures::future::LocalBoxFuture;
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long a webhook may take to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The sinks OUTBOX_SINKS can list
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Redis,
+    Webhook,
+    Log,
+}
+
+pub trait Sink {
+    /// Name for logs and the outbox's last_error
+    fn name(&self) -> &str;
+
+    /// Publish one event, Ok once the sink has it
+    fn publish<'a>(&'a self, event: &'a Value) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+/// Appends events to a Redis stream with XADD, the stream entry has the type and the JSON envelope
+pub struct RedisStreamSink {
+    redis: Addr<RedisActor>,
+    stream: String,
+}
+
+impl RedisStreamSink {
+    pub fn new(redis: Addr<RedisActor>, stream: &str) -> Self {
+        RedisStreamSink {
+            redis,
+            stream: stream.into(),
+        }
+    }
+}
+
+impl Sink for RedisStreamSink {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn publish<'a>(&'a self, event: &'a Value) -> LocalBoxFuture<'a, Result<(), String>> {
+        let event_type = event["type"].as_str().unwrap_or_default().to_string();
+        let command = resp_array!["XADD", &self.stream, "*", "type", event_type, "event", event.to_string()];
+        Box::pin(async move {
+            match self.redis.send(Command(command)).await {
+                Ok(Ok(RespValue::Error(error))) => Err(error),
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(error)) => Err(format!("{:?}", error)),
+                Err(error) => Err(error.to_string()),
+            }
+        })
+    }
+}
+
+/// POSTs events as JSON to a URL, any 2xx response counts as delivered
+pub struct WebhookSink {
+    client: Client,
+    url: String,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Self {
+        WebhookSink {
+            client: Client::builder().timeout(WEBHOOK_TIMEOUT).finish(),
+            url: url.into(),
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn publish<'a>(&'a self, event: &'a Value) -> LocalBoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.url)
+                .header("X-Outbox-Event-Id", event["id"].to_string())
+                .send_json(event)
+                .await
+                .map_err(|error| error.to_string())?;
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("responded with {}", response.status()))
+            }
+        })
+    }
+}
+
+/// Appends events to a file, one JSON envelope per line
+pub struct LogFileSink {
+    path: PathBuf,
+}
+
+impl LogFileSink {
+    pub fn new(path: &str) -> Self {
+        LogFileSink { path: path.into() }
+    }
+}
+
+impl Sink for LogFileSink {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn publish<'a>(&'a self, event: &'a Value) -> LocalBoxFuture<'a, Result<(), String>> {
+        let path = self.path.clone();
+        let line = format!("{}\n", event);
+        Box::pin(async move {
+            actix_web::web::block(move || {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()))
+            })
+            .await
+            .map_err(|error| error.to_string())
+        })
+    }
+}
+
+/// Build the sinks listed in OUTBOX_SINKS, a webhook sink per URL in OUTBOX_WEBHOOK_URLS
+pub fn configured_sinks(config: &Config) -> Vec<Box<dyn Sink>> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    for kind in &config.outbox_sinks {
+        match kind {
+            SinkKind::Redis => {
+                let redis = RedisActor::start(&config.redis_url);
+                sinks.push(Box::new(RedisStreamSink::new(redis, &config.outbox_redis_stream)));
+            }
+            SinkKind::Webhook => {
+                for url in &config.outbox_webhook_urls {
+                    sinks.push(Box::new(WebhookSink::new(url)));
+                }
+            }
+            SinkKind::Log => sinks.push(Box::new(LogFileSink::new(&config.outbox_log_file))),
+        }
+    }
+    sinks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn it_appends_events_to_a_log_file() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.log", uuid::Uuid::new_v4()));
+        let sink = LogFileSink::new(path.to_str().unwrap());
+        sink.publish(&json!({ "id": 1 })).await.unwrap();
+        sink.publish(&json!({ "id": 2 })).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"id\":1}\n{\"id\":2}\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_fails_on_unreachable_webhooks() {
+        let sink = WebhookSink::new("http://127.0.0.1:1/events");
+        assert!(sink.publish(&json!({ "id": 1 })).await.is_err());
+    }
+}
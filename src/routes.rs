@@ -2,11 +2,19 @@
 //! combined.
 
 use crate::handlers::{
-    auth::{login, logout},
+    audit::get_audit_log,
+    auth::{login, logout, update_password},
     health::get_health,
-    user::{create_user, delete_user, get_user, get_users, update_user},
+    registration::{create_invite, register},
+    tenant::Tenants,
+    user::{
+        create_user, delete_user, get_user, get_users, patch_user, purge_users, restore_user, search_users,
+        update_user,
+    },
+    user_bulk::{export_users, import_users, IMPORT_MAX_BYTES},
 };
 use crate::middleware::auth::Auth as AuthMiddleware;
+use crate::server_helpers::resource::resource_routes;
 use actix_files::Files;
 use actix_web::web;
 use actix_ratelimit::RedisStore;
@@ -28,13 +36,29 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                 // AUTH routes
                 .service(
                     web::scope("/auth")
-                        .route("/logout", web::post().to(logout)),
+                        .route("/logout", web::post().to(logout))
+                        .route("/password", web::post().to(update_password)),
                 )
+                // AUDIT routes
+                .service(web::scope("/audit").route("", web::get().to(get_audit_log)))
+                // TENANT routes
+                .service(web::scope("/tenant").configure(resource_routes::<Tenants>))
                 // USER routes
                 .service(
                     web::scope("/user")
+                        .route("/export", web::get().to(export_users))
+                        .service(
+                            web::resource("/import")
+                                .app_data(web::PayloadConfig::new(IMPORT_MAX_BYTES))
+                                .route(web::post().to(import_users)),
+                        )
+                        .route("/invite", web::post().to(create_invite))
+                        .route("/purge", web::post().to(purge_users))
+                        .route("/search", web::get().to(search_users))
+                        .route("/{id}/restore", web::post().to(restore_user))
                         .route("/{id}", web::get().to(get_user))
                         .route("/{id}", web::put().to(update_user))
+                        .route("/{id}", web::patch().to(patch_user))
                         .route("/{id}", web::delete().to(delete_user))
                         .route("", web::get().to(get_users))
                         .route("", web::post().to(create_user)),
@@ -44,6 +68,7 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
             web::scope("/api/ext/v1")
                 .wrap(get_ip_rate_limiter(&store))
                 .route("/login", web::post().to(login))
+                .configure(registration_routes)
         )
         // Serve secure static files from the static-private folder
         .service(
@@ -65,3 +90,10 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
             ),
         );
 }
+
+/// Self-registration is off unless REGISTRATION_ENABLED is set
+fn registration_routes(cfg: &mut web::ServiceConfig) {
+    if CONFIG.registration_enabled {
+        cfg.route("/register", web::post().to(register));
+    }
+}
@@ -4,21 +4,45 @@ use crate::auth::{get_session_service, get_identity_service};
 use crate::server_helpers::cache::add_cache;
 use crate::server_helpers::state::new_state;
 use crate::config::CONFIG;
-use crate::database::connection::add_pool;
+use crate::config::secrets::audit_secrets;
+use crate::config::logging::init_logger;
+use crate::config::telemetry::init_telemetry;
+use crate::database::connection::InferPool;
+use crate::database::migrations::migrate_on_startup;
+use crate::database::routing::RoutedPool;
+use crate::outbox::OutboxPublisher;
 use crate::routes::routes;
 use futures::future;
+use actix::Actor;
 use actix_cors::Cors;
 use actix_web::web;
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{App, HttpServer};
 use listenfd::ListenFd;
 use crate::config::tls;
 use crate::middleware::redis_identity::RedisSessionPolicy;
 use crate::middleware::redirect_https::RedirectHTTPS;
-use actix_web::http::header;
+use crate::middleware::access_log::AccessLog;
+use crate::middleware::request_id::{RequestIdentifier, REQUEST_ID_HEADER};
+use crate::server_helpers::extractors::TENANT_HEADER;
+use crate::middleware::telemetry::Telemetry;
+use actix_web::http::header::{self, HeaderName};
 
 pub async fn server() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    init_logger();
+    let _telemetry = init_telemetry()?;
+    // Only reached outside of production, where these would have stopped the startup
+    for problem in audit_secrets(&CONFIG) {
+        log::warn!("Insecure configuration: {}: {}", problem.key.to_uppercase(), problem.message);
+    }
+    // One pool shared by all workers, so DATABASE_POOL_MAX_SIZE is the limit for the whole process
+    let pool = match InferPool::init_pool_with_retry(CONFIG.clone()).await {
+        Ok(InferPool::Mysql(pool)) => pool,
+        Err(error) => return Err(to_io_error(format!("Could not connect to the database: {}", error))),
+    };
+    prepare_database(&pool)?;
+    OutboxPublisher::new(pool.clone(), &CONFIG).start();
+    let pool = web::Data::new(pool);
 
     // Create the application state
     // String is used here, but it can be anything
@@ -34,18 +58,28 @@ pub async fn server() -> std::io::Result<()> {
     let mut server = HttpServer::new(move || {
         App::new()
             .configure(add_cache)
-            .wrap(Cors::default().allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT]).supports_credentials())
-            .wrap(Logger::default())
+            .wrap(Cors::default()
+                .allowed_headers(vec![
+                    header::AUTHORIZATION,
+                    header::ACCEPT,
+                    HeaderName::from_static(REQUEST_ID_HEADER),
+                    HeaderName::from_static(TENANT_HEADER),
+                ])
+                .expose_headers(vec![HeaderName::from_static(REQUEST_ID_HEADER)])
+                .supports_credentials())
+            .wrap(RequestIdentifier)
+            .wrap(AccessLog::new(CONFIG.log_format))
             .wrap(get_identity_service(RedisSessionPolicy::new()))
             .wrap(get_session_service())
-            .configure(add_pool)
+            .wrap(Telemetry)
+            .app_data(pool.clone())
             .app_data(data.clone())
             .configure(routes)
     });
 
     let mut server_unsecure = HttpServer::new(move || {
         App::new()
-            .wrap(RedirectHTTPS::with_replacements(&[((":".to_string()+&CONFIG.port).to_owned(), (":".to_string()+&CONFIG.secure_port).to_owned())]))
+            .wrap(RedirectHTTPS::with_replacements(&[(format!(":{}", CONFIG.port), format!(":{}", CONFIG.secure_port))]))
             .route("/", web::get().to(|| web::HttpResponse::Ok()
                 .content_type("text/plain")
                 .body("Always HTTPS!")))
@@ -69,3 +103,16 @@ pub async fn server() -> std::io::Result<()> {
     future::try_join(s1_future, s2_future).await?;
     Ok(())
 }
+
+/// Make sure the database schema is current before serving requests
+fn prepare_database(pool: &RoutedPool) -> std::io::Result<()> {
+    let conn = pool.get().map_err(|error| to_io_error(error.to_string()))?;
+    for name in migrate_on_startup(&conn, CONFIG.auto_migrate).map_err(to_io_error)? {
+        log::info!("Applied migration {}", name);
+    }
+    Ok(())
+}
+
+fn to_io_error(message: String) -> std::io::Error {
+    std::io::Error::other(message)
+}
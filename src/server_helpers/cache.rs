@@ -1,44 +1,169 @@
 use crate::config::CONFIG;
-use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::errors::{ApiError, CacheErrorKind};
 use actix::prelude::*;
 use actix_redis::{Command, RedisActor, RespValue};
 use actix_web::web::{Data, ServiceConfig};
-use redis_async::resp::{FromResp};
+use futures::future::join_all;
+use redis_async::resp::FromResp;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::instrument;
 
 pub type Cache = Data<Addr<RedisActor>>;
 
-/// Retrieve an entry in redis
-#[allow(dead_code)]
-pub async fn get<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
+/// Retrieve an entry in redis, None when the key is missing
+#[instrument(name = "redis::get", skip(redis), err)]
+pub async fn get(redis: Cache, key: &str) -> Result<Option<String>, ApiError> {
     let command = resp_array!["GET", key];
     send(redis, command).await
 }
 
+/// Retrieve several entries in redis at once, in the order of the keys
+#[allow(dead_code)]
+#[instrument(name = "redis::mget", skip(redis), err)]
+pub async fn mget<'a>(redis: Cache, keys: &'a [&'a str]) -> Result<Vec<Option<String>>, ApiError> {
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut command = vec![RespValue::from("MGET")];
+    command.extend(keys.iter().map(|key| RespValue::from(*key)));
+    send(redis, RespValue::Array(command)).await
+}
+
+/// Retrieve an entry stored with `set_json`, None when the key is missing
+#[allow(dead_code)]
+#[instrument(name = "redis::get_json", skip(redis), err)]
+pub async fn get_json<T: DeserializeOwned>(redis: Cache, key: &str) -> Result<Option<T>, ApiError> {
+    match get(redis, key).await? {
+        Some(value) => serde_json::from_str(&value).map(Some).map_err(serialization_error),
+        None => Ok(None),
+    }
+}
+
 /// Insert or update an entry in redis
 #[allow(dead_code)]
-pub async fn set<'a>(redis: Cache, key: &'a str, value: &'a str) -> Result<String, ApiError> {
+#[instrument(name = "redis::set", skip(redis, value), err)]
+pub async fn set<'a>(redis: Cache, key: &'a str, value: &'a str) -> Result<(), ApiError> {
     let command = resp_array!["SET", key, value];
     send(redis, command).await
 }
 
-/// Delete an entry in redis
+/// Insert or update an entry in redis that expires after the TTL
+#[instrument(name = "redis::set_ex", skip(redis, value), err)]
+pub async fn set_ex<'a>(redis: Cache, key: &'a str, value: &'a str, ttl: Duration) -> Result<(), ApiError> {
+    send(redis, set_ex_command(key, value, ttl)).await
+}
+
+/// Insert or update an entry in redis as JSON
+#[allow(dead_code)]
+#[instrument(name = "redis::set_json", skip(redis, value), err)]
+pub async fn set_json<'a, T: Serialize>(redis: Cache, key: &'a str, value: &'a T) -> Result<(), ApiError> {
+    let value = serde_json::to_string(value).map_err(serialization_error)?;
+    set(redis, key, &value).await
+}
+
+/// Insert or update an entry in redis as JSON that expires after the TTL
+#[allow(dead_code)]
+#[instrument(name = "redis::set_json_ex", skip(redis, value), err)]
+pub async fn set_json_ex<'a, T: Serialize>(
+    redis: Cache,
+    key: &'a str,
+    value: &'a T,
+    ttl: Duration,
+) -> Result<(), ApiError> {
+    let value = serde_json::to_string(value).map_err(serialization_error)?;
+    set_ex(redis, key, &value, ttl).await
+}
+
+/// Check whether an entry exists in redis
 #[allow(dead_code)]
-pub async fn delete<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
+#[instrument(name = "redis::exists", skip(redis), err)]
+pub async fn exists(redis: Cache, key: &str) -> Result<bool, ApiError> {
+    let command = resp_array!["EXISTS", key];
+    send::<i64>(redis, command).await.map(|count| count > 0)
+}
+
+/// Delete an entry in redis, returns whether there was one
+#[instrument(name = "redis::delete", skip(redis), err)]
+pub async fn delete(redis: Cache, key: &str) -> Result<bool, ApiError> {
     let command = resp_array!["DEL", key];
+    send::<i64>(redis, command).await.map(|count| count > 0)
+}
+
+/// Increment a counter in redis, a missing key starts from 0
+#[instrument(name = "redis::incr", skip(redis), err)]
+pub async fn incr(redis: Cache, key: &str) -> Result<i64, ApiError> {
+    let command = resp_array!["INCR", key];
     send(redis, command).await
 }
 
-/// Send a command to the redis actor
-async fn send<'a>(redis: Cache, command: RespValue) -> Result<String, ApiError> {
-    let error_message = format!("Could not send {:?} command to Redis", command);
-    let error = ApiError::CacheError(error_message.into());
-    let response = redis.send(Command(command)).await.map_err(|_| error)?;
-    match response {
-        Ok(message) => Ok::<String, _>(FromResp::from_resp(message).unwrap_or("".into())),
-        Err(message) => Err(ApiError::CacheError(format!("{:?}", message))),
+/// Send a command to the redis actor, for commands without a helper
+/// Error replies keep their kind, replies that cannot be converted to T are Type errors.
+pub async fn send<T: FromResp>(redis: Cache, command: RespValue) -> Result<T, ApiError> {
+    let response = redis.send(Command(command)).await??;
+    Ok(T::from_resp(response)?)
+}
+
+/// Commands sent to redis together, without waiting for the reply of each
+///
+/// The replies come back in the order of the commands. A pipeline is not a transaction,
+/// commands of other clients can run in between.
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    commands: Vec<RespValue>,
+}
+
+#[allow(dead_code)]
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Add any command, eg. `resp_array!["EXPIRE", key, "60"]`
+    pub fn command(mut self, command: RespValue) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn set(self, key: &str, value: &str) -> Self {
+        self.command(resp_array!["SET", key, value])
+    }
+
+    pub fn set_ex(self, key: &str, value: &str, ttl: Duration) -> Self {
+        self.command(set_ex_command(key, value, ttl))
+    }
+
+    pub fn delete(self, key: &str) -> Self {
+        self.command(resp_array!["DEL", key])
+    }
+
+    pub fn incr(self, key: &str) -> Self {
+        self.command(resp_array!["INCR", key])
+    }
+
+    /// Send the commands, returns their replies or the first error
+    #[instrument(name = "redis::pipeline", skip(self, redis), fields(count = self.commands.len()), err)]
+    pub async fn execute(self, redis: Cache) -> Result<Vec<RespValue>, ApiError> {
+        // The actor writes the commands in the order they were sent, without waiting for replies
+        let replies = join_all(self.commands.into_iter().map(|command| redis.send(Command(command)))).await;
+        replies
+            .into_iter()
+            .map(|reply| Ok(RespValue::from_resp(reply??)?))
+            .collect()
     }
 }
 
+/// SET with a TTL in milliseconds, at least one so that the entry still expires
+fn set_ex_command(key: &str, value: &str, ttl: Duration) -> RespValue {
+    let millis = ttl.as_millis().max(1).to_string();
+    resp_array!["SET", key, value, "PX", millis]
+}
+
+fn serialization_error(error: serde_json::Error) -> ApiError {
+    ApiError::CacheError(CacheErrorKind::Serialization, error.to_string())
+}
+
 /// Add the redis actor to actix data if the URL is set
 pub fn add_cache(cfg: &mut ServiceConfig) {
     if !&CONFIG.redis_url.is_empty() {
@@ -57,22 +182,90 @@ mod tests {
         Data::new(cache)
     }
 
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Entry {
+        name: String,
+        count: i64,
+    }
+
     #[actix_rt::test]
     async fn it_creates_new_application_cache_and_sets_and_reads_it() {
         let cache = get_cache();
         set(cache.clone(), "testing", "123").await.unwrap();
         let value = get(cache, "testing").await.unwrap();
-        assert_eq!(value, "123");
+        assert_eq!(value, Some("123".into()));
     }
 
     #[actix_rt::test]
     async fn it_removes_an_entry_in_application_cache() {
         let cache = get_cache();
-        set(cache.clone(), "testing", "123").await.unwrap();
-        let value = get(cache.clone(), "testing").await.unwrap();
-        assert_eq!(value, "123");
-        delete(cache.clone(), "testing").await.unwrap();
-        let value = get(cache, "testing").await.unwrap();
-        assert_eq!(value, "");
+        set(cache.clone(), "testing_removed", "123").await.unwrap();
+        let value = get(cache.clone(), "testing_removed").await.unwrap();
+        assert_eq!(value, Some("123".into()));
+        assert!(delete(cache.clone(), "testing_removed").await.unwrap());
+        let value = get(cache.clone(), "testing_removed").await.unwrap();
+        assert_eq!(value, None);
+        assert!(!delete(cache, "testing_removed").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn it_tells_a_missing_key_from_an_empty_value() {
+        let cache = get_cache();
+        set(cache.clone(), "testing_empty", "").await.unwrap();
+        assert_eq!(get(cache.clone(), "testing_empty").await.unwrap(), Some("".into()));
+        assert!(exists(cache.clone(), "testing_empty").await.unwrap());
+        delete(cache.clone(), "testing_empty").await.unwrap();
+        assert!(!exists(cache, "testing_empty").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn it_increments_a_counter() {
+        let cache = get_cache();
+        delete(cache.clone(), "testing_counter").await.unwrap();
+        assert_eq!(incr(cache.clone(), "testing_counter").await.unwrap(), 1);
+        assert_eq!(incr(cache.clone(), "testing_counter").await.unwrap(), 2);
+        delete(cache, "testing_counter").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_stores_json_with_a_ttl() {
+        let cache = get_cache();
+        let entry = Entry { name: "Satoshi".into(), count: 21 };
+        set_json_ex(cache.clone(), "testing_json", &entry, Duration::from_millis(200)).await.unwrap();
+        assert_eq!(get_json::<Entry>(cache.clone(), "testing_json").await.unwrap(), Some(entry));
+        actix_rt::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(get_json::<Entry>(cache, "testing_json").await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_the_error_kind() {
+        let cache = get_cache();
+        set(cache.clone(), "testing_kind", "not json").await.unwrap();
+        let response = get_json::<Entry>(cache.clone(), "testing_kind").await;
+        assert!(matches!(response, Err(ApiError::CacheError(CacheErrorKind::Serialization, _))));
+        let response = incr(cache.clone(), "testing_kind").await;
+        assert!(matches!(response, Err(ApiError::CacheError(CacheErrorKind::Response, _))));
+        let response = send::<i64>(cache.clone(), resp_array!["GET", "testing_kind"]).await;
+        assert!(matches!(response, Err(ApiError::CacheError(CacheErrorKind::Type, _))));
+        delete(cache, "testing_kind").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_several_entries_and_pipelines_commands() {
+        let cache = get_cache();
+        let replies = Pipeline::new()
+            .set("testing_pipeline_a", "1")
+            .set_ex("testing_pipeline_b", "2", Duration::from_secs(60))
+            .delete("testing_pipeline_c")
+            .incr("testing_pipeline_a")
+            .execute(cache.clone())
+            .await
+            .unwrap();
+        assert_eq!(replies[3], RespValue::Integer(2));
+        let keys = ["testing_pipeline_a", "testing_pipeline_b", "testing_pipeline_c"];
+        let values = mget(cache.clone(), &keys).await.unwrap();
+        assert_eq!(values, vec![Some("2".into()), Some("2".into()), None]);
+        assert!(mget(cache.clone(), &[]).await.unwrap().is_empty());
+        Pipeline::new().delete(keys[0]).delete(keys[1]).execute(cache).await.unwrap();
     }
 }
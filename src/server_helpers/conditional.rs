@@ -0,0 +1,122 @@
+//! Conditional requests (RFC 7232) based on the version column of an entity
+//!
+//! GET responses carry a strong ETag of the version and answer If-None-Match with 304.
+//! Writes take the versions from If-Match and only apply to a matching row, see `ExpectedVersion`.
+
+use actix_web::{
+    http::{
+        header::{EntityTag, ETag, Header, IfNoneMatch, IF_NONE_MATCH},
+        Method,
+    },
+    web::{HttpRequest, HttpResponse},
+    Error,
+    Responder,
+};
+use futures::future::{ok, Ready};
+use serde::Serialize;
+
+/// The strong ETag for a version of an entity
+pub fn etag(version: i32) -> EntityTag {
+    EntityTag::strong(version.to_string())
+}
+
+/// Versions the client accepts from If-Match, see the extractor
+/// None when the header is missing or "*", any version will do then.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpectedVersion(pub Option<Vec<i32>>);
+
+impl ExpectedVersion {
+    /// Read the versions from a list of entity tags.
+    /// Weak tags never match for writes and tags that are not versions cannot match either.
+    pub fn from_tags(tags: &[EntityTag]) -> Self {
+        let versions = tags
+            .iter()
+            .filter(|tag| !tag.weak)
+            .filter_map(|tag| tag.tag().parse::<i32>().ok())
+            .collect();
+        ExpectedVersion(Some(versions))
+    }
+
+    pub fn versions(&self) -> Option<&[i32]> {
+        self.0.as_deref()
+    }
+
+    pub fn matches(&self, version: i32) -> bool {
+        self.versions().is_none_or(|versions| versions.contains(&version))
+    }
+}
+
+/// A JSON response tagged with the version of the entity
+/// GET and HEAD requests whose If-None-Match matches get an empty 304 instead.
+#[derive(Debug, PartialEq)]
+pub struct Tagged<T> {
+    pub data: T,
+    pub etag: EntityTag,
+}
+
+impl<T> Tagged<T> {
+    pub fn new(data: T, version: i32) -> Self {
+        Tagged {
+            data,
+            etag: etag(version),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    /// Whether the client already has this version
+    fn is_not_modified(&self, req: &HttpRequest) -> bool {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return false;
+        }
+        if !req.headers().contains_key(IF_NONE_MATCH) {
+            return false;
+        }
+        match IfNoneMatch::parse(req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl<T: Serialize> Responder for Tagged<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        if self.is_not_modified(req) {
+            return ok(HttpResponse::NotModified().set(ETag(self.etag)).finish());
+        }
+        ok(HttpResponse::Ok().set(ETag(self.etag)).json(&self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[test]
+    fn it_reads_expected_versions() {
+        let tags = vec![EntityTag::strong("3".into()), EntityTag::weak("4".into()), EntityTag::strong("x".into())];
+        let expected = ExpectedVersion::from_tags(&tags);
+        assert_eq!(expected, ExpectedVersion(Some(vec![3])));
+        assert!(expected.matches(3));
+        assert!(!expected.matches(4));
+        assert!(ExpectedVersion::default().matches(4));
+    }
+
+    #[actix_rt::test]
+    async fn it_responds_not_modified() {
+        let req = test::TestRequest::default().header("if-none-match", "W/\"2\", \"3\"").to_http_request();
+        let response = Tagged::new("user", 3).respond_to(&req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get("etag").unwrap(), "\"3\"");
+
+        let response = Tagged::new("user", 4).respond_to(&req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
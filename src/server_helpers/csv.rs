@@ -0,0 +1,98 @@
+//! Minimal CSV (RFC 4180) reading and writing for imports and exports
+//!
+//! Fields are separated by commas and may be quoted with double quotes,
+//! quotes inside a quoted field are doubled. Quoted fields can span lines.
+
+/// Parse CSV text into records of fields, blank lines are skipped
+pub fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, '"') => return Err(format!("line {}: unexpected quote in an unquoted field", line)),
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                end_record(&mut records, &mut record, &mut field);
+                line += 1;
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("line {}: unterminated quoted field", line));
+    }
+    end_record(&mut records, &mut record, &mut field);
+    Ok(records)
+}
+
+fn end_record(records: &mut Vec<Vec<String>>, record: &mut Vec<String>, field: &mut String) {
+    record.push(std::mem::take(field));
+    let record = std::mem::take(record);
+    if record.len() > 1 || !record[0].is_empty() {
+        records.push(record);
+    }
+}
+
+/// Write a record as a CSV line, quoting the fields that need it
+pub fn csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_quoted_fields() {
+        let records = parse_csv("first_name,last_name\r\n\"Nakamoto, \"\"Satoshi\"\"\",\"two\nlines\"\n\nHal,Finney").unwrap();
+        assert_eq!(records, vec![
+            vec!["first_name", "last_name"],
+            vec!["Nakamoto, \"Satoshi\"", "two\nlines"],
+            vec!["Hal", "Finney"],
+        ]);
+    }
+
+    #[test]
+    fn it_rejects_broken_quotes() {
+        assert_eq!(parse_csv("a,\"b\n"), Err("line 2: unterminated quoted field".into()));
+        assert!(parse_csv("a,b\"c").is_err());
+    }
+
+    #[test]
+    fn it_writes_lines() {
+        assert_eq!(csv_line(&["Satoshi", "Nakamoto, \"S\""]), "Satoshi,\"Nakamoto, \"\"S\"\"\"\r\n");
+        let line = csv_line(&["a,b", "c"]);
+        assert_eq!(parse_csv(&line).unwrap(), vec![vec!["a,b", "c"]]);
+    }
+}
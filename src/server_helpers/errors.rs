@@ -1,3 +1,5 @@
+use actix::MailboxError;
+use actix_redis::{Error as RedisError, RespError};
 use actix_web::{
     error::{BlockingError, ResponseError},
     http::StatusCode,
@@ -15,18 +17,34 @@ use uuid::Error as ParseError;
 pub enum ApiError {
     BadRequest(String),
     BlockingError(String),
-    CacheError(String),
+    #[display(fmt = "{}: {}", _0, _1)]
+    CacheError(CacheErrorKind, String),
     CannotDecodeJwtToken(String),
     CannotEncodeJwtToken(String),
+    Forbidden(String),
     InternalServerError(String),
     NotFound(String),
     ParseError(String),
     PoolError(String),
+    PreconditionFailed(String),
     #[display(fmt = "")]
     ValidationError(Vec<String>),
     Unauthorized(String),
 }
 
+/// What went wrong with a cache command, so that callers can tell a refused command from an outage
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum CacheErrorKind {
+    /// Redis cannot be reached: not connected, disconnected or the actor is gone
+    Connection,
+    /// Redis refused the command with an error reply, eg. WRONGTYPE
+    Response,
+    /// The reply does not have the type the caller asked for
+    Type,
+    /// A value cannot be encoded to or decoded from JSON
+    Serialization,
+}
+
 /// User-friendly error messages
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ErrorResponse {
@@ -40,9 +58,15 @@ impl ResponseError for ApiError {
             ApiError::BadRequest(error) => {
                 HttpResponse::BadRequest().json::<ErrorResponse>(error.into())
             }
+            ApiError::Forbidden(error) => {
+                HttpResponse::Forbidden().json::<ErrorResponse>(error.into())
+            }
             ApiError::NotFound(message) => {
                 HttpResponse::NotFound().json::<ErrorResponse>(message.into())
             }
+            ApiError::PreconditionFailed(error) => {
+                HttpResponse::PreconditionFailed().json::<ErrorResponse>(error.into())
+            }
             ApiError::ValidationError(errors) => {
                 HttpResponse::UnprocessableEntity().json::<ErrorResponse>(errors.to_vec().into())
             }
@@ -70,6 +94,12 @@ impl From<Vec<String>> for ErrorResponse {
     }
 }
 
+/// Unique indexes and the validation error reported when a write violates them
+const UNIQUE_INDEXES: [(&str, &str); 2] = [
+    ("users_tenant_email_normalized", "email already in use"),
+    ("tenants_slug", "slug already in use"),
+];
+
 /// Convert DBErrors to ApiErrors
 impl From<DBError> for ApiError {
     fn from(error: DBError) -> ApiError {
@@ -78,8 +108,13 @@ impl From<DBError> for ApiError {
         match error {
             DBError::DatabaseError(kind, info) => {
                 if let DatabaseErrorKind::UniqueViolation = kind {
-                    let message = info.details().unwrap_or_else(|| info.message()).to_string();
-                    return ApiError::BadRequest(message);
+                    // MySQL only names the violated index in the message,
+                    // which is not passed on as it also contains the duplicate value
+                    let message = info.message();
+                    return match UNIQUE_INDEXES.iter().find(|(index, _)| message.contains(index)) {
+                        Some((_, error)) => ApiError::ValidationError(vec![error.to_string()]),
+                        None => ApiError::BadRequest("Duplicate value".into()),
+                    };
                 }
                 ApiError::InternalServerError("Unknown database error".into())
             }
@@ -88,6 +123,50 @@ impl From<DBError> for ApiError {
     }
 }
 
+/// Convert sqlx errors to ApiErrors, like the diesel ones
+/// Other errors are logged, the response does not tell clients about the database.
+#[cfg(feature = "async-db")]
+impl From<sqlx::Error> for ApiError {
+    fn from(error: sqlx::Error) -> ApiError {
+        match error {
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => ApiError::PoolError(error.to_string()),
+            _ => {
+                log::error!("Async database error: {}", error);
+                ApiError::InternalServerError("Unknown database error".into())
+            }
+        }
+    }
+}
+
+/// Convert Redis client errors to ApiErrors, keeping the kind
+impl From<RespError> for ApiError {
+    fn from(error: RespError) -> ApiError {
+        let kind = match &error {
+            RespError::Remote(_) => CacheErrorKind::Response,
+            RespError::RESP(_, _) => CacheErrorKind::Type,
+            _ => CacheErrorKind::Connection,
+        };
+        ApiError::CacheError(kind, error.to_string())
+    }
+}
+
+/// Convert errors of the Redis actor to ApiErrors
+impl From<RedisError> for ApiError {
+    fn from(error: RedisError) -> ApiError {
+        match error {
+            RedisError::Redis(error) => error.into(),
+            _ => ApiError::CacheError(CacheErrorKind::Connection, error.to_string()),
+        }
+    }
+}
+
+/// Convert failed sends to the Redis actor to ApiErrors
+impl From<MailboxError> for ApiError {
+    fn from(error: MailboxError) -> ApiError {
+        ApiError::CacheError(CacheErrorKind::Connection, error.to_string())
+    }
+}
+
 /// Convert PoolErrors to ApiErrors
 impl From<PoolError> for ApiError {
     fn from(error: PoolError) -> ApiError {
@@ -1,31 +1,407 @@
-use crate::auth::{decode_jwt, PrivateClaim};
-use crate::models::user::AuthUser;
-use actix_identity::RequestIdentity;
+use crate::auth::{identity_tenant_id, identity_user_id, SESSION_TENANT_KEY};
+use crate::config::CONFIG;
+use crate::database::connection::PoolType;
+use crate::database::routing::ReadPool;
+use crate::handlers::registration::RegistrationContext;
+use crate::handlers::user_bulk::DataFormat;
+use crate::middleware::request_id::RequestId;
+use crate::models::audit::AuditContext;
+use crate::models::tenant::{find_by_slug, is_valid_slug, OperatorUser, TenantId};
+use crate::models::user::{is_admin, AdminUser, AuthUser};
+use crate::server_helpers::cache::Cache;
+use crate::server_helpers::conditional::ExpectedVersion;
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::telemetry::block;
+use actix_identity::{Identity, RequestIdentity};
+use actix_session::{Session, UserSession};
 use actix_web::{
     dev::Payload,
-    web::{HttpRequest, HttpResponse},
+    http::header::{Header, IfMatch, IF_MATCH},
+    web::{Data, HttpRequest},
     Error,
     FromRequest,
+    HttpMessage,
 };
-use futures::future::{ok, err, Ready};
+use futures::future::{ok, err, LocalBoxFuture, Ready};
+use std::net::IpAddr;
+use uuid::Uuid;
 
 /// Extractor for pulling the identity out of a request.
 ///
 /// Simply add "user: AuthUser" to a handler to invoke this.
+/// Works with session ids as well as JWTs, responds with 401 when not logged in.
 impl FromRequest for AuthUser {
     type Error = Error;
     type Config = ();
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let identity = RequestIdentity::get_identity(req);
-        if let Some(identity) = identity {
-            let private_claim: PrivateClaim = decode_jwt(&identity).unwrap();
-            return ok(AuthUser {
-                id: private_claim.user_id.to_string(),
-                email: private_claim.email,
-            });
+        match RequestIdentity::get_identity(req).and_then(|identity| identity_user_id(&identity)) {
+            Some(id) => ok(AuthUser { id }),
+            None => err(ApiError::Unauthorized("Not logged in".into()).into()),
         }
-        err(HttpResponse::Unauthorized().into())
+    }
+}
+
+/// Header naming the tenant of a request by its slug
+pub const TENANT_HEADER: &str = "x-tenant";
+
+/// Extractor for a logged in user with the admin flag.
+///
+/// Simply add "admin: AdminUser" to a handler to restrict it to admins.
+/// Responds with 401 when not logged in and 403 for other users.
+/// Admins are admins of their own tenant only.
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Config = ();
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let admin = require_admin(req);
+        Box::pin(async move {
+            let (id, tenant_id) = admin.await?;
+            Ok(AdminUser { id, tenant_id })
+        })
+    }
+}
+
+/// Extractor for an admin of the default tenant.
+///
+/// Simply add "operator: OperatorUser" to a handler to restrict it to the people running the deployment.
+/// Responds with 401 when not logged in and 403 for everybody else.
+impl FromRequest for OperatorUser {
+    type Error = Error;
+    type Config = ();
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let admin = require_admin(req);
+        Box::pin(async move {
+            match admin.await? {
+                (id, TenantId::DEFAULT) => Ok(OperatorUser { id }),
+                _ => Err(ApiError::Forbidden("Operator permission required".into()).into()),
+            }
+        })
+    }
+}
+
+/// Extractor for the tenant a request acts in.
+///
+/// Simply add "tenant: TenantId" to a handler and pass it to `models::user`.
+/// Logged in users act in their own tenant. Anonymous requests name one with the X-Tenant header
+/// or a subdomain of TENANT_DOMAIN, or fall back to TENANT_DEFAULT.
+/// Responds with 404 for unknown tenants and 403 when a logged in user names another tenant.
+impl FromRequest for TenantId {
+    type Error = Error;
+    type Config = ();
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let tenant = resolve_tenant(req);
+        Box::pin(async move { Ok(tenant.await?) })
+    }
+}
+
+/// Resolve the tenant of a request, see the TenantId extractor
+fn resolve_tenant(req: &HttpRequest) -> LocalBoxFuture<'static, Result<TenantId, ApiError>> {
+    let claimed = claimed_tenant(req);
+    let requested = requested_tenant_slug(req, &CONFIG.tenant_domain);
+    let pool = req.app_data::<Data<PoolType>>().cloned();
+    Box::pin(async move {
+        let slug = match (claimed, requested) {
+            (Some(tenant), None) => return Ok(tenant),
+            (_, Some(slug)) => slug,
+            (None, None) if !CONFIG.tenant_default.is_empty() => CONFIG.tenant_default.clone(),
+            (None, None) => return Err(ApiError::BadRequest("No tenant given".into())),
+        };
+        let pool = pool.ok_or_else(|| ApiError::InternalServerError("Database pool is not configured".into()))?;
+        let tenant = block(move || {
+            let conn = pool.read()?;
+            find_by_slug(&conn, &slug)?.ok_or_else(|| ApiError::NotFound(format!("Tenant {} not found", slug)))
+        })
+        .await?;
+        match claimed {
+            Some(claimed) if claimed.0 != tenant.id => Err(ApiError::Forbidden("Logged in to another tenant".into())),
+            _ => Ok(TenantId(tenant.id)),
+        }
+    })
+}
+
+/// The tenant of the logged in user
+/// Sessions keep it next to the user id, sessions from before tenants belong to the default one.
+fn claimed_tenant(req: &HttpRequest) -> Option<TenantId> {
+    let identity = RequestIdentity::get_identity(req)?;
+    let session_tenant = req.get_session().get::<TenantId>(SESSION_TENANT_KEY).unwrap_or(None);
+    session_tenant.or_else(|| identity_tenant_id(&identity))
+}
+
+/// The slug a request names with the tenant header or a subdomain of `domain`
+fn requested_tenant_slug(req: &HttpRequest, domain: &str) -> Option<String> {
+    let header = req
+        .headers()
+        .get(TENANT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_lowercase())
+        .filter(|slug| !slug.is_empty());
+    if header.is_some() || domain.is_empty() {
+        return header;
+    }
+    let connection_info = req.connection_info();
+    let host = connection_info.host().split(':').next().unwrap_or_default().to_lowercase();
+    host.strip_suffix(domain)
+        .and_then(|subdomain| subdomain.strip_suffix('.'))
+        .filter(|subdomain| is_valid_slug(subdomain))
+        .map(String::from)
+}
+
+/// IP address of the client, X-Forwarded-For is only read when the peer is a trusted proxy
+fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let forwarded_for = req.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok());
+    forwarded_client_ip(peer, forwarded_for, &CONFIG.trusted_proxies)
+}
+
+/// Walk X-Forwarded-For back from the peer while the hops are trusted proxies
+/// Addresses before the first untrusted hop can be made up by the client.
+fn forwarded_client_ip(peer: Option<IpAddr>, forwarded_for: Option<&str>, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let mut client = peer?;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Check that the logged in user is an admin in their tenant, returns their id and tenant
+fn require_admin(req: &HttpRequest) -> LocalBoxFuture<'static, Result<(Uuid, TenantId), ApiError>> {
+    let user_id = RequestIdentity::get_identity(req).and_then(|identity| identity_user_id(&identity));
+    let tenant = resolve_tenant(req);
+    let pool = req.app_data::<Data<PoolType>>().cloned();
+    Box::pin(async move {
+        let user_id = user_id.ok_or_else(|| ApiError::Unauthorized("Not logged in".into()))?;
+        let tenant = tenant.await?;
+        let pool = pool.ok_or_else(|| ApiError::InternalServerError("Database pool is not configured".into()))?;
+        if block(move || is_admin(&pool, tenant, user_id)).await? {
+            Ok((user_id, tenant))
+        } else {
+            Err(ApiError::Forbidden("Admin permission required".into()))
+        }
+    })
+}
+
+/// Extractor for the actor, IP and request id recorded with audit entries.
+///
+/// Simply add "audit: AuditContext" to a handler to invoke this.
+/// The actor is empty for anonymous requests.
+impl FromRequest for AuditContext {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let actor_id = RequestIdentity::get_identity(req).and_then(|identity| identity_user_id(&identity));
+        let ip = client_ip(req).map(|ip| ip.to_string());
+        let request_id = req.extensions().get::<RequestId>().map(|request_id| request_id.0.clone());
+        ok(AuditContext {
+            actor_id,
+            ip,
+            request_id,
+        })
+    }
+}
+
+/// Extractor for the pool of a request that only reads.
+///
+/// Simply add "pool: ReadPool" to a handler instead of "pool: Data<PoolType>".
+/// Reads go to the replicas, unless the logged in user wrote within READ_YOUR_WRITES_WINDOW.
+impl FromRequest for ReadPool {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let pool = match req.app_data::<Data<PoolType>>() {
+            Some(pool) => pool,
+            None => return err(ApiError::InternalServerError("Database pool is not configured".into()).into()),
+        };
+        let reader = RequestIdentity::get_identity(req).and_then(|identity| identity_user_id(&identity));
+        match reader {
+            Some(user_id) => ok(ReadPool(pool.for_reader(user_id))),
+            None => ok(ReadPool(pool.get_ref().clone())),
+        }
+    }
+}
+
+/// Extractor for the versions a write is conditional on.
+///
+/// Simply add "expected: ExpectedVersion" to a handler to honour If-Match.
+/// Responds with 400 when the header is not a list of entity tags.
+impl FromRequest for ExpectedVersion {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if !req.headers().contains_key(IF_MATCH) {
+            return ok(ExpectedVersion::default());
+        }
+        match IfMatch::parse(req) {
+            Ok(IfMatch::Any) => ok(ExpectedVersion::default()),
+            Ok(IfMatch::Items(tags)) => ok(ExpectedVersion::from_tags(&tags)),
+            Err(_) => err(ApiError::BadRequest("If-Match must be a list of entity tags".into()).into()),
+        }
+    }
+}
+
+/// Extractor for the format of an import body.
+///
+/// Simply add "format: DataFormat" to a handler to read it from the Content-Type.
+/// Responds with 400 for other content types.
+impl FromRequest for DataFormat {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        match DataFormat::from_content_type(req.content_type()) {
+            Some(format) => ok(format),
+            None => err(ApiError::BadRequest("Content-Type must be text/csv or application/x-ndjson".into()).into()),
+        }
+    }
+}
+
+/// Extractor for the identity, session, Redis and tenant of a registration.
+///
+/// Simply add "context: RegistrationContext" to a handler to invoke this.
+/// Fails like the extractors it is made of.
+impl FromRequest for RegistrationContext {
+    type Error = Error;
+    type Config = ();
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let parts = <(Identity, Session, Cache, TenantId)>::from_request(req, payload);
+        Box::pin(async move {
+            let (identity, session, redis, tenant) = parts.await?;
+            Ok(RegistrationContext {
+                identity,
+                session,
+                redis,
+                tenant,
+            })
+        })
+    }
+}
+
+/// Extractor for the id assigned by the RequestIdentifier middleware.
+///
+/// Simply add "request_id: RequestId" to a handler to invoke this.
+/// A fresh id is generated if the middleware is not mounted.
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let request_id = req.extensions().get::<RequestId>().cloned();
+        ok(request_id.unwrap_or_else(RequestId::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tenant::find;
+    use crate::models::tenant::tests::create_tenant;
+    use crate::tests::helpers::tests::{get_conn, get_data_pool};
+    use actix_web::test;
+
+    #[actix_rt::test]
+    async fn it_refuses_an_anonymous_auth_user() {
+        let (request, mut payload) = test::TestRequest::default().to_http_parts();
+        let response = AuthUser::from_request(&request, &mut payload).await;
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn it_gets_the_requested_tenant_slug() {
+        let request = test::TestRequest::default().header("x-tenant", " Acme ").to_http_request();
+        assert_eq!(requested_tenant_slug(&request, ""), Some("acme".into()));
+
+        let request = test::TestRequest::default().header("host", "acme.example.com:8080").to_http_request();
+        assert_eq!(requested_tenant_slug(&request, "example.com"), Some("acme".into()));
+        assert_eq!(requested_tenant_slug(&request, ""), None);
+
+        let request = test::TestRequest::default().header("host", "acme.notexample.com").to_http_request();
+        assert_eq!(requested_tenant_slug(&request, "example.com"), None);
+
+        let request = test::TestRequest::default().header("host", "example.com").to_http_request();
+        assert_eq!(requested_tenant_slug(&request, "example.com"), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_resolves_the_tenant_of_anonymous_requests() {
+        let conn = get_conn();
+        let tenant = create_tenant(&conn);
+        let slug = find(&conn, tenant.0).unwrap().unwrap().slug;
+
+        let (request, mut payload) = test::TestRequest::default()
+            .app_data(get_data_pool())
+            .header("x-tenant", slug.as_str())
+            .to_http_parts();
+        assert_eq!(TenantId::from_request(&request, &mut payload).await.unwrap(), tenant);
+
+        let (request, mut payload) = test::TestRequest::default().app_data(get_data_pool()).to_http_parts();
+        assert_eq!(TenantId::from_request(&request, &mut payload).await.unwrap(), TenantId::DEFAULT);
+
+        let (request, mut payload) = test::TestRequest::default()
+            .app_data(get_data_pool())
+            .header("x-tenant", "nothing-here")
+            .to_http_parts();
+        assert!(TenantId::from_request(&request, &mut payload).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_the_expected_version() {
+        let (request, mut payload) = test::TestRequest::default().header("if-match", "\"7\"").to_http_parts();
+        let expected = ExpectedVersion::from_request(&request, &mut payload).await.unwrap();
+        assert_eq!(expected, ExpectedVersion(Some(vec![7])));
+
+        let (request, mut payload) = test::TestRequest::default().header("if-match", "*").to_http_parts();
+        let expected = ExpectedVersion::from_request(&request, &mut payload).await.unwrap();
+        assert_eq!(expected, ExpectedVersion(None));
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_the_audit_context() {
+        let (request, mut payload) = test::TestRequest::default()
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .header("x-forwarded-for", "10.0.0.1")
+            .to_http_parts();
+        request.extensions_mut().insert(RequestId("test-request".into()));
+        let context = AuditContext::from_request(&request, &mut payload).await.unwrap();
+        assert_eq!(context.actor_id, None);
+        // No proxy is trusted by default, the header of the peer is ignored
+        assert_eq!(context.ip, Some("203.0.113.7".into()));
+        assert_eq!(context.request_id, Some("test-request".into()));
+    }
+
+    #[test]
+    fn it_only_trusts_forwarded_addresses_from_trusted_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let forwarded_for = Some("6.6.6.6, 1.2.3.4");
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(forwarded_client_ip(Some(ip("203.0.113.7")), Some("10.0.0.1"), &trusted), Some(ip("203.0.113.7")));
+        assert_eq!(forwarded_client_ip(Some(ip("1.2.3.4")), Some("6.6.6.6"), &trusted), Some(ip("1.2.3.4")));
+        assert_eq!(forwarded_client_ip(Some(ip("10.0.0.1")), forwarded_for, &[]), Some(ip("10.0.0.1")));
+        assert_eq!(forwarded_client_ip(Some(ip("10.0.0.1")), forwarded_for, &trusted), Some(ip("1.2.3.4")));
+        assert_eq!(forwarded_client_ip(Some(ip("10.0.0.1")), Some("6.6.6.6, 1.2.3.4, 10.0.0.2"), &trusted), Some(ip("1.2.3.4")));
+        assert_eq!(forwarded_client_ip(Some(ip("10.0.0.1")), None, &trusted), Some(ip("10.0.0.1")));
+        assert_eq!(forwarded_client_ip(None, forwarded_for, &trusted), None);
     }
 }
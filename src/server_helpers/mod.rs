@@ -1,5 +1,10 @@
 pub mod state;
 pub mod extractors;
 pub mod cache;
+pub mod conditional;
+pub mod csv;
 pub mod errors;
-pub mod response;
\ No newline at end of file
+pub mod pagination;
+pub mod resource;
+pub mod response;
+pub mod telemetry;
@@ -0,0 +1,98 @@
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+pub const DEFAULT_LIMIT: i64 = 25;
+pub const MAX_LIMIT: i64 = 100;
+
+/// A window into a list, limit is capped at MAX_LIMIT
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Page {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl Page {
+    pub fn new(limit: Option<i64>, offset: Option<i64>) -> Self {
+        Page {
+            limit: limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+            offset: offset.unwrap_or(0).max(0),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PageLinks {
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+/// Response envelope for paginated lists
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Paginated<T> {
+    pub data: Vec<T>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub links: PageLinks,
+}
+
+impl<T> Paginated<T> {
+    /// Wrap a page of data, next/prev links keep the other query parameters of the request
+    pub fn new(data: Vec<T>, total: i64, page: Page, req: &HttpRequest) -> Self {
+        let next = if page.offset + page.limit < total {
+            Some(page_link(req, page.limit, page.offset + page.limit))
+        } else {
+            None
+        };
+        let prev = if page.offset > 0 {
+            Some(page_link(req, page.limit, (page.offset - page.limit).max(0)))
+        } else {
+            None
+        };
+        Paginated {
+            data,
+            total,
+            limit: page.limit,
+            offset: page.offset,
+            links: PageLinks { next, prev },
+        }
+    }
+}
+
+/// Build a link to the same resource with a different limit and offset
+fn page_link(req: &HttpRequest, limit: i64, offset: i64) -> String {
+    let mut query: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    query.retain(|(key, _)| key != "limit" && key != "offset");
+    query.push(("limit".into(), limit.to_string()));
+    query.push(("offset".into(), offset.to_string()));
+    let query = serde_urlencoded::to_string(query).unwrap_or_default();
+    format!("{}?{}", req.path(), query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    #[test]
+    fn it_caps_the_limit() {
+        assert_eq!(Page::new(Some(10_000), Some(-5)), Page { limit: MAX_LIMIT, offset: 0 });
+        assert_eq!(Page::new(None, None), Page { limit: DEFAULT_LIMIT, offset: 0 });
+    }
+
+    #[test]
+    fn it_builds_page_links() {
+        let req = test::TestRequest::with_uri("/api/v1/user?sort=email&limit=10&offset=10")
+            .to_http_request();
+        let paginated = Paginated::new(vec![1, 2], 25, Page::new(Some(10), Some(10)), &req);
+        assert_eq!(
+            paginated.links.next,
+            Some("/api/v1/user?sort=email&limit=10&offset=20".to_string())
+        );
+        assert_eq!(
+            paginated.links.prev,
+            Some("/api/v1/user?sort=email&limit=10&offset=0".to_string())
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Generic CRUD handlers for simple resources
+//!
+//! A resource implements `Resource` with its queries, the handlers here take care of
+//! pagination, validation, the audit trail and error responses. Mount them with
+//! `web::scope("/tenant").configure(resource_routes::<Tenants>)`.
+//!
+//! Resources with more involved rules, like users with versions and soft deletes,
+//! keep their own handlers.
+
+use crate::database::connection::{Conn, PoolType};
+use crate::database::routing::ReadPool;
+use crate::models::audit::{record, AuditContext, Changes};
+use crate::models::user::AuthUser;
+use crate::server_helpers::errors::ApiError;
+use crate::server_helpers::pagination::{Page, Paginated};
+use crate::server_helpers::response::{respond_json, respond_ok};
+use crate::server_helpers::telemetry::block;
+use crate::validate::validate;
+use actix_web::web::{self, Data, HttpRequest, HttpResponse, Json, Path, Query};
+use actix_web::FromRequest;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+/// A resource with UUID ids, served by the generic handlers
+pub trait Resource: 'static {
+    /// Singular name, used for audit actions ("tenant.create") and error messages
+    const NAME: &'static str;
+
+    /// A row as it is loaded
+    type Model: Serialize + Send + 'static;
+    /// Body of POST requests
+    type Create: DeserializeOwned + Validate + Send + 'static;
+    /// Body of PUT requests
+    type Update: DeserializeOwned + Validate + Send + 'static;
+    /// What the handlers respond with
+    type Response: Serialize + From<Self::Model> + 'static;
+    /// Who may read, eg. `AuthUser` or `OperatorUser`
+    type Reader: FromRequest + 'static;
+    /// Who may write, eg. `AuthUser` or `AdminUser`
+    type Writer: FromRequest + Into<AuthUser> + 'static;
+
+    /// Get a page of rows, along with the total count
+    fn list(conn: &Conn, page: Page) -> Result<(Vec<Self::Model>, i64), ApiError>;
+
+    /// Find a row by its id
+    fn find(conn: &Conn, id: Uuid) -> Result<Option<Self::Model>, ApiError>;
+
+    /// Insert a row with the given id
+    fn create(conn: &Conn, id: Uuid, params: Self::Create, actor_id: Uuid) -> Result<(), ApiError>;
+
+    /// Update an existing row
+    fn update(conn: &Conn, id: Uuid, params: Self::Update, actor_id: Uuid) -> Result<(), ApiError>;
+
+    /// Delete an existing row
+    fn delete(conn: &Conn, id: Uuid) -> Result<(), ApiError>;
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+pub struct ListQuery {
+    #[validate(range(min = 1, message = "limit must be a positive number"))]
+    pub limit: Option<i64>,
+
+    #[validate(range(min = 0, message = "offset must not be negative"))]
+    pub offset: Option<i64>,
+}
+
+/// Mount the routes of a resource: GET and POST on "", GET, PUT and DELETE on "/{id}"
+pub fn resource_routes<R: Resource>(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::get().to(list::<R>))
+        .route("", web::post().to(create::<R>))
+        .route("/{id}", web::get().to(get::<R>))
+        .route("/{id}", web::put().to(update::<R>))
+        .route("/{id}", web::delete().to(delete::<R>));
+}
+
+/// Get a page of a resource
+pub async fn list<R: Resource>(
+    pool: ReadPool,
+    query: Query<ListQuery>,
+    req: HttpRequest,
+    _reader: R::Reader,
+) -> Result<Json<Paginated<R::Response>>, ApiError> {
+    let query = Json(query.into_inner());
+    validate(&query)?;
+
+    let page = Page::new(query.limit, query.offset);
+    let (rows, total) = block(move || {
+        let conn = pool.read()?;
+        R::list(&conn, page)
+    })
+    .await?;
+    let rows = rows.into_iter().map(R::Response::from).collect();
+    respond_json(Paginated::new(rows, total, page, &req))
+}
+
+/// Get one row of a resource
+pub async fn get<R: Resource>(id: Path<Uuid>, pool: ReadPool, _reader: R::Reader) -> Result<Json<R::Response>, ApiError> {
+    let id = id.into_inner();
+    let row = block(move || {
+        let conn = pool.read()?;
+        find_or_not_found::<R>(&conn, id)
+    })
+    .await?;
+    respond_json(row.into())
+}
+
+/// Create a row of a resource
+pub async fn create<R: Resource>(
+    pool: Data<PoolType>,
+    params: Json<R::Create>,
+    writer: R::Writer,
+    audit: AuditContext,
+) -> Result<Json<R::Response>, ApiError> {
+    validate(&params)?;
+
+    let id = Uuid::new_v4();
+    let actor_id = writer.into().id;
+    let row = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            R::create(conn, id, params.into_inner(), actor_id)?;
+            let created = find_or_not_found::<R>(conn, id)?;
+            let action = format!("{}.create", R::NAME);
+            record(conn, &audit.entry(&action, R::NAME, Some(id), Changes::created(&created)))?;
+            Ok(created)
+        })
+    })
+    .await?;
+    respond_json(row.into())
+}
+
+/// Update a row of a resource
+pub async fn update<R: Resource>(
+    id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<R::Update>,
+    writer: R::Writer,
+    audit: AuditContext,
+) -> Result<Json<R::Response>, ApiError> {
+    validate(&params)?;
+
+    let id = id.into_inner();
+    let actor_id = writer.into().id;
+    let row = block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let before = find_or_not_found::<R>(conn, id)?;
+            R::update(conn, id, params.into_inner(), actor_id)?;
+            let updated = find_or_not_found::<R>(conn, id)?;
+            let action = format!("{}.update", R::NAME);
+            record(conn, &audit.entry(&action, R::NAME, Some(id), Changes::updated(&before, &updated)))?;
+            Ok(updated)
+        })
+    })
+    .await?;
+    respond_json(row.into())
+}
+
+/// Delete a row of a resource
+pub async fn delete<R: Resource>(
+    id: Path<Uuid>,
+    pool: Data<PoolType>,
+    _writer: R::Writer,
+    audit: AuditContext,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    block(move || {
+        audit.unit_of_work(&pool, |conn| {
+            let before = find_or_not_found::<R>(conn, id)?;
+            R::delete(conn, id)?;
+            let action = format!("{}.delete", R::NAME);
+            record(conn, &audit.entry(&action, R::NAME, Some(id), Changes::deleted(&before)))
+        })
+    })
+    .await?;
+    respond_ok()
+}
+
+fn find_or_not_found<R: Resource>(conn: &Conn, id: Uuid) -> Result<R::Model, ApiError> {
+    R::find(conn, id)?.ok_or_else(|| ApiError::NotFound(format!("{} {} not found", R::NAME, id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::users;
+    use crate::database::types::DbUuid;
+    use crate::handlers::user::tests::get_auth_user;
+    use crate::handlers::user::{CreateUserRequest, UpdateUserRequest, UserResponse};
+    use crate::models::tenant::TenantId;
+    use crate::models::user::{normalize_email, NewUser, User};
+    use crate::tests::helpers::tests::{get_data_pool, get_read_pool};
+    use actix_web::test;
+    use diesel::prelude::*;
+
+    /// Users through the generic handlers, with hard deletes
+    struct Users;
+
+    impl Resource for Users {
+        const NAME: &'static str = "user";
+
+        type Model = User;
+        type Create = CreateUserRequest;
+        type Update = UpdateUserRequest;
+        type Response = UserResponse;
+        type Reader = AuthUser;
+        type Writer = AuthUser;
+
+        fn list(conn: &Conn, page: Page) -> Result<(Vec<User>, i64), ApiError> {
+            let total = users::table.count().get_result(conn)?;
+            let rows = users::table
+                .order(users::id.asc())
+                .limit(page.limit)
+                .offset(page.offset)
+                .load(conn)?;
+            Ok((rows, total))
+        }
+
+        fn find(conn: &Conn, id: Uuid) -> Result<Option<User>, ApiError> {
+            Ok(users::table.find(DbUuid(id)).first(conn).optional()?)
+        }
+
+        fn create(conn: &Conn, id: Uuid, params: CreateUserRequest, actor_id: Uuid) -> Result<(), ApiError> {
+            let user: User = NewUser {
+                id,
+                tenant_id: TenantId::DEFAULT,
+                first_name: params.first_name,
+                last_name: params.last_name,
+                email: params.email,
+                password: params.password,
+                created_by: actor_id,
+                updated_by: actor_id,
+            }
+            .into();
+            crate::models::user::create(conn, &user)?;
+            Ok(())
+        }
+
+        fn update(conn: &Conn, id: Uuid, params: UpdateUserRequest, actor_id: Uuid) -> Result<(), ApiError> {
+            diesel::update(users::table.find(DbUuid(id)))
+                .set((
+                    users::first_name.eq(params.first_name),
+                    users::last_name.eq(params.last_name),
+                    users::email_normalized.eq(normalize_email(&params.email)),
+                    users::email.eq(params.email),
+                    users::updated_by.eq(DbUuid(actor_id)),
+                ))
+                .execute(conn)?;
+            Ok(())
+        }
+
+        fn delete(conn: &Conn, id: Uuid) -> Result<(), ApiError> {
+            diesel::delete(users::table.find(DbUuid(id))).execute(conn)?;
+            Ok(())
+        }
+    }
+
+    fn create_params() -> CreateUserRequest {
+        CreateUserRequest {
+            first_name: "Resource".into(),
+            last_name: "Test".into(),
+            email: format!("resource-test-{}@nothing.org", Uuid::new_v4()),
+            password: "123456".into(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_serves_a_resource() {
+        let created = create::<Users>(get_data_pool(), Json(create_params()), get_auth_user(), AuditContext::default())
+            .await
+            .unwrap()
+            .into_inner();
+        let params = UpdateUserRequest {
+            first_name: "Updated".into(),
+            last_name: created.last_name.clone(),
+            email: created.email.clone(),
+        };
+        let id = || Path::from(created.id);
+        let updated = update::<Users>(id(), get_data_pool(), Json(params), get_auth_user(), AuditContext::default())
+            .await
+            .unwrap();
+        assert_eq!(updated.into_inner().first_name, "Updated");
+        let fetched = get::<Users>(id(), get_read_pool(), get_auth_user()).await.unwrap();
+        assert_eq!(fetched.into_inner().first_name, "Updated");
+
+        let req = test::TestRequest::with_uri("/api/v1/user?limit=1").to_http_request();
+        let query = Query(ListQuery { limit: Some(1), offset: None });
+        let page = list::<Users>(get_read_pool(), query, req, get_auth_user()).await.unwrap().into_inner();
+        assert_eq!(page.data.len(), 1);
+        assert!(page.links.next.is_some());
+
+        delete::<Users>(id(), get_data_pool(), get_auth_user(), AuditContext::default()).await.unwrap();
+        let response = get::<Users>(id(), get_read_pool(), get_auth_user()).await;
+        assert_eq!(response.unwrap_err(), ApiError::NotFound(format!("user {} not found", created.id)));
+    }
+
+    #[actix_rt::test]
+    async fn it_validates_and_maps_errors() {
+        let params = CreateUserRequest { first_name: "A".into(), ..create_params() };
+        let response = create::<Users>(get_data_pool(), Json(params), get_auth_user(), AuditContext::default()).await;
+        assert!(matches!(response, Err(ApiError::ValidationError(_))));
+
+        let missing = Path::from(Uuid::new_v4());
+        let response = delete::<Users>(missing, get_data_pool(), get_auth_user(), AuditContext::default()).await;
+        assert!(matches!(response, Err(ApiError::NotFound(_))));
+
+        let req = test::TestRequest::with_uri("/api/v1/user").to_http_request();
+        let query = Query(ListQuery { limit: Some(0), offset: None });
+        assert!(list::<Users>(get_read_pool(), query, req, get_auth_user()).await.is_err());
+    }
+}
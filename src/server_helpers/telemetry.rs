@@ -0,0 +1,21 @@
+use actix_web::{error::BlockingError, web};
+use std::fmt::Debug;
+use tracing::{info_span, Instrument};
+
+/// Drop-in replacement for `actix_web::web::block` that keeps the trace intact.
+///
+/// The closure runs on the blocking thread pool inside a `web::block` span,
+/// so spans opened there (eg. diesel queries) are children of the request
+/// instead of starting a new trace on the pool thread.
+pub async fn block<F, I, E>(f: F) -> Result<I, BlockingError<E>>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    let span = info_span!("web::block");
+    let blocking_span = span.clone();
+    web::block(move || blocking_span.in_scope(f))
+        .instrument(span)
+        .await
+}
@@ -1,21 +1,33 @@
 #[cfg(test)]
 pub mod tests {
-    use crate::auth::get_identity_service;
+    use crate::auth::{get_identity_service, get_session_service};
+    use crate::middleware::redis_identity::RedisSessionPolicy;
     use crate::server_helpers::cache::add_cache;
     use crate::config::CONFIG;
-    use crate::database::connection::{add_pool, init_pool, Pool};
+    use crate::database::connection::PoolType;
+    use crate::database::routing::{MysqlPooledConnection, ReadPool, RoutedPool};
     use crate::handlers::auth::LoginRequest;
+    use crate::database::seed::{load_fixtures, seed};
+    use crate::models::tenant::TenantId;
     use crate::routes::routes;
     use crate::server_helpers::state::{new_state, AppState};
     use actix_web::dev::ServiceResponse;
     use actix_web::{test, web::Data, App};
-    use diesel::mysql::MysqlConnection;
     use serde::Serialize;
 
+    pub const TEST_USER_EMAIL: &str = "satoshi@nakamotoinstitute.org";
+
+    /// Seed the bundled fixtures, which hold the user that tests log in with
+    pub fn ensure_test_user() {
+        let path = std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/users.yaml"));
+        let _ = seed(&get_pool(), TenantId::DEFAULT, &load_fixtures(path).unwrap());
+    }
+
     /// Helper for HTTP GET integration tests
     pub async fn test_get(route: &str) -> ServiceResponse {
+        ensure_test_user();
         let login_request = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
+            email: TEST_USER_EMAIL.into(),
             password: "123456".into(),
         };
 
@@ -23,8 +35,9 @@ pub mod tests {
             App::new()
                 .configure(add_cache)
                 .app_data(app_state())
-                .wrap(get_identity_service())
-                .configure(add_pool)
+                .wrap(get_identity_service(RedisSessionPolicy::new()))
+                .wrap(get_session_service())
+                .app_data(get_data_pool())
                 .configure(routes),
         )
         .await;
@@ -55,8 +68,9 @@ pub mod tests {
             App::new()
                 .configure(add_cache)
                 .app_data(app_state())
-                .wrap(get_identity_service())
-                .configure(add_pool)
+                .wrap(get_identity_service(RedisSessionPolicy::new()))
+                .wrap(get_session_service())
+                .app_data(get_data_pool())
                 .configure(routes),
         )
         .await;
@@ -100,25 +114,37 @@ pub mod tests {
     }
 
     /// Returns a r2d2 Pooled Connection to be used in tests
-    pub fn get_pool() -> Pool<MysqlConnection> {
-        init_pool::<MysqlConnection>(CONFIG.clone()).unwrap()
+    pub fn get_pool() -> PoolType {
+        RoutedPool::new(CONFIG.clone()).unwrap()
+    }
+
+    /// Returns a connection to the primary for model functions
+    pub fn get_conn() -> MysqlPooledConnection {
+        get_pool().get().unwrap()
     }
 
     /// Returns a r2d2 Pooled Connection wrappedn in Actix Application Data
-    pub fn get_data_pool() -> Data<Pool<MysqlConnection>> {
+    pub fn get_data_pool() -> Data<PoolType> {
         Data::new(get_pool())
     }
 
+    /// Returns the pool as read-only handlers get it
+    pub fn get_read_pool() -> ReadPool {
+        ReadPool(get_pool())
+    }
+
     /// Login to routes  
     pub async fn login() -> ServiceResponse {
+        ensure_test_user();
         let login_request = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
+            email: TEST_USER_EMAIL.into(),
             password: "123456".into(),
         };
         let mut app = test::init_service(
             App::new()
-                .wrap(get_identity_service())
-                .configure(add_pool)
+                .wrap(get_identity_service(RedisSessionPolicy::new()))
+                .wrap(get_session_service())
+                .app_data(get_data_pool())
                 .configure(routes),
         )
         .await;
@@ -1,7 +1,9 @@
 #[cfg(test)]
 mod tests {
+    use crate::handlers::registration::InviteRequest;
     use crate::handlers::user::{tests::get_first_users_id, CreateUserRequest};
-    use crate::tests::helpers::tests::{assert_get, assert_post};
+    use crate::tests::helpers::tests::{assert_get, assert_post, test_post};
+    use actix_web::http::StatusCode;
     use actix_web::web::Path;
     use uuid::Uuid;
 
@@ -24,9 +26,19 @@ mod tests {
         let params = CreateUserRequest {
             first_name: "Satoshi".into(),
             last_name: "Nakamoto".into(),
-            email: "satoshi@nakamotoinstitute.org".into(),
+            email: format!("satoshi-{}@nakamotoinstitute.org", Uuid::new_v4()),
             password: "123456".into(),
         };
         assert_post(PATH, params).await;
     }
+
+    #[actix_rt::test]
+    async fn it_only_lets_admins_invite() {
+        // The test user is not an admin
+        let params = InviteRequest {
+            email: "torvalds@transmeta.com".into(),
+        };
+        let response = test_post(&format!("{}/invite", PATH), params).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
 }
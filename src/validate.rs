@@ -17,7 +17,7 @@ where
 
 /// Collect ValidationErrors and return a vector of the messages
 /// Adds a default_error when none is supplied
-fn collect_errors(error: ValidationErrors) -> Vec<String> {
+pub fn collect_errors(error: ValidationErrors) -> Vec<String> {
   error
     .field_errors()
     .into_iter()